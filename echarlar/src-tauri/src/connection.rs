@@ -0,0 +1,158 @@
+//! The connection to the server, shared by every command.
+//!
+//! A background task reads every frame the server sends: responses go to the
+//! request waiting for them, pushes go to the frontend as [`PUSH_EVENT`]s, in
+//! the order they arrived. Direct messages are handled here instead.
+
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use shared::protocol::{
+    Features, Frame, FrameCodec, Hello, Push, Request, RequestId, Response, SoftwareInfo,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use tauri::{AppHandle, Emitter};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_util::codec::Framed;
+
+/// Event carrying each `Push` of the server to the frontend.
+pub(crate) const PUSH_EVENT: &str = "push";
+
+/// Event telling the frontend the connection is gone.
+pub(crate) const DISCONNECTED_EVENT: &str = "disconnected";
+
+type Connection = Framed<TcpStream, FrameCodec>;
+
+static WRITER: Mutex<Option<SplitSink<Connection, Frame>>> = Mutex::const_new(None);
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Requests waiting for their response.
+static PENDING: LazyLock<std::sync::Mutex<HashMap<RequestId, oneshot::Sender<Response>>>> =
+    LazyLock::new(Default::default);
+
+/// Connect to the server, unless already connected.
+pub(crate) async fn connect(app: AppHandle, address: &str) -> Result<(), String> {
+    let mut writer = WRITER.lock().await;
+    if writer.is_some() {
+        println!("Connection has already established...");
+        return Ok(());
+    }
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| format!("Connection failed: {}", e))?;
+    let mut connection = Framed::new(stream, FrameCodec::new());
+    handshake(&mut connection).await?;
+
+    let (sink, frames) = connection.split();
+    *writer = Some(sink);
+    // Pushes are handled apart from the reader, so handling one can wait for
+    // the response to a request.
+    let (pushes, received) = mpsc::unbounded_channel();
+    tokio::spawn(read_frames(app.clone(), frames, pushes));
+    tokio::spawn(forward_pushes(app, received));
+    Ok(())
+}
+
+/// Agree on the protocol version with the server before sending any request.
+async fn handshake(connection: &mut Connection) -> Result<(), String> {
+    let hello = Hello::new(
+        SoftwareInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        Features::empty(),
+    );
+    connection
+        .send(Frame::Hello(hello))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match connection.next().await {
+        Some(Ok(Frame::Welcome(welcome))) => {
+            println!(
+                "Connected to {} {} using protocol version {}",
+                welcome.server.name, welcome.server.version, welcome.version
+            );
+            Ok(())
+        }
+        Some(Ok(Frame::HandshakeError(err))) => Err(format!("Server rejected connection: {}", err)),
+        Some(Ok(frame)) => Err(format!("Unexpected frame during handshake: {:?}", frame)),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Err("Connection closed by server".to_string()),
+    }
+}
+
+/// Send a request to the server and wait for its response.
+pub(crate) async fn request(request: Request) -> Result<Response, String> {
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, response) = oneshot::channel();
+    PENDING.lock().unwrap().insert(id, sender);
+
+    let sent = match WRITER.lock().await.as_mut() {
+        Some(writer) => writer
+            .send(Frame::Request { id, request })
+            .await
+            .map_err(|e| e.to_string()),
+        None => Err("Not connected to server".to_string()),
+    };
+    if let Err(e) = sent {
+        PENDING.lock().unwrap().remove(&id);
+        return Err(e);
+    }
+    response
+        .await
+        .map_err(|_| "Connection closed by server".to_string())
+}
+
+/// Read the frames of the server until the connection closes.
+async fn read_frames(
+    app: AppHandle,
+    mut frames: SplitStream<Connection>,
+    pushes: mpsc::UnboundedSender<Push>,
+) {
+    while let Some(frame) = frames.next().await {
+        match frame {
+            Ok(Frame::Response { id, response }) => {
+                if let Some(sender) = PENDING.lock().unwrap().remove(&id) {
+                    let _ = sender.send(response);
+                }
+            }
+            Ok(Frame::Push(push)) => {
+                let _ = pushes.send(push);
+            }
+            Ok(frame) => println!("Unexpected frame: {:?}", frame),
+            Err(e) => {
+                println!("Connection failed: {}", e);
+                break;
+            }
+        }
+    }
+
+    *WRITER.lock().await = None;
+    // Dropping the senders fails the requests still waiting.
+    PENDING.lock().unwrap().clear();
+    let _ = app.emit(DISCONNECTED_EVENT, ());
+}
+
+async fn forward_pushes(app: AppHandle, mut pushes: mpsc::UnboundedReceiver<Push>) {
+    while let Some(push) = pushes.recv().await {
+        // Sender keys are for this device, not for the user to see.
+        if let Push::Direct { message } = push {
+            if let Err(e) = crate::receive_direct(vec![message]).await {
+                println!("Can not receive direct message: {}", e);
+            }
+            continue;
+        }
+        if let Push::Room { room, event } = &push {
+            if let Err(e) = crate::room_changed(room, event).await {
+                println!("Can not follow change of room {}: {}", room.uuid, e);
+            }
+        }
+        if let Err(e) = app.emit(PUSH_EVENT, push) {
+            println!("Can not forward push: {}", e);
+        }
+    }
+}
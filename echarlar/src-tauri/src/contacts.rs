@@ -0,0 +1,188 @@
+//! Users this device met, with the identity key it knows for each and
+//! whether the user verified it, persisted across restarts.
+//!
+//! Every identity key the server hands out goes through [`Contacts::observe`],
+//! and the frontend hears of the keys that changed as an
+//! [`IDENTITY_CHANGED_EVENT`].
+
+use serde::{Deserialize, Serialize};
+use shared::types::{Address, IdentityKey, User};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Event carrying a contact whose identity key changed to the frontend.
+pub(crate) const IDENTITY_CHANGED_EVENT: &str = "identity_changed";
+
+/// What this device knows of a contact.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Contact {
+    pub(crate) address: Address,
+    pub(crate) identity_key: IdentityKey,
+    /// Whether the user compared their safety number for `identity_key`.
+    pub(crate) verified: bool,
+    /// Whether `identity_key` replaced the key known before, and the user
+    /// did not verify or unverify the contact since. The UI warns about it.
+    pub(crate) identity_changed: bool,
+}
+
+pub(crate) struct Contacts {
+    path: PathBuf,
+    contacts: HashMap<Uuid, Contact>,
+}
+
+impl Contacts {
+    /// Load the contacts saved at `path`, none if there is no file yet.
+    pub(crate) fn load(path: PathBuf) -> Result<Self, String> {
+        let contacts = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| format!("Corrupt contacts file: {}", e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("Can not read contacts: {}", e)),
+        };
+        Ok(Self { path, contacts })
+    }
+
+    pub(crate) fn get(&self, user: &Uuid) -> Option<&Contact> {
+        self.contacts.get(user)
+    }
+
+    /// Record the identity key the server handed out for `user`. A key other
+    /// than the known one unverifies the contact and flags the change.
+    pub(crate) fn observe(&mut self, user: &User) -> Result<Contact, String> {
+        let contact = match self.contacts.get(&user.uuid) {
+            Some(contact)
+                if contact.identity_key == user.identity_key && contact.address == user.address =>
+            {
+                return Ok(contact.clone());
+            }
+            Some(contact) if contact.identity_key == user.identity_key => Contact {
+                address: user.address.clone(),
+                ..contact.clone()
+            },
+            Some(_) => Contact {
+                address: user.address.clone(),
+                identity_key: user.identity_key,
+                verified: false,
+                identity_changed: true,
+            },
+            None => Contact {
+                address: user.address.clone(),
+                identity_key: user.identity_key,
+                verified: false,
+                identity_changed: false,
+            },
+        };
+        self.contacts.insert(user.uuid, contact.clone());
+        self.save()?;
+        Ok(contact)
+    }
+
+    /// Mark `user` verified or not for its current identity key, which
+    /// acknowledges a change of key.
+    pub(crate) fn set_verified(&mut self, user: &Uuid, verified: bool) -> Result<Contact, String> {
+        let contact = self
+            .contacts
+            .get_mut(user)
+            .ok_or(format!("Unknown contact {}", user))?;
+        contact.verified = verified;
+        contact.identity_changed = false;
+        let contact = contact.clone();
+        self.save()?;
+        Ok(contact)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Can not save contacts: {}", e))?;
+        }
+        let json = serde_json::to_vec(&self.contacts).map_err(|e| e.to_string())?;
+        // Replace the file at once, so a crash never leaves half of it.
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, json)
+            .and_then(|_| fs::rename(&partial, &self.path))
+            .map_err(|e| format!("Can not save contacts: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(address: &str, key: u8) -> User {
+        User::new(address.to_string(), [key; 32])
+    }
+
+    fn path() -> PathBuf {
+        std::env::temp_dir().join(format!("contacts-{}.json", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut contacts = Contacts::load(path()).unwrap();
+        let alice = user("alice", 1);
+        let contact = contacts.observe(&alice).unwrap();
+        assert_eq!(contact.identity_key, [1; 32]);
+        assert!(!contact.verified && !contact.identity_changed);
+
+        // A new address is no change of identity.
+        let renamed = User {
+            address: "alice2".to_string(),
+            ..alice.clone()
+        };
+        let contact = contacts.set_verified(&alice.uuid, true).unwrap();
+        assert!(contact.verified);
+        let contact = contacts.observe(&renamed).unwrap();
+        assert_eq!(contact.address, "alice2");
+        assert!(contact.verified && !contact.identity_changed);
+
+        let changed = User {
+            identity_key: [2; 32],
+            ..renamed
+        };
+        let contact = contacts.observe(&changed).unwrap();
+        assert_eq!(contact.identity_key, [2; 32]);
+        assert!(!contact.verified && contact.identity_changed);
+        // Seeing the new key again keeps the warning.
+        assert!(contacts.observe(&changed).unwrap().identity_changed);
+    }
+
+    #[test]
+    fn test_set_verified_acknowledges_change() {
+        let mut contacts = Contacts::load(path()).unwrap();
+        let alice = user("alice", 1);
+        contacts.observe(&alice).unwrap();
+        contacts
+            .observe(&User {
+                identity_key: [2; 32],
+                ..alice.clone()
+            })
+            .unwrap();
+
+        let contact = contacts.set_verified(&alice.uuid, false).unwrap();
+        assert!(!contact.verified && !contact.identity_changed);
+        assert!(contacts.set_verified(&Uuid::new_v4(), true).is_err());
+    }
+
+    #[test]
+    fn test_persisted() {
+        let path = path();
+        let alice = user("alice", 1);
+        let mut contacts = Contacts::load(path.clone()).unwrap();
+        contacts.observe(&alice).unwrap();
+        contacts.set_verified(&alice.uuid, true).unwrap();
+
+        let contacts = Contacts::load(path.clone()).unwrap();
+        let contact = contacts.get(&alice.uuid).unwrap();
+        assert_eq!(contact.address, "alice");
+        assert_eq!(contact.identity_key, [1; 32]);
+        assert!(contact.verified);
+        assert!(!path.with_extension("partial").exists());
+
+        fs::write(&path, b"not json").unwrap();
+        assert!(Contacts::load(path.clone()).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
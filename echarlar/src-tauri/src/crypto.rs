@@ -0,0 +1,328 @@
+//! End-to-end encryption state of this device, persisted across restarts:
+//! the account it logs in with, the private halves of the prekeys it
+//! published, and its sessions with other devices and in rooms.
+//!
+//! The server only ever sees the envelopes and direct messages this state
+//! produces.
+
+use serde::{Deserialize, Serialize};
+use shared::auth::SigningKey;
+use shared::crypto::{EncryptedMessage, GroupSession, PrekeyPair, SenderId, Session};
+use shared::keys::{OneTimePrekey, PrekeyBundle, PrekeyId, SignedPrekey};
+use shared::types::{Content, DirectMessage, Envelope, IdentityKey, Message, User};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Number of one-time prekeys a device keeps published.
+const ONE_TIME_PREKEYS: u32 = 100;
+
+/// The user this device logs in as.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Account {
+    pub(crate) identity: SigningKey,
+    pub(crate) device: Uuid,
+    pub(crate) user: User,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    account: Option<Account>,
+    signed_prekey: Option<PrekeyPair>,
+    one_time_prekeys: HashMap<PrekeyId, PrekeyPair>,
+    next_prekey_id: PrekeyId,
+    /// Pairwise sessions with other devices, by user then device.
+    sessions: HashMap<Uuid, HashMap<Uuid, Session>>,
+    /// Sender key sessions of the rooms of the user.
+    rooms: HashMap<Uuid, GroupSession>,
+}
+
+pub(crate) struct Crypto {
+    path: PathBuf,
+    state: State,
+    /// Content of the messages already decrypted, by message uuid, with the
+    /// revision it belongs to. A message key only decrypts once.
+    contents: HashMap<Uuid, (Option<SystemTime>, Content)>,
+}
+
+impl Crypto {
+    /// Load the state saved at `path`, a fresh one if there is no file yet.
+    pub(crate) fn load(path: PathBuf) -> Result<Self, String> {
+        let state = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| format!("Corrupt encryption state: {}", e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(format!("Can not read encryption state: {}", e)),
+        };
+        Ok(Self {
+            path,
+            state,
+            contents: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn account(&self) -> Option<&Account> {
+        self.state.account.as_ref()
+    }
+
+    pub(crate) fn set_account(&mut self, account: Account) -> Result<(), String> {
+        self.state.account = Some(account);
+        self.save()
+    }
+
+    /// Record the user as the server returned it on login.
+    pub(crate) fn set_user(&mut self, user: User) -> Result<(), String> {
+        self.account_mut()?.user = user;
+        self.save()
+    }
+
+    /// Prekeys to publish, given that `left` one-time prekeys of this device
+    /// are left on the server: the signed prekey, generated on first use, and
+    /// enough new one-time prekeys to be back at `ONE_TIME_PREKEYS`.
+    ///
+    /// Their private halves are saved before they are published.
+    pub(crate) fn prekeys_to_publish(
+        &mut self,
+        left: u32,
+    ) -> Result<(SignedPrekey, Vec<OneTimePrekey>), String> {
+        let state = &mut self.state;
+        let identity = &state.account.as_ref().ok_or("Not registered")?.identity;
+        let signed = match &state.signed_prekey {
+            Some(pair) => pair.signed(identity),
+            None => {
+                state.next_prekey_id += 1;
+                let pair = PrekeyPair::generate(state.next_prekey_id);
+                let signed = pair.signed(identity);
+                state.signed_prekey = Some(pair);
+                signed
+            }
+        };
+        let mut one_time_prekeys = Vec::new();
+        for _ in left..ONE_TIME_PREKEYS {
+            state.next_prekey_id += 1;
+            let pair = PrekeyPair::generate(state.next_prekey_id);
+            one_time_prekeys.push(pair.one_time());
+            state.one_time_prekeys.insert(pair.id, pair);
+        }
+        self.save()?;
+        Ok((signed, one_time_prekeys))
+    }
+
+    pub(crate) fn has_room(&self, room: &Uuid) -> bool {
+        self.state.rooms.contains_key(room)
+    }
+
+    /// Start encrypting in `room`, with `members` and their identity keys,
+    /// with a new sender key, unless this device already does. Returns
+    /// whether it started, in which case every member needs the sender key.
+    pub(crate) fn start_room(
+        &mut self,
+        room: &Uuid,
+        members: HashMap<Uuid, IdentityKey>,
+    ) -> Result<bool, String> {
+        let account = self.state.account.as_ref().ok_or("Not registered")?;
+        if self.state.rooms.contains_key(room) {
+            return Ok(false);
+        }
+        let sender = SenderId {
+            user: account.user.uuid,
+            device: account.device,
+        };
+        let session = GroupSession::new(*room, sender, members);
+        self.state.rooms.insert(*room, session);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Follow a change of the members of `room`. Returns the users whose
+    /// devices need the sender key of this device, none if this device does
+    /// not encrypt in the room yet.
+    pub(crate) fn update_members(
+        &mut self,
+        room: &Uuid,
+        members: HashMap<Uuid, IdentityKey>,
+    ) -> Result<Vec<Uuid>, String> {
+        let Some(session) = self.state.rooms.get_mut(room) else {
+            return Ok(Vec::new());
+        };
+        let recipients = session.update_members(members);
+        // The sender key may have rotated.
+        self.save()?;
+        Ok(recipients)
+    }
+
+    /// Forget the sender keys of a room the user is no longer a member of.
+    pub(crate) fn forget_room(&mut self, room: &Uuid) -> Result<(), String> {
+        if self.state.rooms.remove(room).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// The sender key of this device in `room`, encrypted for the device of
+    /// `bundle` as the payload of a direct message. `None` for this device.
+    ///
+    /// Starts a session with the device unless one can already send.
+    pub(crate) fn distribution_for(
+        &mut self,
+        room: &Uuid,
+        bundle: &PrekeyBundle,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let account = self.state.account.as_ref().ok_or("Not registered")?;
+        if bundle.device == account.device {
+            return Ok(None);
+        }
+        let group = self
+            .state
+            .rooms
+            .get(room)
+            .ok_or(format!("No encryption session for room {}", room))?;
+        let sessions = self.state.sessions.entry(bundle.user).or_default();
+        let message = match sessions
+            .get_mut(&bundle.device)
+            .map(|session| group.distribute(session))
+        {
+            Some(Ok(message)) => message,
+            // Sessions started by the peer can not send before they received.
+            _ => {
+                let mut session =
+                    Session::initiate(&account.identity, bundle).map_err(|e| e.to_string())?;
+                let message = group.distribute(&mut session).map_err(|e| e.to_string())?;
+                sessions.insert(bundle.device, session);
+                message
+            }
+        };
+        self.save()?;
+        bincode::serialize(&message)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Accept the sender key another device sent in `direct`. The session of
+    /// its room must be started.
+    ///
+    /// A handshake in the message starts a new session with the device, in
+    /// place of the one known: both devices may have started one at once.
+    pub(crate) fn receive_distribution(&mut self, direct: &DirectMessage) -> Result<(), String> {
+        let message: EncryptedMessage =
+            bincode::deserialize(&direct.payload).map_err(|e| e.to_string())?;
+        let account = self.state.account.as_ref().ok_or("Not registered")?;
+        let group = self
+            .state
+            .rooms
+            .get_mut(&direct.room)
+            .ok_or(format!("No encryption session for room {}", direct.room))?;
+        let sender = SenderId {
+            user: direct.sender,
+            device: direct.sender_device,
+        };
+        let sessions = self.state.sessions.entry(direct.sender).or_default();
+
+        let known = sessions
+            .get(&direct.sender_device)
+            .cloned()
+            .map(|mut session| {
+                group
+                    .receive_distribution(sender, &mut session, &message)
+                    .map(|_| session)
+            });
+        let session = match (known, &message.handshake) {
+            (Some(Ok(session)), _) => session,
+            (_, Some(handshake)) => {
+                let signed_prekey = self
+                    .state
+                    .signed_prekey
+                    .as_ref()
+                    .filter(|prekey| prekey.id == handshake.signed_prekey)
+                    .ok_or("Handshake with an unknown signed prekey")?;
+                let one_time_prekey = match handshake.one_time_prekey {
+                    Some(id) => Some(
+                        self.state
+                            .one_time_prekeys
+                            .get(&id)
+                            .ok_or("Handshake with an unknown one-time prekey")?,
+                    ),
+                    None => None,
+                };
+                let mut session =
+                    Session::respond(&account.identity, handshake, signed_prekey, one_time_prekey)
+                        .map_err(|e| e.to_string())?;
+                group
+                    .receive_distribution(sender, &mut session, &message)
+                    .map_err(|e| e.to_string())?;
+                // So the session can not be started twice.
+                if let Some(id) = handshake.one_time_prekey {
+                    self.state.one_time_prekeys.remove(&id);
+                }
+                session
+            }
+            (Some(Err(e)), None) => return Err(e.to_string()),
+            (None, None) => return Err("No session with the sending device".to_string()),
+        };
+        sessions.insert(direct.sender_device, session);
+        self.save()
+    }
+
+    /// Encrypt `content` into an envelope for `room`.
+    pub(crate) fn seal(&mut self, room: &Uuid, content: &Content) -> Result<Envelope, String> {
+        let envelope = self
+            .state
+            .rooms
+            .get_mut(room)
+            .ok_or(format!("No encryption session for room {}", room))?
+            .seal(content)
+            .map_err(|e| e.to_string())?;
+        // The chain of the sender key moved on.
+        self.save()?;
+        Ok(envelope)
+    }
+
+    /// The content of `message`, if this device can decrypt it.
+    pub(crate) fn content(&mut self, room: &Uuid, message: &Message) -> Option<Content> {
+        let envelope = message.envelope.as_ref()?;
+        // Stored before messages were encrypted.
+        if envelope.is_legacy() {
+            return envelope.legacy_content();
+        }
+        if let Some((edited, content)) = self.contents.get(&message.uuid) {
+            if *edited == message.edited {
+                return Some(content.clone());
+            }
+        }
+        let content = self
+            .state
+            .rooms
+            .get_mut(room)?
+            .open(message.owner, envelope)
+            .map_err(|e| println!("Can not decrypt message {}: {}", message.uuid, e))
+            .ok()?;
+        self.contents
+            .insert(message.uuid, (message.edited, content.clone()));
+        if let Err(e) = self.save() {
+            println!("{}", e);
+        }
+        Some(content)
+    }
+
+    fn account_mut(&mut self) -> Result<&mut Account, String> {
+        self.state
+            .account
+            .as_mut()
+            .ok_or("Not registered".to_string())
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Can not save encryption state: {}", e))?;
+        }
+        let json = serde_json::to_vec(&self.state).map_err(|e| e.to_string())?;
+        // Replace the file at once, so a crash never leaves half of it.
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, json)
+            .and_then(|_| fs::rename(&partial, &self.path))
+            .map_err(|e| format!("Can not save encryption state: {}", e))
+    }
+}
@@ -1,51 +1,548 @@
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::sync::{Mutex, OnceCell};
+mod connection;
+mod contacts;
+mod crypto;
 
-static CONNECTION: OnceCell<Mutex<TcpStream>> = OnceCell::const_new();
+use connection::request;
+use contacts::{Contact, Contacts, IDENTITY_CHANGED_EVENT};
+use crypto::{Account, Crypto};
+use serde::Serialize;
+use shared::auth::{identity_key, Challenge, SigningKey};
+use shared::crypto::SafetyNumber;
+use shared::protocol::{
+    Cursor, ErrorCode, ReactionCount, Request, Response, RoomEvent, RoomSummary,
+};
+use shared::types::{Content, DirectMessage, IdentityKey, Message, Room, User};
+use std::collections::HashMap;
+use std::sync::{MutexGuard, OnceLock};
+use tauri::{AppHandle, Emitter, Manager};
+use uuid::Uuid;
+
+static CRYPTO: OnceLock<std::sync::Mutex<Crypto>> = OnceLock::new();
+
+static CONTACTS: OnceLock<std::sync::Mutex<Contacts>> = OnceLock::new();
+
+/// The application, to emit events outside of commands.
+static APP: OnceLock<AppHandle> = OnceLock::new();
+
+/// Number of direct messages fetched at once after logging in.
+const DIRECT_PAGE: u32 = 100;
+
+/// Number of users looked up at once.
+const FETCHED_USERS: usize = 256;
 
 #[tauri::command]
-async fn connect_to_server() -> Result<(), String> {
+async fn connect_to_server(app: tauri::AppHandle) -> Result<(), String> {
     println!("Try connecting to server...");
-    if is_connected().await? {
-        println!("Connection has already established...");
+    connection::connect(app, "127.0.0.1:8080").await
+}
+
+/// Register a new user at `address`, owning a new identity key, and log in
+/// as that user from this device.
+#[tauri::command]
+async fn register(address: String) -> Result<User, String> {
+    let identity = SigningKey::from_bytes(&rand::random());
+    let device = Uuid::new_v4();
+    let register = Request::Register {
+        address,
+        identity_key: identity_key(&identity),
+        device,
+        signature: challenge().await?.sign(&identity),
+    };
+    let user = match request(register).await? {
+        Response::LoggedIn { user } => user,
+        Response::Error { message, .. } => return Err(message),
+        other => return Err(format!("Unexpected response: {:?}", other)),
+    };
+    crypto()?.set_account(Account {
+        identity,
+        device,
+        user: user.clone(),
+    })?;
+    after_login().await?;
+    Ok(user)
+}
+
+/// Log in as the user this device registered.
+#[tauri::command]
+async fn login() -> Result<User, String> {
+    let (identity, user, device) = match crypto()?.account() {
+        Some(account) => (account.identity.clone(), account.user.uuid, account.device),
+        None => return Err("Not registered".to_string()),
+    };
+    let login = Request::Login {
+        user,
+        device,
+        signature: challenge().await?.sign(&identity),
+    };
+    let user = match request(login).await? {
+        Response::LoggedIn { user } => user,
+        Response::Error { message, .. } => return Err(message),
+        other => return Err(format!("Unexpected response: {:?}", other)),
+    };
+    crypto()?.set_user(user.clone())?;
+    after_login().await?;
+    Ok(user)
+}
+
+async fn challenge() -> Result<Challenge, String> {
+    match request(Request::Challenge).await? {
+        Response::Challenge(challenge) => Ok(challenge),
+        Response::Error { message, .. } => Err(message),
+        other => Err(format!("Unexpected response: {:?}", other)),
+    }
+}
+
+/// Publish the prekeys others start sessions with this device with, and
+/// receive the sender keys sent to it while offline.
+async fn after_login() -> Result<(), String> {
+    let count = Request::UploadPrekeys {
+        signed_prekey: None,
+        one_time_prekeys: Vec::new(),
+    };
+    let left = match request(count).await? {
+        Response::PrekeysUploaded { one_time_prekeys } => one_time_prekeys,
+        Response::Error { message, .. } => return Err(message),
+        other => return Err(format!("Unexpected response: {:?}", other)),
+    };
+    let (signed_prekey, one_time_prekeys) = crypto()?.prekeys_to_publish(left)?;
+    let upload = Request::UploadPrekeys {
+        signed_prekey: Some(signed_prekey),
+        one_time_prekeys,
+    };
+    match request(upload).await? {
+        Response::PrekeysUploaded { .. } => {}
+        Response::Error { message, .. } => return Err(message),
+        other => return Err(format!("Unexpected response: {:?}", other)),
+    }
+
+    loop {
+        let fetch = Request::FetchDirect { limit: DIRECT_PAGE };
+        let messages = match request(fetch).await? {
+            Response::DirectMessages { messages } => messages,
+            Response::Error { message, .. } => return Err(message),
+            other => return Err(format!("Unexpected response: {:?}", other)),
+        };
+        let last_page = messages.len() < DIRECT_PAGE as usize;
+        receive_direct(messages).await?;
+        if last_page {
+            return Ok(());
+        }
+    }
+}
+
+/// Accept the sender keys other devices sent to this one, then acknowledge
+/// them so the server forgets them.
+///
+/// A sender key that can not be accepted is acknowledged all the same: it
+/// would fail again.
+pub(crate) async fn receive_direct(messages: Vec<DirectMessage>) -> Result<(), String> {
+    if messages.is_empty() {
         return Ok(());
     }
-    let stream = TcpStream::connect("127.0.0.1:8080")
-        .await
-        .map_err(|e| format!("Connection failed: {}", e))?;
-    CONNECTION
-        .set(Mutex::new(stream))
-        .map_err(|_| "Connection already set".to_string())?;
+    let mut received = Vec::with_capacity(messages.len());
+    for message in messages {
+        // Sender keys are kept in the session of the room.
+        let accepted = match room_session(&message.room).await {
+            Ok(()) => crypto()?.receive_distribution(&message),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = accepted {
+            println!("Can not accept sender key of {}: {}", message.sender, e);
+        }
+        received.push(message.uuid);
+    }
+    match request(Request::AckDirect { messages: received }).await? {
+        Response::Acknowledged => Ok(()),
+        Response::Error { message, .. } => Err(message),
+        other => Err(format!("Unexpected response: {:?}", other)),
+    }
+}
+
+/// Start encrypting in `room` unless this device already does, handing the
+/// new sender key to every device of its members.
+async fn room_session(room: &Uuid) -> Result<(), String> {
+    if crypto()?.has_room(room) {
+        return Ok(());
+    }
+    let room = find_room(room).await?;
+    let members = member_keys(&room).await?;
+    let started = crypto()?.start_room(&room.uuid, members)?;
+    if started {
+        distribute(&room.uuid, room.members.iter().copied()).await?;
+    }
     Ok(())
 }
 
+/// Follow a change of a room: hand the sender key of this device to the
+/// members who need it, or forget the room once the user is out of it.
+pub(crate) async fn room_changed(room: &Room, event: &RoomEvent) -> Result<(), String> {
+    let Some(user) = crypto()?.account().map(|account| account.user.uuid) else {
+        return Ok(());
+    };
+    match event {
+        RoomEvent::MemberLeft { member } | RoomEvent::MemberKicked { member, .. }
+            if *member == user =>
+        {
+            crypto()?.forget_room(&room.uuid)
+        }
+        RoomEvent::Deleted { .. } => crypto()?.forget_room(&room.uuid),
+        RoomEvent::MembersAdded { .. }
+        | RoomEvent::MemberLeft { .. }
+        | RoomEvent::MemberKicked { .. } => {
+            if !crypto()?.has_room(&room.uuid) {
+                return Ok(());
+            }
+            let members = member_keys(room).await?;
+            let recipients = crypto()?.update_members(&room.uuid, members)?;
+            distribute(&room.uuid, recipients).await
+        }
+        RoomEvent::Created | RoomEvent::Renamed { .. } => Ok(()),
+    }
+}
+
+/// The identity keys of the members of `room`, which sender keys must come
+/// from.
+async fn member_keys(room: &Room) -> Result<HashMap<Uuid, IdentityKey>, String> {
+    let members: Vec<Uuid> = room.members.iter().copied().collect();
+    let mut keys = HashMap::with_capacity(members.len());
+    for users in members.chunks(FETCHED_USERS) {
+        let fetch = Request::FetchUsers {
+            users: users.to_vec(),
+        };
+        match request(fetch).await? {
+            Response::Users { users } => {
+                for user in users {
+                    observe(&user)?;
+                    keys.insert(user.uuid, user.identity_key);
+                }
+            }
+            Response::Error { message, .. } => return Err(message),
+            other => return Err(format!("Unexpected response: {:?}", other)),
+        }
+    }
+    Ok(keys)
+}
+
+/// Hand the sender key of this device in `room` to every device of `users`
+/// over a pairwise session, started from a prekey bundle when needed.
+///
+/// Devices whose bundle or delivery fails are skipped with a warning, so they
+/// do not keep the others from the sender key, which may have rotated already.
+async fn distribute(room: &Uuid, users: impl IntoIterator<Item = Uuid>) -> Result<(), String> {
+    for user in users {
+        let bundles = match request(Request::FetchPrekeyBundle { user }).await? {
+            Response::PrekeyBundles { bundles } => bundles,
+            // No device of the user can decrypt yet.
+            Response::Error {
+                code: ErrorCode::NotFound,
+                ..
+            } => continue,
+            Response::Error { message, .. } => return Err(message),
+            other => return Err(format!("Unexpected response: {:?}", other)),
+        };
+        // Observed along with the members of the room.
+        let known = contacts()?.get(&user).map(|contact| contact.identity_key);
+        for bundle in bundles {
+            let skip = |reason: String| {
+                println!(
+                    "Not handing the sender key of room {} to device {} of {}: {}",
+                    room, bundle.device, user, reason
+                )
+            };
+            if bundle.user != user {
+                skip(format!("prekey bundle of {}", bundle.user));
+                continue;
+            }
+            if known != Some(bundle.identity_key) {
+                skip("prekey bundle with another identity key than its own".to_string());
+                continue;
+            }
+            let payload = match crypto()?.distribution_for(room, &bundle) {
+                Ok(Some(payload)) => payload,
+                Ok(None) => continue,
+                Err(e) => {
+                    skip(e);
+                    continue;
+                }
+            };
+            let send = Request::SendDirect {
+                room: *room,
+                user,
+                device: bundle.device,
+                payload,
+            };
+            match request(send).await? {
+                Response::Acknowledged => {}
+                Response::Error { message, .. } => skip(message),
+                other => return Err(format!("Unexpected response: {:?}", other)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Look `room` up among the rooms of the logged in user.
+async fn find_room(room: &Uuid) -> Result<Room, String> {
+    let mut after = None;
+    loop {
+        let (rooms, next) = match request(Request::ListRooms { limit: 50, after }).await? {
+            Response::Rooms { rooms, next } => (rooms, next),
+            Response::Error { message, .. } => return Err(message),
+            other => return Err(format!("Unexpected response: {:?}", other)),
+        };
+        if let Some(summary) = rooms.into_iter().find(|summary| summary.room.uuid == *room) {
+            return Ok(summary.room);
+        }
+        after = Some(next.ok_or(format!("Not a member of room {}", room))?);
+    }
+}
+
+/// Send a text message, replying to `reply_to` and posted in the thread of
+/// `thread_root` when set.
 #[tauri::command]
-async fn send_message(mut message: String) -> Result<String, String> {
-    message.push('\n');
-    message.push('\r');
-    let mutex = CONNECTION
-        .get()
-        .ok_or("Not connected to server".to_string())?;
-    let mut stream = mutex.lock().await;
+async fn send_message(
+    room: Uuid,
+    message: String,
+    reply_to: Option<Uuid>,
+    thread_root: Option<Uuid>,
+) -> Result<String, String> {
+    room_session(&room).await?;
+    let envelope = crypto()?.seal(&room, &Content::Text(message))?;
+    let response = request(Request::SendMessage {
+        envelope,
+        reply_to,
+        thread_root,
+    })
+    .await?;
+
+    match response {
+        Response::MessageSent { .. } => Ok(("OK").to_string()),
+        Response::Error { message, .. } => Err(message),
+        other => Err(format!("Unexpected response: {:?}", other)),
+    }
+}
+
+/// A page of the rooms the user is a member of.
+#[derive(Serialize)]
+struct RoomsPage {
+    rooms: Vec<RoomSummary>,
+    /// Pass as `after` to `list_rooms` to get the next page.
+    next: Option<Uuid>,
+}
+
+/// List the rooms of the logged in user, to fill the sidebar.
+#[tauri::command]
+async fn list_rooms(after: Option<Uuid>) -> Result<RoomsPage, String> {
+    match request(Request::ListRooms { limit: 50, after }).await? {
+        Response::Rooms { rooms, next } => Ok(RoomsPage { rooms, next }),
+        Response::Error { message, .. } => Err(message),
+        other => Err(format!("Unexpected response: {:?}", other)),
+    }
+}
+
+/// A message with its decrypted content, `None` when deleted or when this
+/// device can not decrypt it.
+#[derive(Serialize)]
+struct MessageView {
+    #[serde(flatten)]
+    message: Message,
+    content: Option<Content>,
+}
+
+/// A page of a thread, for the thread side panel.
+#[derive(Serialize)]
+struct ThreadPage {
+    root: MessageView,
+    messages: Vec<MessageView>,
+    /// Pass as `after` to `fetch_thread` to get the messages that follow.
+    next: Option<Cursor>,
+    /// Reaction counts of the messages with reactions, by message uuid.
+    reactions: HashMap<Uuid, Vec<ReactionCount>>,
+}
+
+/// Fetch the messages of the thread started by `root`, oldest first.
+#[tauri::command]
+async fn fetch_thread(room: Uuid, root: Uuid, after: Option<Cursor>) -> Result<ThreadPage, String> {
+    let fetch = Request::FetchThread {
+        room,
+        root,
+        limit: 50,
+        after,
+    };
+    match request(fetch).await? {
+        Response::Thread {
+            root,
+            messages,
+            next,
+            reactions,
+        } => {
+            let mut crypto = crypto()?;
+            let mut view = |message: Message| MessageView {
+                content: crypto.content(&room, &message),
+                message,
+            };
+            Ok(ThreadPage {
+                root: view(root),
+                messages: messages.into_iter().map(&mut view).collect(),
+                next,
+                reactions,
+            })
+        }
+        Response::Error { message, .. } => Err(message),
+        other => Err(format!("Unexpected response: {:?}", other)),
+    }
+}
+
+/// A contact, and whether to warn that its identity key changed.
+#[derive(Clone, Serialize)]
+struct ContactView {
+    user: Uuid,
+    #[serde(flatten)]
+    contact: Contact,
+}
+
+/// Look up a user by address, checking its identity key against the one
+/// known for it.
+#[tauri::command]
+async fn find_contact(address: String) -> Result<ContactView, String> {
+    match request(Request::FindUser { address }).await? {
+        Response::User { user } => Ok(ContactView {
+            user: user.uuid,
+            contact: observe(&user)?,
+        }),
+        Response::Error { message, .. } => Err(message),
+        other => Err(format!("Unexpected response: {:?}", other)),
+    }
+}
 
-    stream
-        .write_all(message.as_bytes())
-        .await
-        .map_err(|e| e.to_string())?;
+/// The safety number of the logged in user and a contact.
+#[derive(Serialize)]
+struct SafetyNumberView {
+    /// Groups of five digits, to compare aloud.
+    number: String,
+    /// Text of the QR code for the contact to scan.
+    scannable: String,
+    #[serde(flatten)]
+    contact: Contact,
+}
 
-    Ok(("OK").to_string())
+#[tauri::command]
+async fn safety_number(contact: Uuid) -> Result<SafetyNumberView, String> {
+    let (number, known) = contact_safety_number(&contact)?;
+    Ok(SafetyNumberView {
+        number: number.to_string(),
+        scannable: number.scannable(),
+        contact: known,
+    })
 }
 
-async fn is_connected() -> Result<bool, String> {
-    Ok(CONNECTION.get().is_some())
+/// Mark a contact verified once the user compared safety numbers aloud or,
+/// with `scanned`, scanned the QR code of the contact.
+#[tauri::command]
+async fn verify_contact(contact: Uuid, scanned: Option<String>) -> Result<ContactView, String> {
+    if let Some(scanned) = scanned {
+        let (number, _) = contact_safety_number(&contact)?;
+        if !number
+            .matches_scanned(&scanned)
+            .map_err(|e| e.to_string())?
+        {
+            return Err("Safety numbers do not match".to_string());
+        }
+    }
+    Ok(ContactView {
+        user: contact,
+        contact: contacts()?.set_verified(&contact, true)?,
+    })
+}
+
+#[tauri::command]
+async fn unverify_contact(contact: Uuid) -> Result<ContactView, String> {
+    Ok(ContactView {
+        user: contact,
+        contact: contacts()?.set_verified(&contact, false)?,
+    })
+}
+
+fn contact_safety_number(contact: &Uuid) -> Result<(SafetyNumber, Contact), String> {
+    let user = crypto()?
+        .account()
+        .map(|account| account.user.clone())
+        .ok_or("Not logged in".to_string())?;
+    let known = contacts()?
+        .get(contact)
+        .cloned()
+        .ok_or(format!("Unknown contact {}", contact))?;
+    let number = SafetyNumber::new(&user.uuid, &user.identity_key, contact, &known.identity_key);
+    Ok((number, known))
+}
+
+/// Record the identity key the server handed out for `user`, and warn the
+/// frontend when it replaced the known one.
+fn observe(user: &User) -> Result<Contact, String> {
+    let mut contacts = contacts()?;
+    let known = contacts.get(&user.uuid).map(|contact| contact.identity_key);
+    let contact = contacts.observe(user)?;
+    if known.is_some_and(|key| key != user.identity_key) {
+        let view = ContactView {
+            user: user.uuid,
+            contact: contact.clone(),
+        };
+        if let Err(e) = APP
+            .get()
+            .ok_or("No application")?
+            .emit(IDENTITY_CHANGED_EVENT, view)
+        {
+            println!("Can not warn of identity change: {}", e);
+        }
+    }
+    Ok(contact)
+}
+
+fn crypto() -> Result<MutexGuard<'static, Crypto>, String> {
+    Ok(CRYPTO
+        .get()
+        .ok_or("Encryption state is not loaded".to_string())?
+        .lock()
+        .unwrap())
+}
+
+fn contacts() -> Result<MutexGuard<'static, Contacts>, String> {
+    Ok(CONTACTS
+        .get()
+        .ok_or("Contacts are not loaded".to_string())?
+        .lock()
+        .unwrap())
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![connect_to_server, send_message])
+        .setup(|app| {
+            APP.set(app.handle().clone())
+                .map_err(|_| "Application already set up")?;
+            let path = app.path().app_data_dir()?.join("crypto.json");
+            CRYPTO
+                .set(std::sync::Mutex::new(Crypto::load(path)?))
+                .map_err(|_| "Encryption state already loaded")?;
+            let path = app.path().app_data_dir()?.join("contacts.json");
+            CONTACTS
+                .set(std::sync::Mutex::new(Contacts::load(path)?))
+                .map_err(|_| "Contacts already loaded")?;
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            connect_to_server,
+            register,
+            login,
+            send_message,
+            list_rooms,
+            fetch_thread,
+            find_contact,
+            safety_number,
+            verify_contact,
+            unverify_contact
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -1,14 +1,39 @@
 import {useEffect, useState} from "react";
 import {invoke} from "@tauri-apps/api/core";
+import {listen} from "@tauri-apps/api/event";
 import "./App.css";
 import Chat from "./components/chat";
 
+interface RoomSummary {
+    room: { uuid: string; name: string };
+    unread: number;
+}
+
+interface RoomsPage {
+    rooms: RoomSummary[];
+    next: string | null;
+}
+
+/** A contact whose identity key changed, until the user verifies it again. */
+interface ContactView {
+    user: string;
+    address: string;
+}
+
+/** A `Push` of the server, as the backend forwards it. */
+type Push = Record<string, { room?: string | { uuid: string } } & Record<string, unknown>>;
+
 function App() {
     const [connected, setConnected] = useState<boolean>(false);
     const [connecting, setConnecting] = useState<boolean>(false);
     const [message, setMessage] = useState<string>("");
     const [response, setResponse] = useState<string>("");
     const [history, setHistory] = useState<string[]>([]);
+    const [rooms, setRooms] = useState<RoomSummary[]>([]);
+    const [room, setRoom] = useState<string | null>(null);
+    const [loggedIn, setLoggedIn] = useState<boolean>(false);
+    const [address, setAddress] = useState<string>("");
+    const [identityChanges, setIdentityChanges] = useState<ContactView[]>([]);
 
     const connectToServer = async (): Promise<void> => {
         if (connecting || connected) {
@@ -21,21 +46,49 @@ function App() {
             setConnected(true);
         } catch (err) {
             console.error("Connection error:", err);
+            return;
         } finally {
             setConnecting(false);
         }
+        try {
+            await invoke("login");
+            setLoggedIn(true);
+            await loadRooms();
+        } catch (err) {
+            // Not registered on this device yet.
+            console.error("Login error:", err);
+        }
+    };
+
+    const register = async (): Promise<void> => {
+        try {
+            await invoke("register", {address});
+            setLoggedIn(true);
+            await loadRooms();
+        } catch (err) {
+            console.error("Register error:", err);
+        }
+    };
+
+    const loadRooms = async (): Promise<void> => {
+        try {
+            const page = await invoke<RoomsPage>("list_rooms", {after: null});
+            setRooms(page.rooms);
+        } catch (err) {
+            console.error("List rooms error:", err);
+        }
     };
 
     const sendMessage = async (): Promise<void> => {
-        if (connecting) {
+        if (connecting || room === null) {
             return;
         }
         setConnecting(true);
 
         try {
             console.log('sendMessage...', message);
-            const reply = await invoke<string>("send_message", {message});
-            setHistory((prev) => [...prev, `You: ${message}`, `Server: ${reply}`]);
+            const reply = await invoke<string>("send_message", {room, message});
+            setHistory((prev) => [...prev, `You: ${message}`]);
             setMessage("");
             setResponse(reply);
         } catch (err) {
@@ -49,18 +102,80 @@ function App() {
         setMessage(e.target.value);
     };
 
+    const onRoomChange = (e: React.ChangeEvent<HTMLSelectElement>) => {
+        setRoom(e.target.value === "" ? null : e.target.value);
+        setHistory([]);
+    };
+
     useEffect(() => {
         connectToServer();
     }, []);
 
+    useEffect(() => {
+        const pushes = listen<Push>("push", (event) => {
+            const [kind, push] = Object.entries(event.payload)[0];
+            if (kind === "Room") {
+                loadRooms();
+                return;
+            }
+            const pushRoom = typeof push.room === "string" ? push.room : push.room?.uuid;
+            if (pushRoom === room && kind === "Message") {
+                setHistory((prev) => [...prev, "New message"]);
+            }
+        });
+        const identityChanged = listen<ContactView>("identity_changed", (event) => {
+            setIdentityChanges((prev) => [
+                ...prev.filter((contact) => contact.user !== event.payload.user),
+                event.payload,
+            ]);
+        });
+        const disconnected = listen("disconnected", () => {
+            setConnected(false);
+            setLoggedIn(false);
+        });
+        return () => {
+            pushes.then((unlisten) => unlisten());
+            identityChanged.then((unlisten) => unlisten());
+            disconnected.then((unlisten) => unlisten());
+        };
+    }, [room]);
+
+    if (connected && !loggedIn) {
+        return (
+            <div className="container">
+                <input
+                    value={address}
+                    onChange={(e) => setAddress(e.target.value)}
+                    placeholder="Address"
+                />
+                <button onClick={register} disabled={address === ""}>
+                    Register
+                </button>
+            </div>
+        );
+    }
+
     return (
         <div className="container">
+            {identityChanges.map((contact) => (
+                <p key={contact.user} className="warning">
+                    The identity key of {contact.address} changed. Compare safety numbers again.
+                </p>
+            ))}
+            <select value={room ?? ""} onChange={onRoomChange} disabled={!loggedIn}>
+                <option value="">Select a room</option>
+                {rooms.map(({room, unread}) => (
+                    <option key={room.uuid} value={room.uuid}>
+                        {unread > 0 ? `${room.name} (${unread})` : room.name}
+                    </option>
+                ))}
+            </select>
             <Chat
                 history={history}
                 message={message}
                 onMessageChange={onMessageChange}
                 onSendMessage={sendMessage}
-                connected={connected}
+                connected={loggedIn && room !== null}
             />
         </div>
     );
@@ -25,21 +25,13 @@ const Chat: React.FC<ChatProps> = ({
                 className='message-list'
                 lockable={true}
                 toBottomHeight={'100%'}
-                dataSource={[
-                    {
-                        position: "left",
-                        type: "text",
-                        title: "Kursat",
-                        text: "Give me a message list example !",
-                        date: new Date(),
-                    },
-                    {
-                        position: "right",
-                        type: "text",
-                        title: "Emre",
-                        text: "That's all.",
-                    },
-                ]}
+                dataSource={history.map((text) => ({
+                    position: text.startsWith("You: ") ? "right" : "left",
+                    type: "text" as const,
+                    title: "",
+                    text,
+                    date: new Date(),
+                }))}
             />
             <Input
                 placeholder="Type here..."
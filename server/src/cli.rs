@@ -1,4 +1,5 @@
 use clap::Parser;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(name = "e-charlar-server", version, about = "An Encrypted Chat Server")]
@@ -7,4 +8,10 @@ pub(crate) struct Cli {
     pub(crate) port: Option<u16>,
     #[arg(long)]
     pub(crate) max_connections: Option<usize>,
+    #[arg(long)]
+    pub(crate) db_path: Option<PathBuf>,
+    /// Replace the messages kept in plaintext since before end-to-end
+    /// encryption with tombstones, purge their plaintext from disk, and exit.
+    #[arg(long)]
+    pub(crate) purge_legacy_messages: bool,
 }
@@ -0,0 +1,40 @@
+use crate::server::Result;
+use futures::{SinkExt, StreamExt};
+use shared::protocol::{Frame, FrameCodec};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// Send and receive `Frame` values from a remote peer.
+///
+/// Wraps a `TcpStream` into a framed transport, so the handler deals with whole
+/// frames instead of raw bytes. Buffering of partial reads and writes, as well
+/// as the length-prefixed encoding, is done by `FrameCodec`.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    framed: Framed<TcpStream, FrameCodec>,
+}
+
+impl Connection {
+    /// Create a new `Connection`, backed by `socket`.
+    pub(crate) fn new(socket: TcpStream) -> Connection {
+        Connection {
+            framed: Framed::new(socket, FrameCodec::new()),
+        }
+    }
+
+    /// Read a single `Frame` value from the underlying stream.
+    ///
+    /// Returns `None` if the remote peer closed the connection cleanly.
+    pub(crate) async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        match self.framed.next().await {
+            Some(frame) => Ok(Some(frame?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Write a single `Frame` value to the underlying stream and flush it.
+    pub(crate) async fn write_frame(&mut self, frame: Frame) -> Result<()> {
+        self.framed.send(frame).await?;
+        Ok(())
+    }
+}
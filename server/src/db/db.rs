@@ -1,7 +1,11 @@
-use shared::types::{Message, Room, User};
+use crate::db::error::Result;
+use serde::{Deserialize, Serialize};
+use shared::keys::{OneTimePrekey, SignedPrekey};
+use shared::types::{Address, DirectMessage, Message, Room, User};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -18,21 +22,290 @@ pub(crate) enum ConfigValue {
     // Port(u16),
 }
 
+/// Position of a message in the history of its room.
+///
+/// Messages are ordered by creation time, then by uuid, so messages created in
+/// the same millisecond still have distinct positions. Cursors compare the
+/// same way: later in history is greater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct MessageCursor {
+    /// Milliseconds since the Unix epoch.
+    pub(crate) created: u64,
+    pub(crate) message: Uuid,
+}
+
+impl MessageCursor {
+    /// Position of `message`.
+    pub(crate) fn of(message: &Message) -> Result<Self> {
+        Ok(Self {
+            created: message.created.duration_since(UNIX_EPOCH)?.as_millis() as u64,
+            message: message.uuid,
+        })
+    }
+
+    /// Position right before every message created at or after `time`.
+    pub(crate) fn before(time: SystemTime) -> Result<Self> {
+        Ok(Self {
+            created: time.duration_since(UNIX_EPOCH)?.as_millis() as u64,
+            // Walking forward in history, uuids of the same millisecond come
+            // in descending order.
+            message: Uuid::max(),
+        })
+    }
+}
+
+impl Ord for MessageCursor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.created
+            .cmp(&other.created)
+            .then_with(|| other.message.cmp(&self.message))
+    }
+}
+
+impl PartialOrd for MessageCursor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Position of the last edit of a message among the edits of its room, or of
+/// its deletion among the deletions.
+///
+/// Edits are ordered by time, then by message uuid, like `MessageCursor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EditCursor {
+    /// Milliseconds since the Unix epoch.
+    pub(crate) edited: u64,
+    pub(crate) message: Uuid,
+}
+
+impl EditCursor {
+    /// Position right before every edit made at or after `time`.
+    pub(crate) fn before(time: SystemTime) -> Result<Self> {
+        Ok(Self {
+            edited: time.duration_since(UNIX_EPOCH)?.as_millis() as u64,
+            message: Uuid::nil(),
+        })
+    }
+}
+
+/// A message deleted for everyone, as `find_deletions` finds it.
+#[derive(Debug)]
+pub(crate) struct Deletion {
+    pub(crate) tombstone: Message,
+    /// The user who deleted the message.
+    pub(crate) by: Uuid,
+}
+
+/// Order in which history is walked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HistoryDirection {
+    /// Newest messages first.
+    Backward,
+    /// Oldest messages first.
+    Forward,
+}
+
 pub(crate) trait Db {
-    fn open(config: &HashMap<ConfigName, ConfigValue>) -> anyhow::Result<Box<dyn DbConnection>>;
+    fn open(config: &HashMap<ConfigName, ConfigValue>) -> Result<Box<dyn DbConnection>>;
 }
 
-pub(crate) trait DbConnection {
-    //fn close(&self) -> anyhow::Result<()>;
-    fn find_user(&self, user_uuid: &Uuid) -> anyhow::Result<User>;
-    fn save_user(&self, user: &User) -> anyhow::Result<()>;
-    fn find_room(&self, room_uuid: &Uuid) -> anyhow::Result<Room>;
-    fn save_room(&self, room: &Room) -> anyhow::Result<()>;
-    fn save_message(&self, room: &Room, message: &Message) -> anyhow::Result<()>;
+pub(crate) trait DbConnection: Send + Sync {
+    //fn close(&self) -> Result<()>;
+    fn find_user(&self, user_uuid: &Uuid) -> Result<Option<User>>;
+    fn find_user_by_address(&self, address: &Address) -> Result<Option<User>>;
+    /// Save `user`, failing with `DbError::AddressTaken` if another user owns its address.
+    fn save_user(&self, user: &User) -> Result<()>;
+    fn find_room(&self, room_uuid: &Uuid) -> Result<Option<Room>>;
+    fn save_room(&self, room: &Room) -> Result<()>;
+    /// List up to `limit` rooms `user_uuid` is a member of, ordered by room uuid
+    /// and starting after the room `after`.
+    ///
+    /// Also returns the `after` of the next page, if there is one.
+    fn list_rooms_for_user(
+        &self,
+        user_uuid: &Uuid,
+        limit: usize,
+        after: Option<Uuid>,
+    ) -> Result<(Vec<Room>, Option<Uuid>)>;
+    /// Delete a room together with all of its messages. Their bytes stay in
+    /// storage until `purge_deleted`.
+    fn delete_room(&self, room_uuid: &Uuid) -> Result<()>;
+    fn save_message(&self, room: &Room, message: &Message) -> Result<()>;
+    fn find_message(&self, room_uuid: &Uuid, message_uuid: &Uuid) -> Result<Option<Message>>;
+    /// Replace a message of a room with `message`, a new revision of it, and
+    /// keep the replaced revision.
+    ///
+    /// Returns `false` if the room has no such message, or it was deleted.
+    fn edit_message(&self, room_uuid: &Uuid, message: &Message) -> Result<bool>;
+    /// Replace a message of a room with `tombstone`, and drop its revisions
+    /// and reactions. Their bytes stay in storage until `purge_deleted`. The
+    /// deletion, by the user `by`, is indexed for `find_deletions`.
+    ///
+    /// Returns `false` if the room has no such message.
+    fn delete_message(&self, room_uuid: &Uuid, tombstone: &Message, by: &Uuid) -> Result<bool>;
+    /// Purge from storage the bytes of the messages deleted since the last
+    /// purge, and of their revisions.
+    ///
+    /// It blocks while rewriting part of the storage, so run it apart from
+    /// requests. Purges run one at a time and take every deletion waiting, so
+    /// it is cheap to call after each of them. Deletions are recorded with the
+    /// data, and those a crash left unpurged are purged on open.
+    fn purge_deleted(&self) -> Result<()>;
+    /// Replace the messages still in the plaintext envelopes of schema
+    /// version 7 migrations with tombstones, drop their plaintext revisions,
+    /// and purge both from storage.
+    ///
+    /// Returns the number of messages replaced. It rewrites all messages, so
+    /// operators run it once clients no longer need the plaintext.
+    fn purge_legacy_messages(&self) -> Result<usize>;
+    /// React to a message with `emoji` on behalf of a user.
+    ///
+    /// Returns `false` if the user already reacted so, or the message is
+    /// missing or deleted.
+    fn add_reaction(&self, message_uuid: &Uuid, user_uuid: &Uuid, emoji: &str) -> Result<bool>;
+    /// Withdraw a reaction. Returns `false` if there was no such reaction.
+    fn remove_reaction(&self, message_uuid: &Uuid, user_uuid: &Uuid, emoji: &str) -> Result<bool>;
+    /// Reactions to a message, as the user who reacted and the emoji.
+    fn find_reactions(&self, message_uuid: &Uuid) -> Result<Vec<(Uuid, String)>>;
+    /// Revisions of a message replaced by edits, oldest first.
+    fn find_revisions(&self, message_uuid: &Uuid) -> Result<Vec<Message>>;
+    /// Find up to `limit` messages of a room whose last edit came after
+    /// `after`, in the order of those edits.
+    ///
+    /// Also returns the `after` of the next page, if there is one.
+    fn find_edits(
+        &self,
+        room_uuid: &Uuid,
+        limit: usize,
+        after: EditCursor,
+    ) -> Result<(Vec<Message>, Option<EditCursor>)>;
+    /// Find up to `limit` messages of a room deleted after `after`, in the
+    /// order of those deletions.
+    ///
+    /// Also returns the `after` of the next page, if there is one.
+    fn find_deletions(
+        &self,
+        room_uuid: &Uuid,
+        limit: usize,
+        after: EditCursor,
+    ) -> Result<(Vec<Deletion>, Option<EditCursor>)>;
+    /// Find up to `limit` messages of a room in `direction`, starting right
+    /// after `after`, or at the newest (oldest) message without it.
+    ///
+    /// Also returns the `after` of the next page, if there is one.
     fn find_messages(
         &self,
         room_uuid: &Uuid,
         limit: usize,
-        at: Option<SystemTime>,
-    ) -> anyhow::Result<(Vec<Message>, Option<SystemTime>)>;
+        after: Option<MessageCursor>,
+        direction: HistoryDirection,
+    ) -> Result<(Vec<Message>, Option<MessageCursor>)>;
+    /// Find up to `limit` messages posted in a room in the thread of
+    /// `root_uuid`, like `find_messages`. The root is not part of its thread.
+    fn find_thread(
+        &self,
+        room_uuid: &Uuid,
+        root_uuid: &Uuid,
+        limit: usize,
+        after: Option<MessageCursor>,
+        direction: HistoryDirection,
+    ) -> Result<(Vec<Message>, Option<MessageCursor>)>;
+    /// Find up to `limit` messages of a room centered on the message
+    /// `message_uuid`, newest first, or `None` if the room has no such message.
+    ///
+    /// Also returns the `after` of the next `HistoryDirection::Backward` page.
+    fn find_messages_around(
+        &self,
+        room_uuid: &Uuid,
+        message_uuid: &Uuid,
+        limit: usize,
+    ) -> Result<Option<(Vec<Message>, Option<MessageCursor>)>>;
+    /// Record a device of a user, if it is new, and return when it was first
+    /// registered.
+    fn register_device(&self, user_uuid: &Uuid, device: &Uuid) -> Result<SystemTime>;
+    /// When the last `Sync` of a device that caught up started.
+    fn find_device_synced(&self, user_uuid: &Uuid, device: &Uuid) -> Result<Option<SystemTime>>;
+    fn save_device_synced(&self, user_uuid: &Uuid, device: &Uuid, time: SystemTime) -> Result<()>;
+    /// Position of the last message of a room acknowledged by a device.
+    fn find_delivery_cursor(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        device: &Uuid,
+    ) -> Result<Option<MessageCursor>>;
+    /// Move the delivery cursor of a device to `cursor`, unless it is already
+    /// there or past it. Returns whether it moved.
+    fn advance_delivery_cursor(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        cursor: &MessageCursor,
+    ) -> Result<bool>;
+    /// Position of the last message of a room read by a user.
+    fn find_read_position(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+    ) -> Result<Option<MessageCursor>>;
+    /// Read positions of all users of a room who read any message.
+    fn find_read_positions(&self, room_uuid: &Uuid) -> Result<Vec<(Uuid, MessageCursor)>>;
+    /// Move the read position of a user to `cursor`, unless it is already
+    /// there or past it. Returns whether it moved.
+    fn advance_read_position(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        cursor: &MessageCursor,
+    ) -> Result<bool>;
+    /// Replace the signed prekey of a device of a user.
+    fn save_signed_prekey(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        prekey: &SignedPrekey,
+    ) -> Result<()>;
+    /// Signed prekeys of the devices of a user, by device.
+    fn find_signed_prekeys(&self, user_uuid: &Uuid) -> Result<Vec<(Uuid, SignedPrekey)>>;
+    /// Add one-time prekeys of a device of a user, replacing those with the
+    /// same ids, unless the device would then hold more than `limit`.
+    ///
+    /// Returns the number of one-time prekeys the device holds.
+    fn save_one_time_prekeys(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        prekeys: &[OneTimePrekey],
+        limit: usize,
+    ) -> Result<usize>;
+    /// Remove and return a one-time prekey of a device of a user, the one
+    /// with the lowest id, so no two callers ever get the same prekey.
+    fn take_one_time_prekey(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+    ) -> Result<Option<OneTimePrekey>>;
+    /// Number of one-time prekeys a device of a user has left.
+    fn count_one_time_prekeys(&self, user_uuid: &Uuid, device: &Uuid) -> Result<usize>;
+    /// Queue a direct message for its recipient device, failing with
+    /// `DbError::Invalid` if `limit` messages of its sender already wait for
+    /// the device.
+    fn save_direct_message(&self, message: &DirectMessage, limit: usize) -> Result<()>;
+    /// Up to `limit` of the direct messages waiting for a device, oldest
+    /// first.
+    fn find_direct_messages(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        limit: usize,
+    ) -> Result<Vec<DirectMessage>>;
+    /// Forget the direct messages `messages` of a device, ignoring those it
+    /// does not have.
+    fn delete_direct_messages(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        messages: &[Uuid],
+    ) -> Result<()>;
 }
@@ -0,0 +1,53 @@
+use shared::types::Address;
+use std::time::SystemTimeError;
+
+/// A specialized `Result` type for database operations.
+pub(crate) type Result<T> = std::result::Result<T, DbError>;
+
+/// Error returned by `Db` and `DbConnection` operations.
+///
+/// Missing entities are not errors: finders return `None` for them.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DbError {
+    /// The address is already used by another user.
+    #[error("address {0} is already taken")]
+    AddressTaken(Address),
+
+    /// A stored record could not be decoded.
+    #[error("corrupt record: {0}")]
+    Corrupt(String),
+
+    /// The value can not be stored as is.
+    #[error("invalid record: {0}")]
+    Invalid(String),
+
+    /// The database was written by a newer version of the server.
+    #[error("database schema version {found} is newer than the supported version {supported}")]
+    SchemaTooNew { found: u32, supported: u32 },
+
+    /// The database is not configured properly.
+    #[error("database misconfigured: {0}")]
+    Config(String),
+
+    /// The storage engine failed.
+    #[error(transparent)]
+    Io(#[from] rocksdb::Error),
+}
+
+impl From<bincode::Error> for DbError {
+    fn from(err: bincode::Error) -> Self {
+        DbError::Corrupt(err.to_string())
+    }
+}
+
+impl From<uuid::Error> for DbError {
+    fn from(err: uuid::Error) -> Self {
+        DbError::Corrupt(err.to_string())
+    }
+}
+
+impl From<SystemTimeError> for DbError {
+    fn from(err: SystemTimeError) -> Self {
+        DbError::Invalid(err.to_string())
+    }
+}
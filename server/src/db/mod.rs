@@ -1,4 +1,11 @@
+#[allow(clippy::module_inception)]
 pub(crate) mod db;
+mod error;
 mod rocksdb;
 
-pub(crate) use db::Db;
+pub(crate) use db::{
+    ConfigName, ConfigValue, Db, DbConnection, Deletion, EditCursor, HistoryDirection,
+    MessageCursor,
+};
+pub(crate) use error::DbError;
+pub(crate) use rocksdb::RocksDb;
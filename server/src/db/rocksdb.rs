@@ -1,183 +1,2002 @@
-use crate::db::db::{ConfigName, ConfigValue, DbConnection};
+use crate::db::db::{
+    ConfigName, ConfigValue, DbConnection, Deletion, EditCursor, HistoryDirection, MessageCursor,
+};
+use crate::db::error::{DbError, Result};
 use crate::db::Db;
-use anyhow::anyhow;
 use bincode::{deserialize, serialize};
-use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, IteratorMode, Options, DB};
-use shared::types::{Message, Room, User};
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, ReadOptions,
+    SliceTransform, WriteBatch, DB,
+};
+use serde::Deserialize;
+use shared::keys::{AgreementKey, OneTimePrekey, PrekeyId, SignedPrekey};
+use shared::protocol::MAX_EMOJI_LEN;
+use shared::types::{Address, Content, DirectMessage, Envelope, IdentityKey, Message, Room, User};
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
+/// Version of the on-disk layout written by this server.
+///
+/// Bumped whenever the layout changes incompatibly, so an older server refuses
+/// to open a database it would misread.
+const SCHEMA_VERSION: u32 = 9;
+
+/// Key of the schema version in `Column::Meta`.
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// Schema version of databases written before the version was recorded: users
+/// without identity keys, and messages under string keys.
+const UNVERSIONED: u32 = 0;
+
+/// Identity key of users migrated from before identity keys. It is no valid
+/// Ed25519 point, so no signature verifies against it and these users can not
+/// log in.
+const NO_IDENTITY_KEY: IdentityKey = {
+    let mut key = [0; 32];
+    key[0] = 2;
+    key
+};
+
 enum Column {
+    /// Database wide settings, such as the schema version.
+    Meta,
     Users,
+    /// Secondary index of `Users`: address to user uuid.
+    UserAddresses,
     Rooms,
+    /// Secondary index of `Rooms`: user uuid followed by room uuid, for every
+    /// member of a room.
+    UserRooms,
+    /// Messages keyed by `message_key`, so the messages of a room are
+    /// contiguous and sorted newest first.
     Messages,
+    /// Secondary index of `Messages`: message uuid to message key.
+    MessageIndex,
+    /// Revisions of messages replaced by edits, keyed by `revision_key`.
+    MessageRevisions,
+    /// Messages posted in threads, keyed by `message_key` with the uuid of
+    /// the thread root in place of the room uuid.
+    ThreadIndex,
+    /// Secondary index of `Messages`: the last edit of each edited message,
+    /// keyed by `edit_key`.
+    EditIndex,
+    /// Secondary index of `Messages`: the deletion of each deleted message,
+    /// keyed like `EditIndex` with the time of the deletion, to the uuid of
+    /// the user who deleted it.
+    DeletionIndex,
+    /// Reactions to messages, keyed by `reaction_key`.
+    Reactions,
+    /// When each device was first registered, keyed by user uuid followed by
+    /// device uuid.
+    Devices,
+    /// When the last `Sync` of each device that caught up started, keyed like
+    /// `Devices`.
+    DeviceSyncs,
+    /// `MessageCursor` of the last message each device acknowledged, keyed by
+    /// `delivery_key`.
+    DeliveryCursors,
+    /// `MessageCursor` of the last message each user read in a room, keyed by
+    /// room uuid followed by user uuid.
+    ReadPositions,
+    /// The current `SignedPrekey` of each device, keyed by `device_key`.
+    SignedPrekeys,
+    /// Public keys of the one-time prekeys of users, keyed by `prekey_key`.
+    OneTimePrekeys,
+    /// Direct messages waiting for their recipient device, keyed by
+    /// `direct_key`.
+    DirectMessages,
+    /// Messages deleted, one by one or with their room, whose bytes
+    /// `purge_deleted` did not purge from storage yet, keyed by `message_key`.
+    PendingPurges,
 }
 
 impl Column {
     fn col_name(col: Column) -> &'static str {
         match col {
+            Column::Meta => "meta",
             Column::Users => "users",
+            Column::UserAddresses => "user_addresses",
             Column::Rooms => "rooms",
+            Column::UserRooms => "user_rooms",
             Column::Messages => "messages",
+            Column::MessageIndex => "message_index",
+            Column::MessageRevisions => "message_revisions",
+            Column::ThreadIndex => "thread_index",
+            Column::EditIndex => "edit_index",
+            Column::DeletionIndex => "deletion_index",
+            Column::Reactions => "reactions",
+            Column::Devices => "devices",
+            Column::DeviceSyncs => "device_syncs",
+            Column::DeliveryCursors => "delivery_cursors",
+            Column::ReadPositions => "read_positions",
+            Column::SignedPrekeys => "signed_prekeys",
+            Column::OneTimePrekeys => "one_time_prekeys",
+            Column::DirectMessages => "direct_messages",
+            Column::PendingPurges => "pending_purges",
         }
     }
 
     fn iter() -> impl Iterator<Item = Column> {
-        [Column::Users, Column::Rooms, Column::Messages].into_iter()
+        [
+            Column::Meta,
+            Column::Users,
+            Column::UserAddresses,
+            Column::Rooms,
+            Column::UserRooms,
+            Column::Messages,
+            Column::MessageIndex,
+            Column::MessageRevisions,
+            Column::ThreadIndex,
+            Column::EditIndex,
+            Column::DeletionIndex,
+            Column::Reactions,
+            Column::Devices,
+            Column::DeviceSyncs,
+            Column::DeliveryCursors,
+            Column::ReadPositions,
+            Column::SignedPrekeys,
+            Column::OneTimePrekeys,
+            Column::DirectMessages,
+            Column::PendingPurges,
+        ]
+        .into_iter()
+    }
+
+    fn options(col: &Column) -> Options {
+        let mut options = Options::default();
+        if let Column::Messages = col {
+            // Seeks only ever look within a single room.
+            options.set_prefix_extractor(SliceTransform::create_fixed_prefix(ROOM_PREFIX_LEN));
+        }
+        options
     }
 }
 
-struct RocksDb {
+pub(crate) struct RocksDb {
     db: DB,
+    /// Serializes `save_user` calls, so two users can not claim the same
+    /// address between the uniqueness check and the write.
+    users_lock: Mutex<()>,
+    /// Serializes room writes, so `Column::UserRooms` always matches the
+    /// members of the last saved room.
+    rooms_lock: Mutex<()>,
+    /// Serializes message edits, deletions and reactions, so no replaced
+    /// revision is lost, no reaction is recorded twice, and neither outlives
+    /// the deletion of its message.
+    messages_lock: Mutex<()>,
+    /// Serializes `take_one_time_prekey` calls, so a one-time prekey is never
+    /// handed out twice.
+    prekeys_lock: Mutex<()>,
+    /// Serializes `save_direct_message` calls, so no user has more direct
+    /// messages waiting for a device than the limit.
+    direct_lock: Mutex<()>,
+    /// Serializes `advance_delivery_cursor` and `advance_read_position` calls,
+    /// so acknowledgements and receipts handled at once never move one back.
+    cursors_lock: Mutex<()>,
+    /// Serializes `purge_deleted` calls, so only one rewrites storage at a
+    /// time while the others wait for it, then purge what is left.
+    purge_lock: Mutex<()>,
 }
 
 impl RocksDb {
-    fn new(path: &Path) -> anyhow::Result<Self> {
+    fn new(path: &Path) -> Result<Self> {
         let mut db_opts = Options::default();
         db_opts.create_if_missing(true);
         db_opts.create_missing_column_families(true);
 
-        Ok(Self {
+        let db = Self {
             db: DB::open_cf_descriptors(
                 &db_opts,
                 path,
                 Column::iter()
-                    .map(|c| ColumnFamilyDescriptor::new(Column::col_name(c), Options::default()))
+                    .map(|c| {
+                        let options = Column::options(&c);
+                        ColumnFamilyDescriptor::new(Column::col_name(c), options)
+                    })
                     .collect::<Vec<_>>(),
             )?,
-        })
+            users_lock: Mutex::new(()),
+            rooms_lock: Mutex::new(()),
+            messages_lock: Mutex::new(()),
+            prekeys_lock: Mutex::new(()),
+            direct_lock: Mutex::new(()),
+            cursors_lock: Mutex::new(()),
+            purge_lock: Mutex::new(()),
+        };
+        db.check_schema_version()?;
+        // Deletions a previous run did not get to purge.
+        db.purge_deleted()?;
+        Ok(db)
+    }
+
+    /// Refuse databases written by a newer server, migrate older ones, and
+    /// stamp new ones with the current schema version.
+    fn check_schema_version(&self) -> Result<()> {
+        let meta = self.column(Column::Meta);
+        match self.db.get_cf(meta, SCHEMA_VERSION_KEY)? {
+            Some(version) => {
+                let found = u32::from_be_bytes(version.as_slice().try_into().map_err(|_| {
+                    DbError::Corrupt(format!("schema version of {} bytes", version.len()))
+                })?);
+                if found > SCHEMA_VERSION {
+                    return Err(DbError::SchemaTooNew {
+                        found,
+                        supported: SCHEMA_VERSION,
+                    });
+                }
+                if found < SCHEMA_VERSION {
+                    self.migrate(found)?;
+                }
+            }
+            None if self.is_empty()? => {
+                self.db
+                    .put_cf(meta, SCHEMA_VERSION_KEY, SCHEMA_VERSION.to_be_bytes())?
+            }
+            None => self.migrate(UNVERSIONED)?,
+        }
+        Ok(())
+    }
+
+    /// Whether the database holds no data yet, as opposed to data written
+    /// before the schema version was recorded.
+    fn is_empty(&self) -> Result<bool> {
+        for column in [Column::Users, Column::Rooms, Column::Messages] {
+            let mut items = self
+                .db
+                .iterator_cf(self.column(column), IteratorMode::Start);
+            if items.next().transpose()?.is_some() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Bring a database written with schema version `from` up to date.
+    fn migrate(&self, from: u32) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        if from < 1 {
+            // Version 1 gave users an identity key and indexed them by address.
+            for item in self
+                .db
+                .iterator_cf(self.column(Column::Users), IteratorMode::Start)
+            {
+                let (key, value) = item?;
+                let user = match deserialize::<User>(&value) {
+                    Ok(user) => user,
+                    Err(_) => {
+                        let user = deserialize::<UserV0>(&value)?.migrate();
+                        batch.put_cf(self.column(Column::Users), &key, serialize(&user)?);
+                        user
+                    }
+                };
+                batch.put_cf(self.column(Column::UserAddresses), &user.address, user.uuid);
+            }
+        }
+        if from < 2 {
+            // Version 2 added the `UserRooms` index.
+            for item in self
+                .db
+                .iterator_cf(self.column(Column::Rooms), IteratorMode::Start)
+            {
+                let (_, room) = item?;
+                let room: Room = deserialize(&room)?;
+                for member in &room.members {
+                    batch.put_cf(
+                        self.column(Column::UserRooms),
+                        user_room_key(member, &room.uuid),
+                        b"",
+                    );
+                }
+            }
+        }
+        if from < 7 {
+            // Version 3 replaced `{room}_{reverse_ts}_{message}` string keys of
+            // `Messages` with `message_key`, version 4 added the `MessageIndex`
+            // index, version 5 added `Message::edited`, version 6 added
+            // `Message::reply_to` and `Message::thread_root`, and version 7
+            // replaced plaintext content with `Message::envelope`. Plaintext
+            // moves to legacy envelopes, which clients still read, until the
+            // operator runs `purge_legacy_messages`.
+            let mut rooms = HashMap::new();
+            for item in self
+                .db
+                .iterator_cf(self.column(Column::Messages), IteratorMode::Start)
+            {
+                let (key, value) = item?;
+                let room_uuid = if from < 3 {
+                    let room = std::str::from_utf8(&key[..key.len().min(36)])
+                        .map_err(|_| DbError::Corrupt("message key is not text".to_string()))?;
+                    Uuid::parse_str(room)?
+                } else {
+                    Uuid::from_slice(&key[..key.len().min(ROOM_PREFIX_LEN)])?
+                };
+                let message = legacy_message(from, &room_uuid, &value)?;
+                let new_key = message_key(&room_uuid, &MessageCursor::of(&message)?);
+                if key[..] != new_key[..] {
+                    batch.delete_cf(self.column(Column::Messages), &key);
+                }
+                batch.put_cf(self.column(Column::Messages), new_key, serialize(&message)?);
+                batch.put_cf(self.column(Column::MessageIndex), message.uuid, new_key);
+                if let Some(key) = edit_key(&room_uuid, &message)? {
+                    batch.put_cf(self.column(Column::EditIndex), key, b"");
+                }
+                rooms.insert(message.uuid, room_uuid);
+            }
+            // Revisions came with version 5.
+            for item in self
+                .db
+                .iterator_cf(self.column(Column::MessageRevisions), IteratorMode::Start)
+            {
+                let (key, value) = item?;
+                let message_uuid = Uuid::from_slice(&key[..key.len().min(16)])?;
+                match rooms.get(&message_uuid) {
+                    Some(room_uuid) => batch.put_cf(
+                        self.column(Column::MessageRevisions),
+                        &key,
+                        serialize(&legacy_message(from, room_uuid, &value)?)?,
+                    ),
+                    None => batch.delete_cf(self.column(Column::MessageRevisions), key),
+                }
+            }
+        } else if from < 8 {
+            // Version 8 added the `EditIndex` index, which the loop above
+            // fills for older versions.
+            for item in self
+                .db
+                .iterator_cf(self.column(Column::Messages), IteratorMode::Start)
+            {
+                let (key, value) = item?;
+                let room_uuid = Uuid::from_slice(&key[..ROOM_PREFIX_LEN])?;
+                if let Some(key) = edit_key(&room_uuid, &deserialize(&value)?)? {
+                    batch.put_cf(self.column(Column::EditIndex), key, b"");
+                }
+            }
+        }
+        if from < 9 {
+            // Version 9 keyed prekeys by device as well as by user. Devices
+            // upload new ones once they see none are left.
+            for column in [Column::SignedPrekeys, Column::OneTimePrekeys] {
+                let column = self.column(column);
+                for item in self.db.iterator_cf(column, IteratorMode::Start) {
+                    let (key, _) = item?;
+                    batch.delete_cf(column, key);
+                }
+            }
+        }
+        batch.put_cf(
+            self.column(Column::Meta),
+            SCHEMA_VERSION_KEY,
+            SCHEMA_VERSION.to_be_bytes(),
+        );
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Replace `deleted`, stored at `key`, with `tombstone` in `batch`, and
+    /// drop its revisions and reactions.
+    fn tombstone(
+        &self,
+        batch: &mut WriteBatch,
+        room_uuid: &Uuid,
+        key: &[u8],
+        deleted: &Message,
+        tombstone: &Message,
+    ) -> Result<()> {
+        batch.put_cf(self.column(Column::Messages), key, serialize(tombstone)?);
+        if let Some(key) = edit_key(room_uuid, deleted)? {
+            batch.delete_cf(self.column(Column::EditIndex), key);
+        }
+        batch.delete_range_cf(
+            self.column(Column::MessageRevisions),
+            tombstone.uuid.as_bytes().to_vec(),
+            key_range_end(tombstone.uuid.as_bytes(), REVISION_KEY_LEN),
+        );
+        batch.delete_range_cf(
+            self.column(Column::Reactions),
+            tombstone.uuid.as_bytes().to_vec(),
+            key_range_end(tombstone.uuid.as_bytes(), REACTION_KEY_MAX_LEN),
+        );
+        Ok(())
+    }
+
+    /// The message an `EditIndex` or `DeletionIndex` entry at `cursor` is
+    /// about, which must exist.
+    fn indexed_message(&self, room_uuid: &Uuid, cursor: &EditCursor) -> Result<Message> {
+        self.find_message(room_uuid, &cursor.message)?
+            .ok_or_else(|| {
+                DbError::Corrupt(format!(
+                    "change of message {} indexed but missing",
+                    cursor.message
+                ))
+            })
     }
 
     fn column(&self, column: Column) -> &ColumnFamily {
         match column {
+            Column::Meta => self.db.cf_handle(Column::col_name(Column::Meta)).unwrap(),
             Column::Users => self.db.cf_handle(Column::col_name(Column::Users)).unwrap(),
+            Column::UserAddresses => self
+                .db
+                .cf_handle(Column::col_name(Column::UserAddresses))
+                .unwrap(),
             Column::Rooms => self.db.cf_handle(Column::col_name(Column::Rooms)).unwrap(),
+            Column::UserRooms => self
+                .db
+                .cf_handle(Column::col_name(Column::UserRooms))
+                .unwrap(),
             Column::Messages => self
                 .db
                 .cf_handle(Column::col_name(Column::Messages))
                 .unwrap(),
+            Column::MessageIndex => self
+                .db
+                .cf_handle(Column::col_name(Column::MessageIndex))
+                .unwrap(),
+            Column::MessageRevisions => self
+                .db
+                .cf_handle(Column::col_name(Column::MessageRevisions))
+                .unwrap(),
+            Column::ThreadIndex => self
+                .db
+                .cf_handle(Column::col_name(Column::ThreadIndex))
+                .unwrap(),
+            Column::EditIndex => self
+                .db
+                .cf_handle(Column::col_name(Column::EditIndex))
+                .unwrap(),
+            Column::DeletionIndex => self
+                .db
+                .cf_handle(Column::col_name(Column::DeletionIndex))
+                .unwrap(),
+            Column::Reactions => self
+                .db
+                .cf_handle(Column::col_name(Column::Reactions))
+                .unwrap(),
+            Column::Devices => self
+                .db
+                .cf_handle(Column::col_name(Column::Devices))
+                .unwrap(),
+            Column::DeviceSyncs => self
+                .db
+                .cf_handle(Column::col_name(Column::DeviceSyncs))
+                .unwrap(),
+            Column::DeliveryCursors => self
+                .db
+                .cf_handle(Column::col_name(Column::DeliveryCursors))
+                .unwrap(),
+            Column::ReadPositions => self
+                .db
+                .cf_handle(Column::col_name(Column::ReadPositions))
+                .unwrap(),
+            Column::SignedPrekeys => self
+                .db
+                .cf_handle(Column::col_name(Column::SignedPrekeys))
+                .unwrap(),
+            Column::OneTimePrekeys => self
+                .db
+                .cf_handle(Column::col_name(Column::OneTimePrekeys))
+                .unwrap(),
+            Column::DirectMessages => self
+                .db
+                .cf_handle(Column::col_name(Column::DirectMessages))
+                .unwrap(),
+            Column::PendingPurges => self
+                .db
+                .cf_handle(Column::col_name(Column::PendingPurges))
+                .unwrap(),
         }
     }
 }
 
+/// A key and its value, as read from RocksDB.
+type Entry = (Box<[u8]>, Box<[u8]>);
+
+impl RocksDb {
+    /// Walk up to `limit` entries of `column`, whose keys are laid out as
+    /// `message_key` with `prefix` in place of the room uuid, the way
+    /// `find_messages` walks history.
+    ///
+    /// Also returns whether more entries follow.
+    fn message_entries(
+        &self,
+        column: Column,
+        prefix: &Uuid,
+        limit: usize,
+        after: Option<MessageCursor>,
+        direction: HistoryDirection,
+    ) -> Result<(Vec<Entry>, bool)> {
+        // Keys sort newest first, so going backward in time is going forward
+        // in keys.
+        let mut options = room_messages(prefix);
+        let mode = match direction {
+            HistoryDirection::Backward => {
+                if let Some(cursor) = after {
+                    // The smallest key greater than the cursor's.
+                    options.set_iterate_lower_bound(
+                        [&message_key(prefix, &cursor)[..], &[0]].concat(),
+                    );
+                }
+                IteratorMode::Start
+            }
+            HistoryDirection::Forward => {
+                if let Some(cursor) = after {
+                    options.set_iterate_upper_bound(message_key(prefix, &cursor));
+                }
+                IteratorMode::End
+            }
+        };
+        let iter = self.db.iterator_cf_opt(self.column(column), options, mode);
+
+        let mut entries = Vec::with_capacity(limit);
+        for item in iter {
+            if entries.len() == limit {
+                return Ok((entries, true));
+            }
+            entries.push(item?);
+        }
+        Ok((entries, false))
+    }
+}
+
 impl Db for RocksDb {
-    fn open(config: &HashMap<ConfigName, ConfigValue>) -> anyhow::Result<Box<dyn DbConnection>> {
+    fn open(config: &HashMap<ConfigName, ConfigValue>) -> Result<Box<dyn DbConnection>> {
         if let Some(ConfigValue::Path(path)) = config.get(&ConfigName::Path) {
-            match RocksDb::new(path.as_path()) {
-                Ok(db) => Ok(Box::new(db)),
-                Err(e) => Err(anyhow!("Failed to open RocksDB: {}", e)),
-            }
+            Ok(Box::new(RocksDb::new(path.as_path())?))
         } else {
-            Err(anyhow!("Path to DB not setup"))
+            Err(DbError::Config("Path to DB not setup".to_string()))
         }
     }
 }
 
 impl DbConnection for RocksDb {
-    fn find_user(&self, user_uuid: &Uuid) -> anyhow::Result<User> {
-        Ok(deserialize(
-            &self
-                .db
-                .get_cf(self.column(Column::Users), user_uuid)?
-                .unwrap(),
-        )?)
+    fn find_user(&self, user_uuid: &Uuid) -> Result<Option<User>> {
+        match self.db.get_cf(self.column(Column::Users), user_uuid)? {
+            Some(user) => Ok(Some(deserialize(&user)?)),
+            None => Ok(None),
+        }
     }
 
-    fn save_user(&self, user: &User) -> anyhow::Result<()> {
-        self.db
-            .put_cf(&self.column(Column::Users), user.uuid, serialize(user)?)?;
+    fn find_user_by_address(&self, address: &Address) -> Result<Option<User>> {
+        match self
+            .db
+            .get_cf(self.column(Column::UserAddresses), address)?
+        {
+            Some(user_uuid) => self.find_user(&Uuid::from_slice(&user_uuid)?),
+            None => Ok(None),
+        }
+    }
+
+    fn save_user(&self, user: &User) -> Result<()> {
+        let _guard = self.users_lock.lock().unwrap();
+
+        if let Some(owner) = self
+            .db
+            .get_cf(self.column(Column::UserAddresses), &user.address)?
+        {
+            if owner != user.uuid.as_bytes() {
+                return Err(DbError::AddressTaken(user.address.clone()));
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        // Drop the index entry of the previous address, if it changed.
+        if let Some(previous) = self.db.get_cf(self.column(Column::Users), user.uuid)? {
+            let previous: User = deserialize(&previous)?;
+            if previous.address != user.address {
+                batch.delete_cf(self.column(Column::UserAddresses), &previous.address);
+            }
+        }
+        batch.put_cf(self.column(Column::Users), user.uuid, serialize(user)?);
+        batch.put_cf(self.column(Column::UserAddresses), &user.address, user.uuid);
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn find_room(&self, room_uuid: &Uuid) -> Result<Option<Room>> {
+        match self.db.get_cf(self.column(Column::Rooms), room_uuid)? {
+            Some(room) => Ok(Some(deserialize(&room)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_room(&self, room: &Room) -> Result<()> {
+        let _guard = self.rooms_lock.lock().unwrap();
+
+        let mut batch = WriteBatch::default();
+        // Drop the index entries, delivery cursors and read positions of members
+        // who are gone.
+        if let Some(previous) = self.find_room(&room.uuid)? {
+            for member in previous.members.difference(&room.members) {
+                batch.delete_cf(
+                    self.column(Column::UserRooms),
+                    user_room_key(member, &room.uuid),
+                );
+                let prefix = [&room.uuid.as_bytes()[..], member.as_bytes()].concat();
+                batch.delete_range_cf(
+                    self.column(Column::DeliveryCursors),
+                    prefix.clone(),
+                    key_range_end(&prefix, DELIVERY_KEY_LEN),
+                );
+                batch.delete_cf(
+                    self.column(Column::ReadPositions),
+                    read_position_key(&room.uuid, member),
+                );
+            }
+        }
+        for member in &room.members {
+            batch.put_cf(
+                self.column(Column::UserRooms),
+                user_room_key(member, &room.uuid),
+                b"",
+            );
+        }
+        batch.put_cf(self.column(Column::Rooms), room.uuid, serialize(room)?);
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn list_rooms_for_user(
+        &self,
+        user_uuid: &Uuid,
+        limit: usize,
+        after: Option<Uuid>,
+    ) -> Result<(Vec<Room>, Option<Uuid>)> {
+        let prefix = user_uuid.as_bytes();
+        let start = user_room_key(user_uuid, &after.unwrap_or(Uuid::nil()));
+        let iter = self.db.iterator_cf(
+            self.column(Column::UserRooms),
+            IteratorMode::From(&start, Direction::Forward),
+        );
+
+        let mut rooms: Vec<Room> = Vec::with_capacity(limit);
+        for item in iter {
+            let (key, _) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let room_uuid = Uuid::from_slice(&key[prefix.len()..])?;
+            if Some(room_uuid) == after {
+                continue;
+            }
+            if rooms.len() == limit {
+                let next = rooms.last().map(|room| room.uuid);
+                return Ok((rooms, next));
+            }
+            let room = self.find_room(&room_uuid)?.ok_or_else(|| {
+                DbError::Corrupt(format!(
+                    "user {user_uuid} indexed in missing room {room_uuid}"
+                ))
+            })?;
+            rooms.push(room);
+        }
+        Ok((rooms, None))
+    }
+
+    fn delete_room(&self, room_uuid: &Uuid) -> Result<()> {
+        let _guard = self.rooms_lock.lock().unwrap();
+
+        let mut batch = WriteBatch::default();
+        if let Some(room) = self.find_room(room_uuid)? {
+            for member in &room.members {
+                batch.delete_cf(
+                    self.column(Column::UserRooms),
+                    user_room_key(member, room_uuid),
+                );
+            }
+        }
+        batch.delete_cf(self.column(Column::Rooms), room_uuid);
+        for item in self.db.iterator_cf_opt(
+            self.column(Column::Messages),
+            room_messages(room_uuid),
+            IteratorMode::Start,
+        ) {
+            let (key, _) = item?;
+            let message_uuid = &key[ROOM_PREFIX_LEN + 8..];
+            batch.put_cf(self.column(Column::PendingPurges), &key, b"");
+            batch.delete_cf(self.column(Column::MessageIndex), message_uuid);
+            batch.delete_range_cf(
+                self.column(Column::MessageRevisions),
+                message_uuid.to_vec(),
+                key_range_end(message_uuid, REVISION_KEY_LEN),
+            );
+            // Threads never leave their room, so this drops the thread index
+            // entries of the room.
+            batch.delete_range_cf(
+                self.column(Column::ThreadIndex),
+                message_uuid.to_vec(),
+                key_range_end(message_uuid, MESSAGE_KEY_LEN),
+            );
+            batch.delete_range_cf(
+                self.column(Column::Reactions),
+                message_uuid.to_vec(),
+                key_range_end(message_uuid, REACTION_KEY_MAX_LEN),
+            );
+        }
+        batch.delete_range_cf(
+            self.column(Column::Messages),
+            room_uuid.as_bytes().to_vec(),
+            room_messages_end(room_uuid),
+        );
+        for column in [Column::EditIndex, Column::DeletionIndex] {
+            batch.delete_range_cf(
+                self.column(column),
+                room_uuid.as_bytes().to_vec(),
+                key_range_end(room_uuid.as_bytes(), EDIT_KEY_LEN),
+            );
+        }
+        batch.delete_range_cf(
+            self.column(Column::DeliveryCursors),
+            room_uuid.as_bytes().to_vec(),
+            key_range_end(room_uuid.as_bytes(), DELIVERY_KEY_LEN),
+        );
+        batch.delete_range_cf(
+            self.column(Column::ReadPositions),
+            room_uuid.as_bytes().to_vec(),
+            key_range_end(room_uuid.as_bytes(), READ_POSITION_KEY_LEN),
+        );
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn save_message(&self, room: &Room, message: &Message) -> Result<()> {
+        let cursor = MessageCursor::of(message)?;
+        let key = message_key(&room.uuid, &cursor);
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.column(Column::Messages), key, serialize(message)?);
+        batch.put_cf(self.column(Column::MessageIndex), message.uuid, key);
+        if let Some(root) = &message.thread_root {
+            batch.put_cf(
+                self.column(Column::ThreadIndex),
+                message_key(root, &cursor),
+                b"",
+            );
+        }
+        self.db.write(batch)?;
         Ok(())
     }
 
-    fn find_room(&self, room_uuid: &Uuid) -> anyhow::Result<Room> {
-        Ok(deserialize(
-            &self
+    fn find_message(&self, room_uuid: &Uuid, message_uuid: &Uuid) -> Result<Option<Message>> {
+        let Some(key) = self
+            .db
+            .get_cf(self.column(Column::MessageIndex), message_uuid)?
+        else {
+            return Ok(None);
+        };
+        if !key.starts_with(room_uuid.as_bytes()) {
+            return Ok(None);
+        }
+        match self.db.get_cf(self.column(Column::Messages), &key)? {
+            Some(message) => Ok(Some(deserialize(&message)?)),
+            None => Err(DbError::Corrupt(format!(
+                "message {message_uuid} indexed but missing"
+            ))),
+        }
+    }
+
+    fn edit_message(&self, room_uuid: &Uuid, message: &Message) -> Result<bool> {
+        let _guard = self.messages_lock.lock().unwrap();
+
+        let key = message_key(room_uuid, &MessageCursor::of(message)?);
+        let Some(previous) = self.db.get_cf(self.column(Column::Messages), key)? else {
+            return Ok(false);
+        };
+        // A deletion may have won the race against this edit.
+        let replaced: Message = deserialize(&previous)?;
+        if replaced.is_deleted() {
+            return Ok(false);
+        }
+        let revision = match self
+            .db
+            .iterator_cf_opt(
+                self.column(Column::MessageRevisions),
+                message_revisions(&message.uuid),
+                IteratorMode::End,
+            )
+            .next()
+        {
+            Some(item) => {
+                let (key, _) = item?;
+                let last = key[16..].try_into().map_err(|_| {
+                    DbError::Corrupt(format!("revision key of {} bytes", key.len()))
+                })?;
+                u32::from_be_bytes(last) + 1
+            }
+            None => 0,
+        };
+
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            self.column(Column::MessageRevisions),
+            revision_key(&message.uuid, revision),
+            previous,
+        );
+        batch.put_cf(self.column(Column::Messages), key, serialize(message)?);
+        if let Some(key) = edit_key(room_uuid, &replaced)? {
+            batch.delete_cf(self.column(Column::EditIndex), key);
+        }
+        if let Some(key) = edit_key(room_uuid, message)? {
+            batch.put_cf(self.column(Column::EditIndex), key, b"");
+        }
+        self.db.write(batch)?;
+        Ok(true)
+    }
+
+    fn delete_message(&self, room_uuid: &Uuid, tombstone: &Message, by: &Uuid) -> Result<bool> {
+        let _guard = self.messages_lock.lock().unwrap();
+
+        let key = message_key(room_uuid, &MessageCursor::of(tombstone)?);
+        let Some(deleted) = self.db.get_cf(self.column(Column::Messages), key)? else {
+            return Ok(false);
+        };
+        let mut batch = WriteBatch::default();
+        self.tombstone(
+            &mut batch,
+            room_uuid,
+            &key,
+            &deserialize(&deleted)?,
+            tombstone,
+        )?;
+        let deletion = EditCursor {
+            message: tombstone.uuid,
+            ..EditCursor::before(SystemTime::now())?
+        };
+        batch.put_cf(
+            self.column(Column::DeletionIndex),
+            edit_cursor_key(room_uuid, &deletion),
+            by.as_bytes(),
+        );
+        batch.put_cf(self.column(Column::PendingPurges), key, b"");
+        self.db.write(batch)?;
+        Ok(true)
+    }
+
+    fn purge_legacy_messages(&self) -> Result<usize> {
+        let _guard = self.messages_lock.lock().unwrap();
+
+        let mut batch = WriteBatch::default();
+        let mut purged = 0;
+        for item in self
+            .db
+            .iterator_cf(self.column(Column::Messages), IteratorMode::Start)
+        {
+            let (key, value) = item?;
+            let message: Message = deserialize(&value)?;
+            if !message.envelope.as_ref().is_some_and(Envelope::is_legacy) {
+                continue;
+            }
+            let room_uuid = Uuid::from_slice(&key[..ROOM_PREFIX_LEN])?;
+            self.tombstone(&mut batch, &room_uuid, &key, &message, &message.tombstone())?;
+            purged += 1;
+        }
+        // Messages edited since the migration keep their plaintext revisions.
+        for item in self
+            .db
+            .iterator_cf(self.column(Column::MessageRevisions), IteratorMode::Start)
+        {
+            let (key, value) = item?;
+            let revision: Message = deserialize(&value)?;
+            if revision.envelope.as_ref().is_some_and(Envelope::is_legacy) {
+                batch.delete_cf(self.column(Column::MessageRevisions), key);
+            }
+        }
+        self.db.write(batch)?;
+
+        // As in `purge_deleted`, but over the whole columns.
+        for column in Column::iter() {
+            self.db.flush_cf(self.column(column))?;
+        }
+        for column in [Column::Messages, Column::MessageRevisions] {
+            self.db
+                .compact_range_cf(self.column(column), None::<&[u8]>, None::<&[u8]>);
+        }
+        Ok(purged)
+    }
+
+    fn purge_deleted(&self) -> Result<()> {
+        let _guard = self.purge_lock.lock().unwrap();
+
+        let mut pending = Vec::new();
+        for item in self
+            .db
+            .iterator_cf(self.column(Column::PendingPurges), IteratorMode::Start)
+        {
+            let (key, _) = item?;
+            pending.push(key);
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // Overwritten and deleted values stay in memtables and SST files until
+        // a compaction rewrites their range, and in the write-ahead log until
+        // every column with writes in it is flushed. Values an open iterator
+        // still sees survive until it is dropped.
+        for column in Column::iter() {
+            self.db.flush_cf(self.column(column))?;
+        }
+        // Keys sort by room, so a deleted room is compacted in one go.
+        for room in pending.chunk_by(|a, b| a[..ROOM_PREFIX_LEN] == b[..ROOM_PREFIX_LEN]) {
+            self.db
+                .compact_range_cf(self.column(Column::Messages), room.first(), room.last());
+        }
+        let mut batch = WriteBatch::default();
+        for key in &pending {
+            let message_uuid = &key[ROOM_PREFIX_LEN + 8..];
+            self.db.compact_range_cf(
+                self.column(Column::MessageRevisions),
+                Some(message_uuid.to_vec()),
+                Some(key_range_end(message_uuid, REVISION_KEY_LEN)),
+            );
+            batch.delete_cf(self.column(Column::PendingPurges), key);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn find_revisions(&self, message_uuid: &Uuid) -> Result<Vec<Message>> {
+        let mut revisions = Vec::new();
+        for item in self.db.iterator_cf_opt(
+            self.column(Column::MessageRevisions),
+            message_revisions(message_uuid),
+            IteratorMode::Start,
+        ) {
+            let (_, revision) = item?;
+            revisions.push(deserialize(&revision)?);
+        }
+        Ok(revisions)
+    }
+
+    fn find_edits(
+        &self,
+        room_uuid: &Uuid,
+        limit: usize,
+        after: EditCursor,
+    ) -> Result<(Vec<Message>, Option<EditCursor>)> {
+        let iter = self.db.iterator_cf_opt(
+            self.column(Column::EditIndex),
+            room_changes(room_uuid, &after),
+            IteratorMode::Start,
+        );
+
+        let mut messages = Vec::with_capacity(limit);
+        let mut last = None;
+        for item in iter {
+            if messages.len() == limit {
+                return Ok((messages, last));
+            }
+            let (key, _) = item?;
+            let cursor = change_cursor(&key)?;
+            messages.push(self.indexed_message(room_uuid, &cursor)?);
+            last = Some(cursor);
+        }
+        Ok((messages, None))
+    }
+
+    fn find_deletions(
+        &self,
+        room_uuid: &Uuid,
+        limit: usize,
+        after: EditCursor,
+    ) -> Result<(Vec<Deletion>, Option<EditCursor>)> {
+        let iter = self.db.iterator_cf_opt(
+            self.column(Column::DeletionIndex),
+            room_changes(room_uuid, &after),
+            IteratorMode::Start,
+        );
+
+        let mut deletions = Vec::with_capacity(limit);
+        let mut last = None;
+        for item in iter {
+            if deletions.len() == limit {
+                return Ok((deletions, last));
+            }
+            let (key, by) = item?;
+            let cursor = change_cursor(&key)?;
+            deletions.push(Deletion {
+                tombstone: self.indexed_message(room_uuid, &cursor)?,
+                by: Uuid::from_slice(&by)?,
+            });
+            last = Some(cursor);
+        }
+        Ok((deletions, None))
+    }
+
+    fn add_reaction(&self, message_uuid: &Uuid, user_uuid: &Uuid, emoji: &str) -> Result<bool> {
+        let key = reaction_key(message_uuid, user_uuid, emoji)?;
+        let _guard = self.messages_lock.lock().unwrap();
+
+        // A deletion may have won the race against this reaction.
+        let deleted = match self
+            .db
+            .get_cf(self.column(Column::MessageIndex), message_uuid)?
+        {
+            Some(message_key) => {
+                match self.db.get_cf(self.column(Column::Messages), message_key)? {
+                    Some(message) => deserialize::<Message>(&message)?.is_deleted(),
+                    None => true,
+                }
+            }
+            None => true,
+        };
+        let reactions = self.column(Column::Reactions);
+        if deleted || self.db.get_cf(reactions, &key)?.is_some() {
+            return Ok(false);
+        }
+        self.db.put_cf(reactions, key, b"")?;
+        Ok(true)
+    }
+
+    fn remove_reaction(&self, message_uuid: &Uuid, user_uuid: &Uuid, emoji: &str) -> Result<bool> {
+        let key = reaction_key(message_uuid, user_uuid, emoji)?;
+        let _guard = self.messages_lock.lock().unwrap();
+
+        let reactions = self.column(Column::Reactions);
+        if self.db.get_cf(reactions, &key)?.is_none() {
+            return Ok(false);
+        }
+        self.db.delete_cf(reactions, key)?;
+        Ok(true)
+    }
+
+    fn find_reactions(&self, message_uuid: &Uuid) -> Result<Vec<(Uuid, String)>> {
+        let mut options = ReadOptions::default();
+        options.set_iterate_lower_bound(message_uuid.as_bytes().to_vec());
+        options
+            .set_iterate_upper_bound(key_range_end(message_uuid.as_bytes(), REACTION_KEY_MAX_LEN));
+        let mut reactions = Vec::new();
+        for item in
+            self.db
+                .iterator_cf_opt(self.column(Column::Reactions), options, IteratorMode::Start)
+        {
+            let (key, _) = item?;
+            let emoji = std::str::from_utf8(&key[32..])
+                .map_err(|_| DbError::Corrupt("reaction is not text".to_string()))?;
+            reactions.push((Uuid::from_slice(&key[16..32])?, emoji.to_string()));
+        }
+        Ok(reactions)
+    }
+
+    fn find_messages(
+        &self,
+        room_uuid: &Uuid,
+        limit: usize,
+        after: Option<MessageCursor>,
+        direction: HistoryDirection,
+    ) -> Result<(Vec<Message>, Option<MessageCursor>)> {
+        let (entries, more) =
+            self.message_entries(Column::Messages, room_uuid, limit, after, direction)?;
+        let messages = entries
+            .iter()
+            .map(|(_, value)| deserialize(value))
+            .collect::<bincode::Result<Vec<Message>>>()?;
+        let next = match messages.last() {
+            Some(last) if more => Some(MessageCursor::of(last)?),
+            _ => None,
+        };
+        Ok((messages, next))
+    }
+
+    fn find_thread(
+        &self,
+        room_uuid: &Uuid,
+        root_uuid: &Uuid,
+        limit: usize,
+        after: Option<MessageCursor>,
+        direction: HistoryDirection,
+    ) -> Result<(Vec<Message>, Option<MessageCursor>)> {
+        let (entries, more) =
+            self.message_entries(Column::ThreadIndex, root_uuid, limit, after, direction)?;
+        let mut messages = Vec::with_capacity(entries.len());
+        for (key, _) in entries {
+            let mut message_key = key.to_vec();
+            message_key[..ROOM_PREFIX_LEN].copy_from_slice(room_uuid.as_bytes());
+            match self
                 .db
-                .get_cf(self.column(Column::Rooms), room_uuid)?
-                .unwrap(),
-        )?)
+                .get_cf(self.column(Column::Messages), &message_key)?
+            {
+                Some(message) => messages.push(deserialize::<Message>(&message)?),
+                None => {
+                    return Err(DbError::Corrupt(format!(
+                        "message {} of thread {root_uuid} indexed but missing",
+                        Uuid::from_slice(&key[ROOM_PREFIX_LEN + 8..])?
+                    )))
+                }
+            }
+        }
+        let next = match messages.last() {
+            Some(last) if more => Some(MessageCursor::of(last)?),
+            _ => None,
+        };
+        Ok((messages, next))
     }
 
-    fn save_room(&self, room: &Room) -> anyhow::Result<()> {
+    fn find_messages_around(
+        &self,
+        room_uuid: &Uuid,
+        message_uuid: &Uuid,
+        limit: usize,
+    ) -> Result<Option<(Vec<Message>, Option<MessageCursor>)>> {
+        let Some(anchor) = self.find_message(room_uuid, message_uuid)? else {
+            return Ok(None);
+        };
+        let cursor = MessageCursor::of(&anchor)?;
+
+        let newer_limit = limit.saturating_sub(1) / 2;
+        let older_limit = limit.saturating_sub(1) - newer_limit;
+        let (mut messages, _) = self.find_messages(
+            room_uuid,
+            newer_limit,
+            Some(cursor),
+            HistoryDirection::Forward,
+        )?;
+        messages.reverse();
+        messages.push(anchor);
+        let (older, next) = if older_limit > 0 {
+            self.find_messages(
+                room_uuid,
+                older_limit,
+                Some(cursor),
+                HistoryDirection::Backward,
+            )?
+        } else {
+            // Whether older messages exist is unknown, let the client ask.
+            (Vec::new(), Some(cursor))
+        };
+        messages.extend(older);
+        Ok(Some((messages, next)))
+    }
+
+    fn register_device(&self, user_uuid: &Uuid, device: &Uuid) -> Result<SystemTime> {
+        let key = device_key(user_uuid, device);
+        if let Some(created) = self.db.get_cf(self.column(Column::Devices), key)? {
+            return Ok(deserialize(&created)?);
+        }
+        let created = SystemTime::now();
         self.db
-            .put_cf(&self.column(Column::Rooms), room.uuid, serialize(room)?)?;
+            .put_cf(self.column(Column::Devices), key, serialize(&created)?)?;
+        Ok(created)
+    }
+
+    fn find_device_synced(&self, user_uuid: &Uuid, device: &Uuid) -> Result<Option<SystemTime>> {
+        let key = device_key(user_uuid, device);
+        match self.db.get_cf(self.column(Column::DeviceSyncs), key)? {
+            Some(time) => Ok(Some(deserialize(&time)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_device_synced(&self, user_uuid: &Uuid, device: &Uuid, time: SystemTime) -> Result<()> {
+        let key = device_key(user_uuid, device);
+        self.db
+            .put_cf(self.column(Column::DeviceSyncs), key, serialize(&time)?)?;
         Ok(())
     }
 
-    fn save_message(&self, room: &Room, message: &Message) -> anyhow::Result<()> {
-        let reverse_ts = u128::MAX - message.created.duration_since(UNIX_EPOCH)?.as_millis();
+    fn find_delivery_cursor(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        device: &Uuid,
+    ) -> Result<Option<MessageCursor>> {
+        match self.db.get_cf(
+            self.column(Column::DeliveryCursors),
+            delivery_key(room_uuid, user_uuid, device),
+        )? {
+            Some(cursor) => Ok(Some(deserialize(&cursor)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn advance_delivery_cursor(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        cursor: &MessageCursor,
+    ) -> Result<bool> {
+        let _guard = self.cursors_lock.lock().unwrap();
+        let current = self.find_delivery_cursor(room_uuid, user_uuid, device)?;
+        if current.is_some_and(|current| current >= *cursor) {
+            return Ok(false);
+        }
         self.db.put_cf(
-            &self.column(Column::Messages),
-            format!("{}_{reverse_ts}_{}", room.uuid, message.uuid),
+            self.column(Column::DeliveryCursors),
+            delivery_key(room_uuid, user_uuid, device),
+            serialize(cursor)?,
+        )?;
+        Ok(true)
+    }
+
+    fn find_read_position(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+    ) -> Result<Option<MessageCursor>> {
+        match self.db.get_cf(
+            self.column(Column::ReadPositions),
+            read_position_key(room_uuid, user_uuid),
+        )? {
+            Some(cursor) => Ok(Some(deserialize(&cursor)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn find_read_positions(&self, room_uuid: &Uuid) -> Result<Vec<(Uuid, MessageCursor)>> {
+        let mut options = ReadOptions::default();
+        options.set_iterate_lower_bound(room_uuid.as_bytes().to_vec());
+        options.set_iterate_upper_bound(key_range_end(room_uuid.as_bytes(), READ_POSITION_KEY_LEN));
+        let mut positions = Vec::new();
+        for item in self.db.iterator_cf_opt(
+            self.column(Column::ReadPositions),
+            options,
+            IteratorMode::Start,
+        ) {
+            let (key, cursor) = item?;
+            positions.push((Uuid::from_slice(&key[16..])?, deserialize(&cursor)?));
+        }
+        Ok(positions)
+    }
+
+    fn advance_read_position(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        cursor: &MessageCursor,
+    ) -> Result<bool> {
+        let _guard = self.cursors_lock.lock().unwrap();
+        let current = self.find_read_position(room_uuid, user_uuid)?;
+        if current.is_some_and(|current| current >= *cursor) {
+            return Ok(false);
+        }
+        self.db.put_cf(
+            self.column(Column::ReadPositions),
+            read_position_key(room_uuid, user_uuid),
+            serialize(cursor)?,
+        )?;
+        Ok(true)
+    }
+
+    fn save_signed_prekey(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        prekey: &SignedPrekey,
+    ) -> Result<()> {
+        self.db.put_cf(
+            self.column(Column::SignedPrekeys),
+            device_key(user_uuid, device),
+            serialize(prekey)?,
+        )?;
+        Ok(())
+    }
+
+    fn find_signed_prekeys(&self, user_uuid: &Uuid) -> Result<Vec<(Uuid, SignedPrekey)>> {
+        let mut options = ReadOptions::default();
+        options.set_iterate_lower_bound(user_uuid.as_bytes().to_vec());
+        options.set_iterate_upper_bound(key_range_end(user_uuid.as_bytes(), 32));
+        let mut prekeys = Vec::new();
+        for item in self.db.iterator_cf_opt(
+            self.column(Column::SignedPrekeys),
+            options,
+            IteratorMode::Start,
+        ) {
+            let (key, prekey) = item?;
+            prekeys.push((Uuid::from_slice(&key[16..])?, deserialize(&prekey)?));
+        }
+        Ok(prekeys)
+    }
+
+    fn save_one_time_prekeys(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        prekeys: &[OneTimePrekey],
+        limit: usize,
+    ) -> Result<usize> {
+        let _guard = self.prekeys_lock.lock().unwrap();
+
+        let stored = self.count_one_time_prekeys(user_uuid, device)?;
+        if stored + prekeys.len() > limit {
+            return Err(DbError::Invalid(format!(
+                "at most {limit} one-time prekeys per device"
+            )));
+        }
+        let mut batch = WriteBatch::default();
+        for prekey in prekeys {
+            batch.put_cf(
+                self.column(Column::OneTimePrekeys),
+                prekey_key(user_uuid, device, prekey.id),
+                prekey.key,
+            );
+        }
+        self.db.write(batch)?;
+        self.count_one_time_prekeys(user_uuid, device)
+    }
+
+    fn take_one_time_prekey(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+    ) -> Result<Option<OneTimePrekey>> {
+        let _guard = self.prekeys_lock.lock().unwrap();
+
+        let column = self.column(Column::OneTimePrekeys);
+        let Some(item) = self
+            .db
+            .iterator_cf_opt(
+                column,
+                device_prekeys(user_uuid, device),
+                IteratorMode::Start,
+            )
+            .next()
+        else {
+            return Ok(None);
+        };
+        let (key, value) = item?;
+        let id = PrekeyId::from_be_bytes(key[32..].try_into().map_err(|_| {
+            DbError::Corrupt(format!("one-time prekey key of {} bytes", key.len()))
+        })?);
+        let public: AgreementKey = value[..]
+            .try_into()
+            .map_err(|_| DbError::Corrupt(format!("one-time prekey of {} bytes", value.len())))?;
+        self.db.delete_cf(column, key)?;
+        Ok(Some(OneTimePrekey { id, key: public }))
+    }
+
+    fn count_one_time_prekeys(&self, user_uuid: &Uuid, device: &Uuid) -> Result<usize> {
+        let mut count = 0;
+        for item in self.db.iterator_cf_opt(
+            self.column(Column::OneTimePrekeys),
+            device_prekeys(user_uuid, device),
+            IteratorMode::Start,
+        ) {
+            item?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn save_direct_message(&self, message: &DirectMessage, limit: usize) -> Result<()> {
+        let _guard = self.direct_lock.lock().unwrap();
+
+        let mut waiting = 0;
+        for item in self.db.iterator_cf_opt(
+            self.column(Column::DirectMessages),
+            device_direct_messages(&message.recipient, &message.recipient_device),
+            IteratorMode::Start,
+        ) {
+            let (_, value) = item?;
+            if deserialize::<DirectMessage>(&value)?.sender == message.sender {
+                waiting += 1;
+            }
+            if waiting >= limit {
+                return Err(DbError::Invalid(format!(
+                    "at most {limit} direct messages of a user wait for a device"
+                )));
+            }
+        }
+        self.db.put_cf(
+            self.column(Column::DirectMessages),
+            direct_key(message)?,
             serialize(message)?,
         )?;
         Ok(())
     }
 
-    fn find_messages(
-        &self,
-        room_uuid: &Uuid,
-        limit: usize,
-        at: Option<SystemTime>,
-    ) -> anyhow::Result<(Vec<Message>, Option<SystemTime>)> {
-        let iter = if let Some(t) = at {
-            let reverse_ts = u128::MAX - t.duration_since(UNIX_EPOCH)?.as_millis();
-            let start_key = format!("{}_{reverse_ts}", room_uuid);
-            self.db.iterator_cf(
-                self.column(Column::Messages),
-                IteratorMode::From(start_key.as_bytes(), rocksdb::Direction::Forward),
-            )
-        } else {
-            self.db
-                .iterator_cf(self.column(Column::Messages), IteratorMode::Start)
-        };
+    fn find_direct_messages(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        limit: usize,
+    ) -> Result<Vec<DirectMessage>> {
+        self.db
+            .iterator_cf_opt(
+                self.column(Column::DirectMessages),
+                device_direct_messages(user_uuid, device),
+                IteratorMode::Start,
+            )
+            .take(limit)
+            .map(|item| Ok(deserialize(&item?.1)?))
+            .collect()
+    }
+
+    fn delete_direct_messages(
+        &self,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        messages: &[Uuid],
+    ) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        for item in self.db.iterator_cf_opt(
+            self.column(Column::DirectMessages),
+            device_direct_messages(user_uuid, device),
+            IteratorMode::Start,
+        ) {
+            let (key, _) = item?;
+            if messages
+                .iter()
+                .any(|message| key[DIRECT_KEY_LEN - 16..] == message.as_bytes()[..])
+            {
+                batch.delete_cf(self.column(Column::DirectMessages), key);
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+}
+
+/// Length of the room uuid every `Column::Messages` key starts with.
+const ROOM_PREFIX_LEN: usize = 16;
+
+/// Length of a `Column::Messages` key.
+const MESSAGE_KEY_LEN: usize = ROOM_PREFIX_LEN + 8 + 16;
+
+/// Key of `Column::Messages`: the room uuid, then the creation time in
+/// milliseconds subtracted from `u64::MAX` so newer messages sort first, then
+/// the message uuid to order messages created within the same millisecond.
+fn message_key(room_uuid: &Uuid, cursor: &MessageCursor) -> [u8; MESSAGE_KEY_LEN] {
+    let mut key = [0; MESSAGE_KEY_LEN];
+    key[..ROOM_PREFIX_LEN].copy_from_slice(room_uuid.as_bytes());
+    key[ROOM_PREFIX_LEN..ROOM_PREFIX_LEN + 8]
+        .copy_from_slice(&(u64::MAX - cursor.created).to_be_bytes());
+    key[ROOM_PREFIX_LEN + 8..].copy_from_slice(cursor.message.as_bytes());
+    key
+}
+
+/// Length of a `Column::MessageRevisions` key.
+const REVISION_KEY_LEN: usize = 16 + 4;
+
+/// Key of `Column::MessageRevisions`: the message uuid, then the number of the
+/// revision, counting from 0 for the original message.
+fn revision_key(message_uuid: &Uuid, revision: u32) -> [u8; REVISION_KEY_LEN] {
+    let mut key = [0; REVISION_KEY_LEN];
+    key[..16].copy_from_slice(message_uuid.as_bytes());
+    key[16..].copy_from_slice(&revision.to_be_bytes());
+    key
+}
+
+/// Read options bounding iteration to the `Column::MessageRevisions` keys of a
+/// message.
+fn message_revisions(message_uuid: &Uuid) -> ReadOptions {
+    let mut options = ReadOptions::default();
+    options.set_iterate_lower_bound(message_uuid.as_bytes().to_vec());
+    options.set_iterate_upper_bound(key_range_end(message_uuid.as_bytes(), REVISION_KEY_LEN));
+    options
+}
+
+/// Length of a `Column::EditIndex` key.
+const EDIT_KEY_LEN: usize = 16 + 8 + 16;
+
+/// Key of `Column::EditIndex` for the last edit of `message`, `None` if it was
+/// never edited: the room uuid, then the time of the edit in milliseconds and
+/// the message uuid, so the edits of a room sort oldest first.
+fn edit_key(room_uuid: &Uuid, message: &Message) -> Result<Option<[u8; EDIT_KEY_LEN]>> {
+    let Some(edited) = message.edited else {
+        return Ok(None);
+    };
+    let cursor = EditCursor {
+        edited: edited.duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        message: message.uuid,
+    };
+    Ok(Some(edit_cursor_key(room_uuid, &cursor)))
+}
+
+fn edit_cursor_key(room_uuid: &Uuid, cursor: &EditCursor) -> [u8; EDIT_KEY_LEN] {
+    let mut key = [0; EDIT_KEY_LEN];
+    key[..16].copy_from_slice(room_uuid.as_bytes());
+    key[16..24].copy_from_slice(&cursor.edited.to_be_bytes());
+    key[24..].copy_from_slice(cursor.message.as_bytes());
+    key
+}
+
+/// The cursor of an `EditIndex` or `DeletionIndex` key.
+fn change_cursor(key: &[u8]) -> Result<EditCursor> {
+    Ok(EditCursor {
+        edited: u64::from_be_bytes(
+            key[16..24]
+                .try_into()
+                .map_err(|_| DbError::Corrupt(format!("edit key of {} bytes", key.len())))?,
+        ),
+        message: Uuid::from_slice(&key[24..])?,
+    })
+}
+
+/// Read options over the `EditIndex` or `DeletionIndex` entries of a room
+/// after `after`.
+fn room_changes(room_uuid: &Uuid, after: &EditCursor) -> ReadOptions {
+    let mut options = ReadOptions::default();
+    // The smallest key greater than the cursor's.
+    options.set_iterate_lower_bound([&edit_cursor_key(room_uuid, after)[..], &[0]].concat());
+    options.set_iterate_upper_bound(key_range_end(room_uuid.as_bytes(), EDIT_KEY_LEN));
+    options
+}
+
+/// Maximum length of a `Column::Reactions` key.
+const REACTION_KEY_MAX_LEN: usize = 16 * 2 + MAX_EMOJI_LEN;
+
+/// Key of `Column::Reactions`: the message uuid, then the uuid of the user who
+/// reacted and the emoji.
+fn reaction_key(message_uuid: &Uuid, user_uuid: &Uuid, emoji: &str) -> Result<Vec<u8>> {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN {
+        return Err(DbError::Invalid(format!(
+            "reaction of {} bytes, expected 1 to {MAX_EMOJI_LEN}",
+            emoji.len()
+        )));
+    }
+    Ok([
+        message_uuid.as_bytes(),
+        user_uuid.as_bytes(),
+        emoji.as_bytes(),
+    ]
+    .concat())
+}
+
+/// Length of a `Column::DeliveryCursors` key.
+const DELIVERY_KEY_LEN: usize = 16 * 3;
+
+/// Key of `Column::DeliveryCursors`: room uuid, so the cursors of a room can be
+/// deleted with it, then user uuid and device uuid.
+fn delivery_key(room_uuid: &Uuid, user_uuid: &Uuid, device: &Uuid) -> [u8; DELIVERY_KEY_LEN] {
+    let mut key = [0; DELIVERY_KEY_LEN];
+    key[..16].copy_from_slice(room_uuid.as_bytes());
+    key[16..32].copy_from_slice(user_uuid.as_bytes());
+    key[32..].copy_from_slice(device.as_bytes());
+    key
+}
+
+/// Length of a `Column::ReadPositions` key.
+const READ_POSITION_KEY_LEN: usize = 16 * 2;
+
+fn read_position_key(room_uuid: &Uuid, user_uuid: &Uuid) -> [u8; READ_POSITION_KEY_LEN] {
+    let mut key = [0; READ_POSITION_KEY_LEN];
+    key[..16].copy_from_slice(room_uuid.as_bytes());
+    key[16..].copy_from_slice(user_uuid.as_bytes());
+    key
+}
+
+/// Read options bounding iteration to the `Column::Messages` keys of a room,
+/// or to the `Column::ThreadIndex` keys of a thread root.
+fn room_messages(room_uuid: &Uuid) -> ReadOptions {
+    let mut options = ReadOptions::default();
+    options.set_iterate_lower_bound(room_uuid.as_bytes().to_vec());
+    options.set_iterate_upper_bound(room_messages_end(room_uuid));
+    options
+}
+
+/// Exclusive upper bound of the `Column::Messages` keys of a room.
+fn room_messages_end(room_uuid: &Uuid) -> Vec<u8> {
+    key_range_end(room_uuid.as_bytes(), MESSAGE_KEY_LEN)
+}
+
+/// Exclusive upper bound of the keys of `key_len` bytes starting with `prefix`.
+///
+/// It extends the largest possible key with the prefix, so it sorts after
+/// every key with the prefix and before every greater key.
+fn key_range_end(prefix: &[u8], key_len: usize) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    end.resize(key_len + 1, 0xff);
+    end
+}
+
+/// Key of `Column::Devices`, `Column::DeviceSyncs` and
+/// `Column::SignedPrekeys`: the user uuid, then the device uuid.
+fn device_key(user_uuid: &Uuid, device: &Uuid) -> [u8; 32] {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(user_uuid.as_bytes());
+    key[16..].copy_from_slice(device.as_bytes());
+    key
+}
+
+/// Length of a `Column::OneTimePrekeys` key.
+const PREKEY_KEY_LEN: usize = 32 + 4;
+
+/// Key of `Column::OneTimePrekeys`: the `device_key`, then the prekey id.
+fn prekey_key(user_uuid: &Uuid, device: &Uuid, id: PrekeyId) -> [u8; PREKEY_KEY_LEN] {
+    let mut key = [0; PREKEY_KEY_LEN];
+    key[..32].copy_from_slice(&device_key(user_uuid, device));
+    key[32..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Read options bounding iteration to the `Column::OneTimePrekeys` keys of a
+/// device.
+fn device_prekeys(user_uuid: &Uuid, device: &Uuid) -> ReadOptions {
+    let prefix = device_key(user_uuid, device);
+    let mut options = ReadOptions::default();
+    options.set_iterate_lower_bound(prefix.to_vec());
+    options.set_iterate_upper_bound(key_range_end(&prefix, PREKEY_KEY_LEN));
+    options
+}
+
+/// Length of a `Column::DirectMessages` key.
+const DIRECT_KEY_LEN: usize = 32 + 8 + 16;
+
+/// Key of `Column::DirectMessages`: the `device_key` of the recipient, then
+/// the time the message was sent in milliseconds and its uuid, so the
+/// messages of a device sort oldest first.
+fn direct_key(message: &DirectMessage) -> Result<[u8; DIRECT_KEY_LEN]> {
+    let created = message.created.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let mut key = [0; DIRECT_KEY_LEN];
+    key[..32].copy_from_slice(&device_key(&message.recipient, &message.recipient_device));
+    key[32..40].copy_from_slice(&created.to_be_bytes());
+    key[40..].copy_from_slice(message.uuid.as_bytes());
+    Ok(key)
+}
+
+/// Read options bounding iteration to the `Column::DirectMessages` keys of a
+/// device.
+fn device_direct_messages(user_uuid: &Uuid, device: &Uuid) -> ReadOptions {
+    let prefix = device_key(user_uuid, device);
+    let mut options = ReadOptions::default();
+    options.set_iterate_lower_bound(prefix.to_vec());
+    options.set_iterate_upper_bound(key_range_end(&prefix, DIRECT_KEY_LEN));
+    options
+}
+
+/// Key of `Column::UserRooms`.
+fn user_room_key(user_uuid: &Uuid, room_uuid: &Uuid) -> [u8; 32] {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(user_uuid.as_bytes());
+    key[16..].copy_from_slice(room_uuid.as_bytes());
+    key
+}
+
+/// Decode a `Message` stored with schema version `version`, before 7.
+///
+/// Messages stored before version 7 held plaintext, which the server can not
+/// turn into envelopes: they migrate to tombstones.
+fn legacy_message(version: u32, room_uuid: &Uuid, value: &[u8]) -> Result<Message> {
+    Ok(if version < 5 {
+        deserialize::<MessageV4>(value)?.migrate(room_uuid)
+    } else if version < 6 {
+        deserialize::<MessageV5>(value)?.migrate(room_uuid)
+    } else {
+        deserialize::<MessageV6>(value)?.migrate(room_uuid)
+    })
+}
+
+/// `MessageType` of messages stored before schema version 7.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+enum LegacyMessageType {
+    Text,
+    File,
+    Audio,
+    Video,
+    Deleted,
+}
+
+/// Plaintext `Content` of messages stored before schema version 7.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+enum LegacyContent {
+    Text(String),
+    File(String),
+    Audio(String),
+    Video(String),
+    Deleted,
+}
+
+impl LegacyContent {
+    /// The legacy envelope of the content, `None` once deleted.
+    fn envelope(self, room_uuid: &Uuid, timestamp: SystemTime) -> Option<Envelope> {
+        let content = match self {
+            LegacyContent::Text(text) => Content::Text(text),
+            LegacyContent::File(file) => Content::File(file),
+            LegacyContent::Audio(audio) => Content::Audio(audio),
+            LegacyContent::Video(video) => Content::Video(video),
+            LegacyContent::Deleted => return None,
+        };
+        Some(Envelope::legacy(*room_uuid, &content, timestamp))
+    }
+}
+
+/// Layout of `User` before schema version 1, without an identity key.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct UserV0 {
+    uuid: Uuid,
+    address: Address,
+    created: SystemTime,
+}
+
+impl UserV0 {
+    fn migrate(self) -> User {
+        User {
+            uuid: self.uuid,
+            address: self.address,
+            identity_key: NO_IDENTITY_KEY,
+            created: self.created,
+        }
+    }
+}
+
+/// Layout of `Message` up to schema version 4, before messages could be
+/// edited.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+#[allow(dead_code)]
+struct MessageV4 {
+    uuid: Uuid,
+    message_type: LegacyMessageType,
+    created: SystemTime,
+    owner: Uuid,
+    content: LegacyContent,
+}
+
+impl MessageV4 {
+    fn migrate(self, room_uuid: &Uuid) -> Message {
+        Message {
+            uuid: self.uuid,
+            created: self.created,
+            owner: self.owner,
+            envelope: self.content.envelope(room_uuid, self.created),
+            edited: None,
+            reply_to: None,
+            thread_root: None,
+        }
+    }
+}
+
+/// Layout of `Message` in schema version 5, before replies and threads.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+#[allow(dead_code)]
+struct MessageV5 {
+    uuid: Uuid,
+    message_type: LegacyMessageType,
+    created: SystemTime,
+    owner: Uuid,
+    content: LegacyContent,
+    edited: Option<SystemTime>,
+}
+
+impl MessageV5 {
+    fn migrate(self, room_uuid: &Uuid) -> Message {
+        let timestamp = self.edited.unwrap_or(self.created);
+        let envelope = self.content.envelope(room_uuid, timestamp);
+        Message {
+            uuid: self.uuid,
+            created: self.created,
+            owner: self.owner,
+            edited: self.edited.filter(|_| envelope.is_some()),
+            envelope,
+            reply_to: None,
+            thread_root: None,
+        }
+    }
+}
 
-        let mut count = 0;
-        let mut messages = Vec::with_capacity(limit);
-        let mut next = None;
-        for item in iter {
-            let (_, v) = item?;
-            let message: Message = deserialize(&v)?;
+/// Layout of `Message` in schema version 6, before envelopes.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+#[allow(dead_code)]
+struct MessageV6 {
+    uuid: Uuid,
+    message_type: LegacyMessageType,
+    created: SystemTime,
+    owner: Uuid,
+    content: LegacyContent,
+    edited: Option<SystemTime>,
+    reply_to: Option<Uuid>,
+    thread_root: Option<Uuid>,
+}
 
-            count += 1;
-            if count > limit {
-                next = Some(message.created);
-                break;
-            }
-            messages.push(message);
+impl MessageV6 {
+    fn migrate(self, room_uuid: &Uuid) -> Message {
+        let timestamp = self.edited.unwrap_or(self.created);
+        let envelope = self.content.envelope(room_uuid, timestamp);
+        Message {
+            uuid: self.uuid,
+            created: self.created,
+            owner: self.owner,
+            edited: self.edited.filter(|_| envelope.is_some()),
+            envelope,
+            reply_to: self.reply_to,
+            thread_root: self.thread_root,
         }
-        Ok((messages, next))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use shared::types::Content;
+    use crate::testing::{envelope, text};
+    use shared::auth::{identity_key, SigningKey};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use tempfile::TempDir;
 
     #[test]
     fn test_store_and_retrieve_user() {
-        let user1 = User::new("user1".to_string());
+        let user1 = User::new("user1".to_string(), [1; 32]);
         let user_uuid = user1.uuid;
         let created = user1.created;
 
         let db = open_db();
         db.save_user(&user1).expect("User should be saved");
 
-        let u1 = db.find_user(&user_uuid).unwrap();
+        let u1 = db
+            .find_user(&user_uuid)
+            .unwrap()
+            .expect("User should be found");
 
         assert_eq!(u1.uuid, user_uuid);
         assert_eq!(u1.created, created);
     }
 
+    #[test]
+    fn test_find_missing_keys() {
+        let db = open_db();
+        assert!(db.find_user(&Uuid::new_v4()).unwrap().is_none());
+        assert!(db.find_room(&Uuid::new_v4()).unwrap().is_none());
+        assert!(db
+            .find_user_by_address(&"nobody".to_string())
+            .unwrap()
+            .is_none());
+        let (messages, next) = db
+            .find_messages(&Uuid::new_v4(), 10, None, HistoryDirection::Backward)
+            .unwrap();
+        assert!(messages.is_empty());
+        assert!(next.is_none());
+    }
+
+    #[test]
+    fn test_corrupted_values() {
+        let db = open_rocksdb();
+        let user_uuid = Uuid::new_v4();
+        let room_uuid = Uuid::new_v4();
+        db.db
+            .put_cf(db.column(Column::Users), user_uuid, b"garbage")
+            .unwrap();
+        db.db
+            .put_cf(db.column(Column::Rooms), room_uuid, [0xff; 64])
+            .unwrap();
+        db.db
+            .put_cf(db.column(Column::UserAddresses), "user1", b"not a uuid")
+            .unwrap();
+
+        assert!(matches!(db.find_user(&user_uuid), Err(DbError::Corrupt(_))));
+        assert!(matches!(db.find_room(&room_uuid), Err(DbError::Corrupt(_))));
+        assert!(matches!(
+            db.find_user_by_address(&"user1".to_string()),
+            Err(DbError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_deserialization_failures() {
+        let db = open_rocksdb();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+
+        // Records cut short, e.g. written by an interrupted or buggy writer.
+        let user = serialize(&user1).unwrap();
+        db.db
+            .put_cf(
+                db.column(Column::Users),
+                user1.uuid,
+                &user[..user.len() / 2],
+            )
+            .unwrap();
+        let room = serialize(&room1).unwrap();
+        db.db
+            .put_cf(
+                db.column(Column::Rooms),
+                room1.uuid,
+                &room[..room.len() - 1],
+            )
+            .unwrap();
+        assert!(db.find_user(&user1.uuid).is_err());
+        assert!(db.find_room(&room1.uuid).is_err());
+
+        // An index entry pointing at a user that does not exist.
+        db.db
+            .put_cf(db.column(Column::UserAddresses), "ghost", Uuid::new_v4())
+            .unwrap();
+        assert!(db
+            .find_user_by_address(&"ghost".to_string())
+            .unwrap()
+            .is_none());
+
+        // A message which is not a message.
+        let message = text_message("hello", &user1);
+        db.save_message(&room1, &message).unwrap();
+        let (messages, _) = db
+            .find_messages(&room1.uuid, 10, None, HistoryDirection::Backward)
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        let key = db
+            .db
+            .iterator_cf(db.column(Column::Messages), IteratorMode::Start)
+            .next()
+            .unwrap()
+            .unwrap()
+            .0;
+        db.db
+            .put_cf(db.column(Column::Messages), key, b"\x02")
+            .unwrap();
+        assert!(db
+            .find_messages(&room1.uuid, 10, None, HistoryDirection::Backward)
+            .is_err());
+    }
+
+    #[test]
+    fn test_schema_too_new() {
+        let temp_dir = TempDir::new().unwrap();
+        {
+            let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+            db.db
+                .put_cf(
+                    db.column(Column::Meta),
+                    SCHEMA_VERSION_KEY,
+                    (SCHEMA_VERSION + 1).to_be_bytes(),
+                )
+                .unwrap();
+        }
+        assert!(matches!(
+            RocksDb::new(temp_dir.path()),
+            Err(DbError::SchemaTooNew { found, supported })
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_find_user_by_address() {
+        let db = open_db();
+        let mut user1 = User::new("user1".to_string(), [1; 32]);
+        db.save_user(&user1).expect("User should be saved");
+
+        let u1 = db
+            .find_user_by_address(&"user1".to_string())
+            .unwrap()
+            .expect("User should be found");
+        assert_eq!(u1.uuid, user1.uuid);
+        assert!(db
+            .find_user_by_address(&"user2".to_string())
+            .unwrap()
+            .is_none());
+
+        // Changing the address moves the index entry.
+        user1.address = "user1-renamed".to_string();
+        db.save_user(&user1).expect("User should be saved");
+        assert!(db
+            .find_user_by_address(&"user1".to_string())
+            .unwrap()
+            .is_none());
+        let u1 = db
+            .find_user_by_address(&"user1-renamed".to_string())
+            .unwrap()
+            .expect("User should be found");
+        assert_eq!(u1.uuid, user1.uuid);
+    }
+
+    #[test]
+    fn test_address_is_unique() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        db.save_user(&user1).expect("User should be saved");
+        // Saving the same user again is fine.
+        db.save_user(&user1).expect("User should be saved");
+
+        let impostor = User::new("user1".to_string(), [2; 32]);
+        assert!(matches!(
+            db.save_user(&impostor),
+            Err(DbError::AddressTaken(address)) if address == "user1"
+        ));
+
+        let u1 = db
+            .find_user_by_address(&"user1".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(u1.uuid, user1.uuid);
+    }
+
     #[test]
     fn test_store_and_retrieve_room() {
-        let user1 = User::new("user1".to_string());
+        let user1 = User::new("user1".to_string(), [1; 32]);
         let room_name = "Room1";
         let room1 = Room::new(room_name, &user1);
         let room_uuid = room1.uuid;
@@ -186,39 +2005,246 @@ mod tests {
         let db = open_db();
         db.save_room(&room1).expect("Room should be saved");
 
-        let r1 = db.find_room(&room_uuid).unwrap();
+        let r1 = db
+            .find_room(&room_uuid)
+            .unwrap()
+            .expect("Room should be found");
 
         assert_eq!(r1.uuid, room_uuid);
         assert_eq!(r1.created, created);
         assert_eq!(r1.name, room_name);
-        let member_uuid = r1.members.iter().nth(0).unwrap();
+        let member_uuid = r1.members.iter().next().unwrap();
         assert_eq!(*member_uuid, user1.uuid);
-        let owner_uuid = r1.owners.iter().nth(0).unwrap();
+        let owner_uuid = r1.owners.iter().next().unwrap();
         assert_eq!(*owner_uuid, user1.uuid);
     }
 
+    #[test]
+    fn test_delete_room() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let room2 = Room::new("Room2", &user1);
+        db.save_room(&room1).expect("Room should be saved");
+        db.save_room(&room2).expect("Room should be saved");
+        db.save_message(&room1, &text_message("in room1", &user1))
+            .expect("Message should be saved");
+        db.save_message(&room2, &text_message("in room2", &user1))
+            .expect("Message should be saved");
+
+        db.delete_room(&room1.uuid).expect("Room should be deleted");
+
+        assert!(db.find_room(&room1.uuid).unwrap().is_none());
+        assert!(db.find_room(&room2.uuid).unwrap().is_some());
+        let (messages, _) = db
+            .find_messages(&room2.uuid, 10, None, HistoryDirection::Backward)
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        // Nothing of room1 is left in the messages column.
+        let remaining = db
+            .find_messages(&room1.uuid, 10, None, HistoryDirection::Backward)
+            .unwrap()
+            .0
+            .into_iter()
+            .filter(|m| text(m) == "in room1")
+            .count();
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn test_list_rooms_for_user() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let user2 = User::new("user2".to_string(), [2; 32]);
+        let mut rooms: Vec<Room> = (0..5)
+            .map(|i| Room::new(&format!("Room{i}"), &user1))
+            .collect();
+        for room in &rooms {
+            db.save_room(room).expect("Room should be saved");
+        }
+        rooms.sort_by_key(|room| room.uuid);
+
+        // Pages follow room uuid order and `next` continues after the page.
+        let (page, next) = db.list_rooms_for_user(&user1.uuid, 2, None).unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(next, Some(rooms[1].uuid));
+        let (page, next) = db.list_rooms_for_user(&user1.uuid, 2, next).unwrap();
+        assert_eq!(page[0].uuid, rooms[2].uuid);
+        let (page, next) = db.list_rooms_for_user(&user1.uuid, 2, next).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].uuid, rooms[4].uuid);
+        assert!(next.is_none());
+        let (page, next) = db.list_rooms_for_user(&user1.uuid, 5, None).unwrap();
+        assert_eq!(page.len(), 5);
+        assert!(next.is_none());
+
+        // The index follows membership changes.
+        assert!(db
+            .list_rooms_for_user(&user2.uuid, 10, None)
+            .unwrap()
+            .0
+            .is_empty());
+        rooms[0].members.insert(user2.uuid);
+        db.save_room(&rooms[0]).unwrap();
+        let (page, _) = db.list_rooms_for_user(&user2.uuid, 10, None).unwrap();
+        assert_eq!(page.len(), 1);
+        assert!(page[0].members.contains(&user2.uuid));
+
+        rooms[0].members.remove(&user2.uuid);
+        db.save_room(&rooms[0]).unwrap();
+        assert!(db
+            .list_rooms_for_user(&user2.uuid, 10, None)
+            .unwrap()
+            .0
+            .is_empty());
+
+        db.delete_room(&rooms[1].uuid).unwrap();
+        let (page, _) = db.list_rooms_for_user(&user1.uuid, 10, None).unwrap();
+        assert_eq!(page.len(), 4);
+        assert!(page.iter().all(|room| room.uuid != rooms[1].uuid));
+    }
+
+    #[test]
+    fn test_migrate_user_rooms() {
+        let temp_dir = TempDir::new().unwrap();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        {
+            // A version 1 database, which has rooms but no membership index.
+            let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+            db.db
+                .put_cf(
+                    db.column(Column::Rooms),
+                    room1.uuid,
+                    serialize(&room1).unwrap(),
+                )
+                .unwrap();
+            db.db
+                .put_cf(
+                    db.column(Column::Meta),
+                    SCHEMA_VERSION_KEY,
+                    1u32.to_be_bytes(),
+                )
+                .unwrap();
+        }
+
+        let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+        let (rooms, _) = db.list_rooms_for_user(&user1.uuid, 10, None).unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].uuid, room1.uuid);
+        assert_eq!(
+            db.db
+                .get_cf(db.column(Column::Meta), SCHEMA_VERSION_KEY)
+                .unwrap()
+                .unwrap(),
+            SCHEMA_VERSION.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn test_migrate_unversioned() {
+        let temp_dir = TempDir::new().unwrap();
+        let user = UserV0 {
+            uuid: Uuid::new_v4(),
+            address: "user1".to_string(),
+            created: SystemTime::now(),
+        };
+        let room = Room {
+            owners: [user.uuid].into(),
+            members: [user.uuid].into(),
+            ..Room::new("Room1", &User::new("nobody".to_string(), [0; 32]))
+        };
+        let messages: Vec<MessageV4> = ["first", "second"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| MessageV4 {
+                uuid: Uuid::new_v4(),
+                message_type: LegacyMessageType::Text,
+                created: UNIX_EPOCH + Duration::from_secs(1_000 + i as u64),
+                owner: user.uuid,
+                content: LegacyContent::Text(text.to_string()),
+            })
+            .collect();
+        {
+            // The layout of the first release: three columns, no schema
+            // version, and string message keys.
+            let mut options = Options::default();
+            options.create_if_missing(true);
+            options.create_missing_column_families(true);
+            let db = DB::open_cf_descriptors(
+                &options,
+                temp_dir.path(),
+                ["users", "rooms", "messages"]
+                    .map(|name| ColumnFamilyDescriptor::new(name, Options::default())),
+            )
+            .unwrap();
+            let cf = |name| db.cf_handle(name).unwrap();
+            db.put_cf(cf("users"), user.uuid, serialize(&user).unwrap())
+                .unwrap();
+            db.put_cf(cf("rooms"), room.uuid, serialize(&room).unwrap())
+                .unwrap();
+            for message in &messages {
+                let reverse_ts = u128::MAX
+                    - message
+                        .created
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis();
+                db.put_cf(
+                    cf("messages"),
+                    format!("{}_{reverse_ts}_{}", room.uuid, message.uuid),
+                    serialize(message).unwrap(),
+                )
+                .unwrap();
+            }
+        }
+
+        let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+        let (found, _) = db
+            .find_messages(&room.uuid, 10, None, HistoryDirection::Backward)
+            .unwrap();
+        let uuids: Vec<_> = found.iter().map(|message| message.uuid).collect();
+        assert_eq!(uuids, vec![messages[1].uuid, messages[0].uuid]);
+        assert!(matches!(
+            legacy_content(&found[0]),
+            Some(Content::Text(text)) if text == "second"
+        ));
+
+        let (rooms, _) = db.list_rooms_for_user(&user.uuid, 10, None).unwrap();
+        assert_eq!(rooms.len(), 1);
+        let migrated = db.find_user_by_address(&user.address).unwrap().unwrap();
+        assert_eq!(migrated.uuid, user.uuid);
+        // Without a key of their own, migrated users can not log in.
+        let challenge = shared::auth::Challenge([7; 32]);
+        let signature = challenge.sign(&SigningKey::from_bytes(&[1; 32]));
+        assert!(challenge
+            .verify(&migrated.identity_key, &signature)
+            .is_err());
+        assert_eq!(
+            db.db
+                .get_cf(db.column(Column::Meta), SCHEMA_VERSION_KEY)
+                .unwrap()
+                .unwrap(),
+            SCHEMA_VERSION.to_be_bytes()
+        );
+    }
+
     #[test]
     fn test_store_and_retrieve_message() {
         let db = open_db();
 
-        let user1 = User::new("user1".to_string());
-        let user2 = User::new("user2".to_string());
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let user2 = User::new("user2".to_string(), [2; 32]);
         let chat1 = &Room::new("chat1", &user1);
         // Generate 20 messages - 10 from user1 and 10 from user 2.
         for i in 0..10 {
-            db.save_message(
-                &chat1,
-                &Message::new_text(&format!("user1: Message {i}"), &user1),
-            )
-            .expect("Message should be saved");
+            db.save_message(chat1, &text_message(&format!("user1: Message {i}"), &user1))
+                .expect("Message should be saved");
 
             thread::sleep(Duration::from_millis(5));
 
-            db.save_message(
-                &chat1,
-                &Message::new_text(&format!("user2: Message {i}"), &user2),
-            )
-            .expect("Message should be saved");
+            db.save_message(chat1, &text_message(&format!("user2: Message {i}"), &user2))
+                .expect("Message should be saved");
 
             thread::sleep(Duration::from_millis(5));
         }
@@ -229,8 +2255,13 @@ mod tests {
         let page_size = 12;
         loop {
             let messages = db
-                .find_messages(&chat1.uuid, page_size, next_token)
-                .expect(&format!("Return {} messages", page_size));
+                .find_messages(
+                    &chat1.uuid,
+                    page_size,
+                    next_token,
+                    HistoryDirection::Backward,
+                )
+                .unwrap_or_else(|_| panic!("Return {} messages", page_size));
             next_token = messages.1;
             let messages = messages.0;
             if count_messages == 0 {
@@ -247,11 +2278,7 @@ mod tests {
                 } else {
                     user_id = 1;
                 }
-                let text = match &m.content {
-                    Content::Text(text) => text,
-                    _ => "undefined",
-                };
-                assert_eq!(text, format!("user{}: Message {}", user_id, message_id));
+                assert_eq!(text(m), format!("user{}: Message {}", user_id, message_id));
                 if user_id == 1 {
                     message_id -= 1;
                 }
@@ -263,9 +2290,870 @@ mod tests {
         assert_eq!(count_messages, 20);
     }
 
+    /// Read every page of the history of a room.
+    fn all_messages(db: &dyn DbConnection, room_uuid: &Uuid, page_size: usize) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut after = None;
+        loop {
+            let (page, next) = db
+                .find_messages(room_uuid, page_size, after, HistoryDirection::Backward)
+                .unwrap();
+            assert!(page.len() <= page_size);
+            messages.extend(page);
+            if next.is_none() {
+                return messages;
+            }
+            after = next;
+        }
+    }
+
+    #[test]
+    fn test_messages_of_the_same_millisecond() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let created = SystemTime::now();
+        let mut saved = Vec::new();
+        for i in 0..25 {
+            let mut message = text_message(&format!("Message {i}"), &user1);
+            message.created = created;
+            db.save_message(&room1, &message).unwrap();
+            saved.push(message.uuid);
+        }
+
+        // No page boundary skips or repeats a message.
+        for page_size in [1, 2, 7, 24, 25, 26] {
+            let found: Vec<Uuid> = all_messages(db.as_ref(), &room1.uuid, page_size)
+                .iter()
+                .map(|m| m.uuid)
+                .collect();
+            let mut expected = saved.clone();
+            expected.sort();
+            assert_eq!(found, expected, "page size {page_size}");
+        }
+    }
+
+    #[test]
+    fn test_messages_of_adjacent_rooms() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let mut room1 = Room::new("Room1", &user1);
+        let mut room2 = Room::new("Room2", &user1);
+        let mut room3 = Room::new("Room3", &user1);
+        // Rooms whose keys are next to each other.
+        room1.uuid = Uuid::from_u128(0x10);
+        room2.uuid = Uuid::from_u128(0x11);
+        room3.uuid = Uuid::from_u128(u128::MAX);
+        for room in [&room1, &room2, &room3] {
+            for i in 0..5 {
+                let text = format!("{} {i}", room.name);
+                db.save_message(room, &text_message(&text, &user1)).unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        for room in [&room1, &room2, &room3] {
+            for page_size in [1, 2, 5, 10] {
+                let messages = all_messages(db.as_ref(), &room.uuid, page_size);
+                assert_eq!(messages.len(), 5);
+                for (m, i) in messages.iter().zip((0..5).rev()) {
+                    assert_eq!(text(m), format!("{} {i}", room.name));
+                }
+            }
+        }
+
+        db.delete_room(&room2.uuid).unwrap();
+        assert!(all_messages(db.as_ref(), &room2.uuid, 10).is_empty());
+        assert_eq!(all_messages(db.as_ref(), &room1.uuid, 10).len(), 5);
+        assert_eq!(all_messages(db.as_ref(), &room3.uuid, 10).len(), 5);
+    }
+
+    #[test]
+    fn test_find_messages_forward() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let mut saved = Vec::new();
+        for i in 0..10 {
+            let message = text_message(&format!("Message {i}"), &user1);
+            db.save_message(&room1, &message).unwrap();
+            saved.push(message);
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let (page, next) = db
+            .find_messages(&room1.uuid, 4, None, HistoryDirection::Forward)
+            .unwrap();
+        let uuids: Vec<Uuid> = page.iter().map(|m| m.uuid).collect();
+        let expected: Vec<Uuid> = saved[..4].iter().map(|m| m.uuid).collect();
+        assert_eq!(uuids, expected);
+
+        // Everything after the 4th message, oldest first.
+        let (page, next) = db
+            .find_messages(&room1.uuid, 10, next, HistoryDirection::Forward)
+            .unwrap();
+        let uuids: Vec<Uuid> = page.iter().map(|m| m.uuid).collect();
+        let expected: Vec<Uuid> = saved[4..].iter().map(|m| m.uuid).collect();
+        assert_eq!(uuids, expected);
+        assert!(next.is_none());
+
+        // Nothing after the newest message.
+        let newest = MessageCursor::of(&saved[9]).unwrap();
+        let (page, _) = db
+            .find_messages(&room1.uuid, 10, Some(newest), HistoryDirection::Forward)
+            .unwrap();
+        assert!(page.is_empty());
+    }
+
+    #[test]
+    fn test_find_messages_around() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let room2 = Room::new("Room2", &user1);
+        let mut saved = Vec::new();
+        for i in 0..10 {
+            let message = text_message(&format!("Message {i}"), &user1);
+            db.save_message(&room1, &message).unwrap();
+            saved.push(message.uuid);
+            thread::sleep(Duration::from_millis(1));
+        }
+        saved.reverse();
+        let other = text_message("elsewhere", &user1);
+        db.save_message(&room2, &other).unwrap();
+
+        // Newest first, with the anchor in the middle.
+        let (window, older) = db
+            .find_messages_around(&room1.uuid, &saved[5], 5)
+            .unwrap()
+            .unwrap();
+        let uuids: Vec<Uuid> = window.iter().map(|m| m.uuid).collect();
+        assert_eq!(uuids, saved[3..8]);
+        let (page, _) = db
+            .find_messages(&room1.uuid, 10, older, HistoryDirection::Backward)
+            .unwrap();
+        let uuids: Vec<Uuid> = page.iter().map(|m| m.uuid).collect();
+        assert_eq!(uuids, saved[8..]);
+
+        // Windows are cut short at both ends of the history.
+        let (window, older) = db
+            .find_messages_around(&room1.uuid, &saved[0], 5)
+            .unwrap()
+            .unwrap();
+        assert_eq!(window.len(), 3);
+        assert!(older.is_some());
+        let (window, older) = db
+            .find_messages_around(&room1.uuid, &saved[9], 5)
+            .unwrap()
+            .unwrap();
+        assert_eq!(window.len(), 3);
+        assert!(older.is_none());
+
+        // Messages of other rooms are not found.
+        assert!(db
+            .find_messages_around(&room1.uuid, &other.uuid, 5)
+            .unwrap()
+            .is_none());
+        assert!(db
+            .find_messages_around(&room1.uuid, &Uuid::new_v4(), 5)
+            .unwrap()
+            .is_none());
+
+        db.delete_room(&room2.uuid).unwrap();
+        assert!(db
+            .find_messages_around(&room2.uuid, &other.uuid, 5)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_delivery_cursors() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let user2 = User::new("user2".to_string(), [2; 32]);
+        let (device1, device2) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut room1 = Room::new("Room1", &user1);
+        room1.members.insert(user2.uuid);
+        db.save_room(&room1).unwrap();
+
+        let registered = db.register_device(&user1.uuid, &device1).unwrap();
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(
+            db.register_device(&user1.uuid, &device1).unwrap(),
+            registered
+        );
+
+        let message = text_message("hello", &user1);
+        db.save_message(&room1, &message).unwrap();
+        let cursor = MessageCursor::of(&message).unwrap();
+        assert!(MessageCursor::before(registered).unwrap() < cursor);
+        for (user, device) in [(&user1, &device1), (&user2, &device2)] {
+            assert!(db
+                .find_delivery_cursor(&room1.uuid, &user.uuid, device)
+                .unwrap()
+                .is_none());
+            assert!(db
+                .advance_delivery_cursor(&room1.uuid, &user.uuid, device, &cursor)
+                .unwrap());
+        }
+        // Never back, nor to where it already is.
+        let earlier = MessageCursor::before(registered).unwrap();
+        for cursor in [earlier, cursor] {
+            assert!(!db
+                .advance_delivery_cursor(&room1.uuid, &user1.uuid, &device1, &cursor)
+                .unwrap());
+        }
+        assert_eq!(
+            db.find_delivery_cursor(&room1.uuid, &user1.uuid, &device1)
+                .unwrap(),
+            Some(cursor)
+        );
+        // Devices are not shared between users.
+        assert!(db
+            .find_delivery_cursor(&room1.uuid, &user1.uuid, &device2)
+            .unwrap()
+            .is_none());
+
+        // Cursors go away with the membership and with the room.
+        room1.members.remove(&user2.uuid);
+        db.save_room(&room1).unwrap();
+        assert!(db
+            .find_delivery_cursor(&room1.uuid, &user2.uuid, &device2)
+            .unwrap()
+            .is_none());
+        db.delete_room(&room1.uuid).unwrap();
+        assert!(db
+            .find_delivery_cursor(&room1.uuid, &user1.uuid, &device1)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_positions() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let user2 = User::new("user2".to_string(), [2; 32]);
+        let mut room1 = Room::new("Room1", &user1);
+        room1.members.insert(user2.uuid);
+        db.save_room(&room1).unwrap();
+        let room2 = Room::new("Room2", &user1);
+        db.save_room(&room2).unwrap();
+
+        let message = text_message("hello", &user1);
+        db.save_message(&room1, &message).unwrap();
+        let cursor = MessageCursor::of(&message).unwrap();
+        for (room, user) in [(&room1, &user1), (&room1, &user2), (&room2, &user1)] {
+            assert!(db
+                .advance_read_position(&room.uuid, &user.uuid, &cursor)
+                .unwrap());
+        }
+        let earlier = MessageCursor::before(message.created).unwrap();
+        for cursor in [earlier, cursor] {
+            assert!(!db
+                .advance_read_position(&room1.uuid, &user1.uuid, &cursor)
+                .unwrap());
+        }
+
+        assert_eq!(
+            db.find_read_position(&room1.uuid, &user2.uuid).unwrap(),
+            Some(cursor)
+        );
+        let mut positions = db.find_read_positions(&room1.uuid).unwrap();
+        positions.sort();
+        let mut expected = vec![(user1.uuid, cursor), (user2.uuid, cursor)];
+        expected.sort();
+        assert_eq!(positions, expected);
+
+        room1.members.remove(&user2.uuid);
+        db.save_room(&room1).unwrap();
+        assert_eq!(db.find_read_positions(&room1.uuid).unwrap().len(), 1);
+        db.delete_room(&room1.uuid).unwrap();
+        assert!(db.find_read_positions(&room1.uuid).unwrap().is_empty());
+        assert_eq!(db.find_read_positions(&room2.uuid).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_message_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let message = text_message("hello", &user1);
+        {
+            // A version 2 database, which has string message keys and
+            // messages without `edited`.
+            let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+            let legacy = MessageV4 {
+                uuid: message.uuid,
+                message_type: LegacyMessageType::Text,
+                created: message.created,
+                owner: message.owner,
+                content: LegacyContent::Text("hello".to_string()),
+            };
+            let reverse_ts = u128::MAX
+                - message
+                    .created
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis();
+            db.db
+                .put_cf(
+                    db.column(Column::Messages),
+                    format!("{}_{reverse_ts}_{}", room1.uuid, message.uuid),
+                    serialize(&legacy).unwrap(),
+                )
+                .unwrap();
+            db.db
+                .put_cf(
+                    db.column(Column::Meta),
+                    SCHEMA_VERSION_KEY,
+                    2u32.to_be_bytes(),
+                )
+                .unwrap();
+        }
+
+        let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+        let (messages, _) = db
+            .find_messages(&room1.uuid, 10, None, HistoryDirection::Backward)
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].uuid, message.uuid);
+        assert!(matches!(
+            legacy_content(&messages[0]),
+            Some(Content::Text(text)) if text == "hello"
+        ));
+        // The migrated message is indexed.
+        let (around, _) = db
+            .find_messages_around(&room1.uuid, &message.uuid, 3)
+            .unwrap()
+            .unwrap();
+        assert_eq!(around.len(), 1);
+        let keys = db
+            .db
+            .iterator_cf(db.column(Column::Messages), IteratorMode::Start)
+            .count();
+        assert_eq!(keys, 1);
+    }
+
+    #[test]
+    fn test_edit_message() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let room2 = Room::new("Room2", &user1);
+        let original = text_message("helo", &user1);
+        db.save_message(&room1, &original).unwrap();
+
+        let first = original.edit(envelope(Uuid::nil(), Uuid::nil(), "hello"));
+        assert!(!db.edit_message(&room2.uuid, &first).unwrap());
+        assert!(db.edit_message(&room1.uuid, &first).unwrap());
+        let second = first.edit(envelope(Uuid::nil(), Uuid::nil(), "hello!"));
+        assert!(db.edit_message(&room1.uuid, &second).unwrap());
+
+        let (messages, _) = db
+            .find_messages(&room1.uuid, 10, None, HistoryDirection::Backward)
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(text(&messages[0]), "hello!");
+        assert_eq!(messages[0].edited, second.edited);
+        let revisions = db.find_revisions(&original.uuid).unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert!(revisions[0].edited.is_none());
+        assert_eq!(revisions[1].edited, first.edited);
+
+        db.delete_room(&room1.uuid).unwrap();
+        assert!(db.find_revisions(&original.uuid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_edits() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let room2 = Room::new("Room2", &user1);
+        let messages = ["a", "b", "c"].map(|t| text_message(t, &user1));
+        for message in &messages {
+            db.save_message(&room1, message).unwrap();
+        }
+        let start = EditCursor::before(UNIX_EPOCH).unwrap();
+        assert!(db.find_edits(&room1.uuid, 10, start).unwrap().0.is_empty());
+
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let edit = |message: &Message, secs| {
+            let mut edited = message.edit(envelope(Uuid::nil(), Uuid::nil(), "edited"));
+            edited.edited = Some(at(secs));
+            assert!(db.edit_message(&room1.uuid, &edited).unwrap());
+            edited
+        };
+        // Only the last edit of a message counts.
+        edit(&messages[2], 10);
+        let first = edit(&messages[0], 20);
+        edit(&first, 30);
+
+        let uuids = |page: Vec<Message>| page.iter().map(|m| m.uuid).collect::<Vec<_>>();
+        let (page, next) = db.find_edits(&room1.uuid, 1, start).unwrap();
+        assert_eq!(uuids(page), [messages[2].uuid]);
+        let (page, next) = db.find_edits(&room1.uuid, 1, next.unwrap()).unwrap();
+        assert_eq!(uuids(page), [messages[0].uuid]);
+        assert!(next.is_none());
+        let since = EditCursor::before(at(15)).unwrap();
+        let (page, _) = db.find_edits(&room1.uuid, 10, since).unwrap();
+        assert_eq!(uuids(page), [messages[0].uuid]);
+        assert!(db.find_edits(&room2.uuid, 10, start).unwrap().0.is_empty());
+
+        // Deleted messages leave the index.
+        let tombstone = messages[2].tombstone();
+        assert!(db
+            .delete_message(&room1.uuid, &tombstone, &user1.uuid)
+            .unwrap());
+        let (page, _) = db.find_edits(&room1.uuid, 10, start).unwrap();
+        assert_eq!(uuids(page), [messages[0].uuid]);
+        db.delete_room(&room1.uuid).unwrap();
+        assert!(db.find_edits(&room1.uuid, 10, start).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_delete_message() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let room2 = Room::new("Room2", &user1);
+        let first = text_message("first", &user1);
+        db.save_message(&room1, &first).unwrap();
+        thread::sleep(Duration::from_millis(2));
+        let second = text_message("secret", &user1);
+        db.save_message(&room1, &second).unwrap();
+        let edited = second.edit(envelope(Uuid::nil(), Uuid::nil(), "secret!"));
+        db.edit_message(&room1.uuid, &edited).unwrap();
+
+        let tombstone = edited.tombstone();
+        assert!(!db
+            .delete_message(&room2.uuid, &tombstone, &user1.uuid)
+            .unwrap());
+        assert!(db
+            .delete_message(&room1.uuid, &tombstone, &user1.uuid)
+            .unwrap());
+
+        // The tombstone keeps the place of the message in history.
+        let (messages, _) = db
+            .find_messages(&room1.uuid, 10, None, HistoryDirection::Backward)
+            .unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].uuid, second.uuid);
+        assert!(messages[0].is_deleted());
+        assert_eq!(messages[1].uuid, first.uuid);
+        assert!(db.find_revisions(&second.uuid).unwrap().is_empty());
+        // The deletion replaces the edit in the indexes.
+        let since = EditCursor::before(UNIX_EPOCH).unwrap();
+        assert!(db.find_edits(&room1.uuid, 10, since).unwrap().0.is_empty());
+        let (deletions, next) = db.find_deletions(&room1.uuid, 10, since).unwrap();
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(deletions[0].tombstone.uuid, second.uuid);
+        assert!(deletions[0].tombstone.is_deleted());
+        assert_eq!(deletions[0].by, user1.uuid);
+        assert!(next.is_none());
+        // Deleted messages can not be edited back to life.
+        let edited = second.edit(envelope(Uuid::nil(), Uuid::nil(), "back"));
+        assert!(!db.edit_message(&room1.uuid, &edited).unwrap());
+    }
+
+    /// Whether a file under `dir` holds `bytes`.
+    fn stored(dir: &Path, bytes: &[u8]) -> bool {
+        std::fs::read_dir(dir).unwrap().any(|entry| {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                return stored(&path, bytes);
+            }
+            let content = std::fs::read(&path).unwrap();
+            content.windows(bytes.len()).any(|window| window == bytes)
+        })
+    }
+
+    #[test]
+    fn test_purge_deleted() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = RocksDb::new(temp_dir.path()).unwrap();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let kept = text_message("kept-6a1f", &user1);
+        db.save_message(&room1, &kept).unwrap();
+        let deleted = text_message("secret-3c9e", &user1);
+        db.save_message(&room1, &deleted).unwrap();
+        let edited = deleted.edit(envelope(Uuid::nil(), Uuid::nil(), "secret-7d2b"));
+        db.edit_message(&room1.uuid, &edited).unwrap();
+
+        assert!(db
+            .delete_message(&room1.uuid, &edited.tombstone(), &user1.uuid)
+            .unwrap());
+        db.purge_deleted().unwrap();
+        // Purging flushed what is left to files.
+        assert!(stored(temp_dir.path(), b"kept-6a1f"));
+        assert!(!stored(temp_dir.path(), b"secret-3c9e"));
+        assert!(!stored(temp_dir.path(), b"secret-7d2b"));
+    }
+
+    #[test]
+    fn test_purge_deleted_room_on_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        {
+            let db = RocksDb::new(temp_dir.path()).unwrap();
+            db.save_room(&room1).unwrap();
+            db.save_message(&room1, &text_message("secret-51e0", &user1))
+                .unwrap();
+            db.db.flush_cf(db.column(Column::Messages)).unwrap();
+            db.delete_room(&room1.uuid).unwrap();
+            // Stopped before purging.
+            assert!(stored(temp_dir.path(), b"secret-51e0"));
+        }
+
+        let db = RocksDb::new(temp_dir.path()).unwrap();
+        assert!(!stored(temp_dir.path(), b"secret-51e0"));
+        assert!(db
+            .db
+            .iterator_cf(db.column(Column::PendingPurges), IteratorMode::Start)
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn test_find_thread() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let root = text_message("root", &user1);
+        db.save_message(&room1, &root).unwrap();
+        let mut replies = Vec::new();
+        for i in 0..3 {
+            thread::sleep(Duration::from_millis(2));
+            let mut reply = text_message(&format!("reply {i}"), &user1);
+            reply.thread_root = Some(root.uuid);
+            db.save_message(&room1, &reply).unwrap();
+            replies.push(reply.uuid);
+            db.save_message(&room1, &text_message("elsewhere", &user1))
+                .unwrap();
+        }
+
+        let (first, next) = db
+            .find_thread(&room1.uuid, &root.uuid, 2, None, HistoryDirection::Forward)
+            .unwrap();
+        assert_eq!(
+            first.iter().map(|m| m.uuid).collect::<Vec<_>>(),
+            replies[..2]
+        );
+        let (rest, next) = db
+            .find_thread(&room1.uuid, &root.uuid, 2, next, HistoryDirection::Forward)
+            .unwrap();
+        assert_eq!(
+            rest.iter().map(|m| m.uuid).collect::<Vec<_>>(),
+            replies[2..]
+        );
+        assert!(next.is_none());
+        let (newest, _) = db
+            .find_thread(&room1.uuid, &root.uuid, 1, None, HistoryDirection::Backward)
+            .unwrap();
+        assert_eq!(newest[0].uuid, replies[2]);
+
+        db.delete_room(&room1.uuid).unwrap();
+        let (messages, _) = db
+            .find_thread(&room1.uuid, &root.uuid, 10, None, HistoryDirection::Forward)
+            .unwrap();
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_migrate_plaintext_messages() {
+        let temp_dir = TempDir::new().unwrap();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let message = text_message("hello", &user1);
+        let parent = Uuid::new_v4();
+        let legacy = |text: &str, edited| MessageV6 {
+            uuid: message.uuid,
+            message_type: LegacyMessageType::Text,
+            created: message.created,
+            owner: message.owner,
+            content: LegacyContent::Text(text.to_string()),
+            edited,
+            reply_to: Some(parent),
+            thread_root: Some(parent),
+        };
+        {
+            // A version 6 database, with an edited plaintext message.
+            let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+            let key = message_key(&room1.uuid, &MessageCursor::of(&message).unwrap());
+            db.db
+                .put_cf(
+                    db.column(Column::Messages),
+                    key,
+                    serialize(&legacy("hello!", Some(SystemTime::now()))).unwrap(),
+                )
+                .unwrap();
+            db.db
+                .put_cf(db.column(Column::MessageIndex), message.uuid, key)
+                .unwrap();
+            db.db
+                .put_cf(
+                    db.column(Column::MessageRevisions),
+                    revision_key(&message.uuid, 0),
+                    serialize(&legacy("hello", None)).unwrap(),
+                )
+                .unwrap();
+            db.db
+                .put_cf(
+                    db.column(Column::Meta),
+                    SCHEMA_VERSION_KEY,
+                    6u32.to_be_bytes(),
+                )
+                .unwrap();
+        }
+
+        // The plaintext stays readable in legacy envelopes, revisions included,
+        // with the place of the message in history and in its thread.
+        let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+        let migrated = db
+            .find_message(&room1.uuid, &message.uuid)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            legacy_content(&migrated),
+            Some(Content::Text(text)) if text == "hello!"
+        ));
+        assert_eq!(migrated.envelope.as_ref().unwrap().room, room1.uuid);
+        assert!(migrated.edited.is_some());
+        assert_eq!(migrated.reply_to, Some(parent));
+        assert_eq!(migrated.thread_root, Some(parent));
+        let revisions = db.find_revisions(&message.uuid).unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert!(matches!(
+            legacy_content(&revisions[0]),
+            Some(Content::Text(text)) if text == "hello"
+        ));
+        let (edits, _) = db
+            .find_edits(&room1.uuid, 10, EditCursor::before(UNIX_EPOCH).unwrap())
+            .unwrap();
+        assert_eq!(edits.len(), 1);
+    }
+
+    #[test]
+    fn test_purge_legacy_messages() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = RocksDb::new(temp_dir.path()).expect("Db should be opened");
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let content = Content::Text("plaintext-5e1f".to_string());
+        let mut legacy = text_message("", &user1);
+        legacy.envelope = Some(Envelope::legacy(room1.uuid, &content, legacy.created));
+        db.save_message(&room1, &legacy).unwrap();
+        db.add_reaction(&legacy.uuid, &user1.uuid, "👍").unwrap();
+        // Re-encrypted by its sender since, with the plaintext as a revision.
+        let mut reencrypted = text_message("", &user1);
+        reencrypted.envelope = legacy.envelope.clone();
+        db.save_message(&room1, &reencrypted).unwrap();
+        let reencrypted = reencrypted.edit(envelope(room1.uuid, Uuid::nil(), "kept-3a9c"));
+        assert!(db.edit_message(&room1.uuid, &reencrypted).unwrap());
+
+        assert_eq!(db.purge_legacy_messages().unwrap(), 1);
+        let purged = db.find_message(&room1.uuid, &legacy.uuid).unwrap().unwrap();
+        assert!(purged.is_deleted());
+        assert!(db.find_reactions(&legacy.uuid).unwrap().is_empty());
+        let kept = db
+            .find_message(&room1.uuid, &reencrypted.uuid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(text(&kept), "kept-3a9c");
+        assert!(db.find_revisions(&reencrypted.uuid).unwrap().is_empty());
+        let (edits, _) = db
+            .find_edits(&room1.uuid, 10, EditCursor::before(UNIX_EPOCH).unwrap())
+            .unwrap();
+        assert_eq!(edits.len(), 1);
+
+        assert!(!stored(temp_dir.path(), b"plaintext-5e1f"));
+        assert!(stored(temp_dir.path(), b"kept-3a9c"));
+        assert_eq!(db.purge_legacy_messages().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_direct_messages() {
+        let db = open_db();
+        let (user1, user2) = (Uuid::new_v4(), Uuid::new_v4());
+        let (laptop, phone) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        let direct = |device, secs| DirectMessage {
+            uuid: Uuid::new_v4(),
+            room: Uuid::new_v4(),
+            sender: user2,
+            sender_device: Uuid::new_v4(),
+            recipient: user1,
+            recipient_device: device,
+            payload: b"key".to_vec(),
+            created: UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let (later, earlier, other) = (direct(laptop, 2), direct(laptop, 1), direct(phone, 1));
+        for message in [&later, &earlier, &other] {
+            db.save_direct_message(message, 2).unwrap();
+        }
+        assert!(matches!(
+            db.save_direct_message(&direct(laptop, 3), 2),
+            Err(DbError::Invalid(_))
+        ));
+        // The limit is per sender, so others still get through.
+        let from_user3 = DirectMessage {
+            sender: Uuid::new_v4(),
+            ..direct(laptop, 3)
+        };
+        db.save_direct_message(&from_user3, 2).unwrap();
+
+        assert_eq!(
+            db.find_direct_messages(&user1, &laptop, 10).unwrap(),
+            [earlier.clone(), later.clone(), from_user3.clone()]
+        );
+        assert_eq!(
+            db.find_direct_messages(&user1, &laptop, 1).unwrap()[0],
+            earlier
+        );
+        assert!(db
+            .find_direct_messages(&user2, &laptop, 10)
+            .unwrap()
+            .is_empty());
+
+        db.delete_direct_messages(&user1, &laptop, &[earlier.uuid, other.uuid])
+            .unwrap();
+        assert_eq!(
+            db.find_direct_messages(&user1, &laptop, 10).unwrap(),
+            [later, from_user3]
+        );
+        assert_eq!(
+            db.find_direct_messages(&user1, &phone, 10).unwrap(),
+            [other]
+        );
+    }
+
+    fn legacy_content(message: &Message) -> Option<Content> {
+        message.envelope.as_ref()?.legacy_content()
+    }
+
+    #[test]
+    fn test_reactions() {
+        let db = open_db();
+        let user1 = User::new("user1".to_string(), [1; 32]);
+        let user2 = User::new("user2".to_string(), [2; 32]);
+        let room1 = Room::new("Room1", &user1);
+        let message = text_message("hello", &user1);
+        let other = text_message("other", &user1);
+        db.save_message(&room1, &message).unwrap();
+        db.save_message(&room1, &other).unwrap();
+
+        assert!(db.add_reaction(&message.uuid, &user1.uuid, "👍").unwrap());
+        assert!(!db.add_reaction(&message.uuid, &user1.uuid, "👍").unwrap());
+        assert!(db.add_reaction(&message.uuid, &user2.uuid, "👍").unwrap());
+        assert!(db.add_reaction(&message.uuid, &user2.uuid, "🎉").unwrap());
+        assert!(db.add_reaction(&other.uuid, &user2.uuid, "🎉").unwrap());
+        assert!(matches!(
+            db.add_reaction(&message.uuid, &user1.uuid, ""),
+            Err(DbError::Invalid(_))
+        ));
+        assert_eq!(db.find_reactions(&message.uuid).unwrap().len(), 3);
+
+        assert!(db
+            .remove_reaction(&message.uuid, &user2.uuid, "👍")
+            .unwrap());
+        assert!(!db
+            .remove_reaction(&message.uuid, &user2.uuid, "👍")
+            .unwrap());
+        let mut reactions = db.find_reactions(&message.uuid).unwrap();
+        reactions.sort();
+        let mut expected = vec![
+            (user1.uuid, "👍".to_string()),
+            (user2.uuid, "🎉".to_string()),
+        ];
+        expected.sort();
+        assert_eq!(reactions, expected);
+
+        // Deleting a message drops its reactions.
+        db.delete_message(&room1.uuid, &message.tombstone(), &user1.uuid)
+            .unwrap();
+        assert!(db.find_reactions(&message.uuid).unwrap().is_empty());
+        assert!(!db.add_reaction(&message.uuid, &user1.uuid, "👍").unwrap());
+        assert!(db.find_reactions(&message.uuid).unwrap().is_empty());
+        assert!(matches!(
+            db.add_reaction(&other.uuid, &user1.uuid, &"x".repeat(MAX_EMOJI_LEN + 1)),
+            Err(DbError::Invalid(_))
+        ));
+        db.delete_room(&room1.uuid).unwrap();
+        assert!(db.find_reactions(&other.uuid).unwrap().is_empty());
+        assert!(!db.add_reaction(&other.uuid, &user1.uuid, "👍").unwrap());
+    }
+
+    #[test]
+    fn test_prekeys() {
+        let db = open_db();
+        let identity = SigningKey::from_bytes(&[1; 32]);
+        let user1 = User::new("user1".to_string(), identity_key(&identity));
+        let user2 = User::new("user2".to_string(), [2; 32]);
+        let (laptop, phone) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        assert!(db.find_signed_prekeys(&user1.uuid).unwrap().is_empty());
+
+        let signed = SignedPrekey::new(1, [3; 32], &identity);
+        db.save_signed_prekey(&user1.uuid, &laptop, &signed)
+            .unwrap();
+        let rotated = SignedPrekey::new(2, [4; 32], &identity);
+        db.save_signed_prekey(&user1.uuid, &laptop, &rotated)
+            .unwrap();
+        db.save_signed_prekey(&user1.uuid, &phone, &signed).unwrap();
+        assert_eq!(
+            db.find_signed_prekeys(&user1.uuid).unwrap(),
+            [(laptop, rotated), (phone, signed)]
+        );
+        assert!(db.find_signed_prekeys(&user2.uuid).unwrap().is_empty());
+
+        let prekeys: Vec<OneTimePrekey> = [300, 2, 1]
+            .into_iter()
+            .map(|id| OneTimePrekey {
+                id,
+                key: [id as u8; 32],
+            })
+            .collect();
+        assert_eq!(
+            db.save_one_time_prekeys(&user1.uuid, &laptop, &prekeys, 3)
+                .unwrap(),
+            3
+        );
+        db.save_one_time_prekeys(&user1.uuid, &phone, &prekeys[..1], 3)
+            .unwrap();
+        db.save_one_time_prekeys(&user2.uuid, &laptop, &prekeys[..1], 3)
+            .unwrap();
+        assert!(matches!(
+            db.save_one_time_prekeys(&user1.uuid, &laptop, &prekeys[..1], 3),
+            Err(DbError::Invalid(_))
+        ));
+
+        // Prekeys are taken lowest id first, and only once.
+        for id in [1, 2, 300] {
+            let prekey = db
+                .take_one_time_prekey(&user1.uuid, &laptop)
+                .unwrap()
+                .unwrap();
+            assert_eq!(prekey.id, id);
+            assert_eq!(prekey.key, [id as u8; 32]);
+        }
+        assert_eq!(db.take_one_time_prekey(&user1.uuid, &laptop).unwrap(), None);
+        assert_eq!(db.count_one_time_prekeys(&user1.uuid, &laptop).unwrap(), 0);
+        assert_eq!(db.count_one_time_prekeys(&user1.uuid, &phone).unwrap(), 1);
+        assert_eq!(db.count_one_time_prekeys(&user2.uuid, &laptop).unwrap(), 1);
+    }
+
+    fn text_message(text: &str, sender: &User) -> Message {
+        Message::new(envelope(Uuid::nil(), Uuid::nil(), text), sender)
+    }
+
     fn open_db() -> Box<dyn DbConnection> {
         let temp_dir = TempDir::new().unwrap();
         let config = HashMap::from([(ConfigName::Path, ConfigValue::Path(temp_dir.into_path()))]);
         RocksDb::open(&config).expect("Db should be opened")
     }
+
+    fn open_rocksdb() -> RocksDb {
+        let temp_dir = TempDir::new().unwrap();
+        RocksDb::new(&temp_dir.into_path()).expect("Db should be opened")
+    }
 }
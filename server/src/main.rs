@@ -1,13 +1,24 @@
 use crate::cli::Cli;
+use crate::db::{ConfigName, ConfigValue, Db, RocksDb};
 use crate::logging::set_up_logging;
 use clap::Parser;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::signal;
 use tracing::{debug, info};
 
 mod cli;
+mod connection;
+mod db;
 mod logging;
+mod rate_limit;
 mod server;
+mod sessions;
+mod shutdown;
+#[cfg(test)]
+mod testing;
 
 /// Default port that a chat server listens on.
 ///
@@ -20,6 +31,11 @@ const DEFAULT_PORT: u16 = 6379;
 /// an active connection terminates.
 const MAX_CONNECTIONS: usize = 250;
 
+/// Default directory the chat server keeps its database in.
+///
+/// Used if no path is specified.
+const DEFAULT_DB_PATH: &str = "db";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     set_up_logging()?;
@@ -29,12 +45,29 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     let port = cli.port.unwrap_or(DEFAULT_PORT);
     let max_connections = cli.max_connections.unwrap_or(MAX_CONNECTIONS);
+    let db_path = cli
+        .db_path
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DB_PATH));
+
+    debug!("Opening the database at {}...", db_path.display());
+
+    let db = RocksDb::open(&HashMap::from([(
+        ConfigName::Path,
+        ConfigValue::Path(db_path),
+    )]))?;
+
+    if cli.purge_legacy_messages {
+        info!("Purging legacy plaintext messages...");
+        let purged = db.purge_legacy_messages()?;
+        info!("Purged {purged} legacy plaintext messages");
+        return Ok(());
+    }
 
     debug!("Binding a TCP listener on port {port}...");
 
     let listener = TcpListener::bind(&format!("127.0.0.1:{}", port)).await?;
 
-    server::run(listener, max_connections, signal::ctrl_c()).await;
+    server::run(listener, Arc::from(db), max_connections, signal::ctrl_c()).await;
 
     Ok(())
 }
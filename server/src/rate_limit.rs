@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Number of keys tracked before expired windows are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits how often the same key, such as a user and the target of its
+/// request, is allowed within a fixed window of time.
+///
+/// Counts live in memory only, so they start over when the server restarts.
+#[derive(Debug)]
+pub(crate) struct RateLimiter<K> {
+    max: u32,
+    window: Duration,
+    /// Start of the current window of each key, and the hits in it.
+    hits: Mutex<HashMap<K, (Instant, u32)>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Allow up to `max` hits of each key per `window`.
+    pub(crate) fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a hit of `key`. Returns `false` if it went over the limit, in
+    /// which case it is not counted.
+    pub(crate) fn hit(&self, key: K) -> bool {
+        self.hit_at(key, Instant::now())
+    }
+
+    fn hit_at(&self, key: K, now: Instant) -> bool {
+        let mut hits = self.hits.lock().unwrap();
+        if hits.len() >= PRUNE_THRESHOLD {
+            hits.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+        let (start, count) = hits.entry(key).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= self.max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hits_reset_with_the_window() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limiter.hit_at("a", start));
+        assert!(limiter.hit_at("a", start));
+        assert!(!limiter.hit_at("a", start + Duration::from_secs(59)));
+        assert!(limiter.hit_at("b", start));
+
+        assert!(limiter.hit_at("a", start + Duration::from_secs(60)));
+        assert!(limiter.hit_at("a", start + Duration::from_secs(61)));
+        assert!(!limiter.hit_at("a", start + Duration::from_secs(62)));
+    }
+}
@@ -1,9 +1,30 @@
+use crate::connection::Connection;
+use crate::db::{DbConnection, DbError};
+use crate::rate_limit::RateLimiter;
+use crate::sessions::{SessionId, Sessions};
+use crate::shutdown::Shutdown;
+use shared::auth::{Challenge, Signature};
+use shared::protocol::{
+    ErrorCode, Features, Frame, HandshakeError, Request, Response, SoftwareInfo,
+};
+use shared::types::{Address, IdentityKey, User};
+use std::collections::HashMap;
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc, Semaphore};
-use tokio::time::{self, Duration};
-use tracing::{error, info};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+mod delivery;
+mod direct;
+mod keys;
+mod messages;
+mod presence;
+mod reactions;
+mod receipts;
+mod rooms;
 
 /// Error returned by most functions.
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -13,6 +34,22 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 /// This is defined as a convenience.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Outcome of a request: the response to send back, which is an error
+/// response in the `Err` case.
+type Reply = std::result::Result<Response, Rejection>;
+
+/// Error response rejecting a request, boxed as responses are large.
+type Rejection = Box<Response>;
+
+/// Maximum time a client is given to send its `Hello` after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Optional protocol features implemented by this server.
+const SERVER_FEATURES: Features = Features::empty();
+
+/// Maximum number of users in one `FetchUsers` request.
+const MAX_FETCHED_USERS: usize = 256;
+
 /// Run the chat server.
 ///
 /// Accepts connections from the supplied listener. For each inbound connection,
@@ -22,7 +59,12 @@ pub type Result<T> = std::result::Result<T, Error>;
 ///
 /// `tokio::signal::ctrl_c()` can be used as the `shutdown` argument. This will
 /// listen for a SIGINT signal.
-pub async fn run(listener: TcpListener, max_connections: usize, shutdown: impl Future) {
+pub async fn run(
+    listener: TcpListener,
+    db: Arc<dyn DbConnection>,
+    max_connections: usize,
+    shutdown: impl Future,
+) {
     // When the provided `shutdown` future completes, we must send a shutdown
     // message to all active connections. We use a broadcast channel for this
     // purpose. The call below ignores the receiver of the broadcast pair, and when
@@ -34,6 +76,10 @@ pub async fn run(listener: TcpListener, max_connections: usize, shutdown: impl F
     // Initialize the listener state
     let mut listener = Listener {
         listener,
+        db,
+        sessions: Arc::new(Sessions::default()),
+        rooms_lock: Arc::new(Mutex::new(())),
+        bundle_fetches: Arc::new(keys::bundle_fetch_limiter()),
         limit_connections: Arc::new(Semaphore::new(max_connections)),
         notify_shutdown,
         shutdown_complete_tx,
@@ -100,11 +146,22 @@ pub async fn run(listener: TcpListener, max_connections: usize, shutdown: impl F
 
 /// Server listener state. Created in the `run` call. It includes a `run` method
 /// which performs the TCP listening and initialization of per-connection state.
-#[derive(Debug)]
 struct Listener {
     /// TCP listener supplied by the `run` caller.
     listener: TcpListener,
 
+    /// Database connection shared by all connection handlers.
+    db: Arc<dyn DbConnection>,
+
+    /// Sessions of all logged in users, shared by all connection handlers.
+    sessions: Arc<Sessions>,
+
+    /// Serializes read-modify-write cycles on rooms across connections.
+    rooms_lock: Arc<Mutex<()>>,
+
+    /// Prekey bundle fetches of all users, shared by all connection handlers.
+    bundle_fetches: Arc<RateLimiter<(Uuid, Uuid)>>,
+
     /// Limit the max number of connections.
     ///
     /// A `Semaphore` is used to limit the max number of connections. Before
@@ -178,6 +235,37 @@ impl Listener {
             // The `accept` method internally attempts to recover errors, so an
             // error here is non-recoverable.
             let socket = self.accept().await?;
+
+            // Create the necessary per-connection handler state.
+            let mut handler = Handler {
+                db: self.db.clone(),
+                sessions: self.sessions.clone(),
+                rooms_lock: self.rooms_lock.clone(),
+                bundle_fetches: self.bundle_fetches.clone(),
+                connection: Connection::new(socket),
+                _permit: permit,
+                // Receive shutdown notifications.
+                shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
+                // Notifies the receiver half once all clones are dropped.
+                _shutdown_complete: self.shutdown_complete_tx.clone(),
+                challenge: None,
+                user: None,
+                device: None,
+                session: None,
+                pushes: None,
+                typing: HashMap::new(),
+            };
+
+            // Spawn a new task to process the connections. Tokio tasks are like
+            // asynchronous green threads and are executed concurrently.
+            tokio::spawn(async move {
+                // Process the connection. If an error is encountered, log it.
+                if let Err(err) = handler.run().await {
+                    error!(cause = ?err, "connection error");
+                }
+                // The permit is dropped together with the handler, returning
+                // it to the semaphore.
+            });
         }
     }
 
@@ -213,3 +301,2036 @@ impl Listener {
         }
     }
 }
+
+/// Per-connection handler. Reads requests from `connection` and writes the
+/// responses back to the peer.
+struct Handler {
+    /// Shared database connection.
+    db: Arc<dyn DbConnection>,
+
+    /// Shared registry of the sessions of logged in users.
+    sessions: Arc<Sessions>,
+
+    /// Shared lock held while a room is read, changed and written back.
+    rooms_lock: Arc<Mutex<()>>,
+
+    /// Shared limiter of prekey bundle fetches.
+    bundle_fetches: Arc<RateLimiter<(Uuid, Uuid)>>,
+
+    /// The TCP connection wrapped with a framed encoder / decoder.
+    ///
+    /// When `Listener` receives an inbound connection, the `TcpStream` is
+    /// passed to `Connection::new`, which initializes the associated buffers.
+    /// `Connection` allows the handler to operate at the "frame" level and keep
+    /// the byte level protocol parsing details encapsulated in `Connection`.
+    connection: Connection,
+
+    /// Permit acquired from `Listener::limit_connections`.
+    ///
+    /// Held for the lifetime of the handler, so the connection slot is only
+    /// released once the connection has been fully processed.
+    _permit: OwnedSemaphorePermit,
+
+    /// Listen for shutdown notifications.
+    ///
+    /// A wrapper around the `broadcast::Receiver` paired with the sender in
+    /// `Listener`. The connection handler processes requests from the
+    /// connection until the peer disconnects **or** a shutdown notification is
+    /// received from `shutdown`. In the latter case, any in-flight work being
+    /// processed for the peer is continued until it reaches a safe state, at
+    /// which point the connection is terminated.
+    shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `Handler` is dropped, this clone of
+    /// `Listener::shutdown_complete_tx` is dropped as well, which lets `run`
+    /// know that the connection has completed.
+    _shutdown_complete: mpsc::Sender<()>,
+
+    /// Challenge handed out to the peer and not yet used to log in.
+    challenge: Option<Challenge>,
+
+    /// User the peer logged in as.
+    user: Option<User>,
+
+    /// Device of the user the peer logged in from.
+    device: Option<Uuid>,
+
+    /// Session registered in `sessions` for the logged in user.
+    session: Option<SessionId>,
+
+    /// Frames pushed to the session by other connections, to be written to
+    /// the peer.
+    pushes: Option<mpsc::Receiver<Frame>>,
+
+    /// Rooms where the logged in user types, with the time its typing expires.
+    typing: HashMap<Uuid, Instant>,
+}
+
+impl Handler {
+    /// Process a single connection.
+    ///
+    /// Request frames are read from the socket and processed. Responses are
+    /// written back to the socket.
+    ///
+    /// When the shutdown signal is received, the connection is processed until
+    /// it reaches a safe state, at which point it is terminated.
+    async fn run(&mut self) -> Result<()> {
+        // No request is accepted before both ends agree on a protocol version.
+        tokio::select! {
+            res = Self::handshake(&mut self.connection) => {
+                if !res? {
+                    return Ok(());
+                }
+            }
+            _ = self.shutdown.recv() => return Ok(()),
+        }
+
+        // As long as the shutdown signal has not been received, try to read a
+        // new request frame.
+        while !self.shutdown.is_shutdown() {
+            // While reading a request frame, also forward pushes to the peer,
+            // expire the typing of the user and listen for the shutdown signal.
+            let typing_deadline = self.typing_deadline();
+            let maybe_frame = tokio::select! {
+                res = self.connection.read_frame() => res?,
+                push = next_push(&mut self.pushes) => {
+                    let Some(frame) = push else {
+                        // The session fell behind its pushes and was dropped.
+                        // The client catches up with `Sync` once reconnected.
+                        debug!("session dropped, closing the connection");
+                        return Ok(());
+                    };
+                    self.connection.write_frame(frame).await?;
+                    continue;
+                }
+                _ = sleep_until(typing_deadline) => {
+                    self.expire_typing();
+                    continue;
+                }
+                _ = self.shutdown.recv() => {
+                    // If a shutdown signal is received, return from `run`.
+                    // This will result in the task terminating.
+                    return Ok(());
+                }
+            };
+
+            // If `None` is returned from `read_frame()` then the peer closed
+            // the socket. There is no further work to do and the task can be
+            // terminated.
+            let frame = match maybe_frame {
+                Some(frame) => frame,
+                None => {
+                    debug!("peer closed the connection");
+                    return Ok(());
+                }
+            };
+
+            debug!(?frame, "received frame");
+
+            // Clients only ever send requests. Anything else is a protocol
+            // violation, and the connection is dropped.
+            let Frame::Request { id, request } = frame else {
+                return Err(format!("unexpected frame from client: {frame:?}").into());
+            };
+
+            let response = match request {
+                // Streams frames of its own before the response.
+                Request::Sync => self.sync().await?,
+                request => self.dispatch(request),
+            };
+            self.connection
+                .write_frame(Frame::Response { id, response })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Perform the protocol handshake.
+    ///
+    /// Returns `false` if the connection must be closed, either because the
+    /// peer went away, did not say hello in time or the hello was rejected.
+    async fn handshake(connection: &mut Connection) -> Result<bool> {
+        let frame = match time::timeout(HANDSHAKE_TIMEOUT, connection.read_frame()).await {
+            Ok(frame) => frame?,
+            Err(_) => {
+                debug!("handshake timed out");
+                return Ok(false);
+            }
+        };
+
+        let reply = match frame {
+            Some(Frame::Hello(hello)) => {
+                debug!(client = ?hello.client, version = hello.version, "received hello");
+                hello.negotiate(
+                    SERVER_FEATURES,
+                    SoftwareInfo {
+                        name: env!("CARGO_PKG_NAME").to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    },
+                )
+            }
+            Some(_) => Err(HandshakeError::ExpectedHello),
+            None => return Ok(false),
+        };
+
+        match reply {
+            Ok(welcome) => {
+                connection.write_frame(Frame::Welcome(welcome)).await?;
+                Ok(true)
+            }
+            Err(err) => {
+                debug!(cause = %err, "handshake rejected");
+                connection.write_frame(Frame::HandshakeError(err)).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Apply a single request and produce the response to write back.
+    fn dispatch(&mut self, request: Request) -> Response {
+        let result = match request {
+            Request::Ping => Ok(Response::Pong),
+            Request::Challenge => {
+                let challenge = Challenge(rand::random());
+                self.challenge = Some(challenge);
+                Ok(Response::Challenge(challenge))
+            }
+            Request::Register {
+                address,
+                identity_key,
+                device,
+                signature,
+            } => self.register(address, identity_key, device, &signature),
+            Request::Login {
+                user,
+                device,
+                signature,
+            } => self.login(&user, device, &signature),
+            Request::FindUser { address } => self.find_user(&address),
+            Request::CreateRoom { name } => self.create_room(name),
+            Request::RenameRoom { room, name } => self.rename_room(&room, name),
+            Request::AddMembers { room, members } => self.add_members(&room, members),
+            Request::LeaveRoom { room } => self.leave_room(&room),
+            Request::KickMember { room, member } => self.kick_member(&room, &member),
+            Request::ListRooms { limit, after } => self.list_rooms(limit, after),
+            Request::DeleteRoom { room } => self.delete_room(&room),
+            Request::SendMessage {
+                envelope,
+                reply_to,
+                thread_root,
+            } => self.send_message(envelope, reply_to, thread_root),
+            Request::EditMessage { message, envelope } => self.edit_message(&message, envelope),
+            Request::DeleteMessage { room, message } => self.delete_message(&room, &message),
+            Request::FetchRevisions { room, message } => self.fetch_revisions(&room, &message),
+            Request::AddReaction {
+                room,
+                message,
+                emoji,
+            } => self.react(&room, &message, emoji, true),
+            Request::RemoveReaction {
+                room,
+                message,
+                emoji,
+            } => self.react(&room, &message, emoji, false),
+            Request::AckDelivery { room, message } => self.ack_delivery(&room, &message),
+            Request::MarkRead { room, message } => self.mark_read(&room, &message),
+            Request::FetchReceipts { room } => self.fetch_receipts(&room),
+            // Streams frames of its own, so `run` handles it.
+            Request::Sync => Err(Box::new(Response::error(
+                ErrorCode::Internal,
+                "sync can not be dispatched",
+            ))),
+            Request::FetchHistory { room, limit, query } => self.fetch_history(&room, limit, query),
+            Request::FetchThread {
+                room,
+                root,
+                limit,
+                after,
+            } => self.fetch_thread(&room, &root, limit, after.as_ref()),
+            Request::SetTyping { room, typing } => self.set_typing(&room, typing),
+            Request::SetPresence { presence } => self.set_presence(presence),
+            Request::FetchPresence { users } => self.fetch_presence(users),
+            Request::UploadPrekeys {
+                signed_prekey,
+                one_time_prekeys,
+            } => self.upload_prekeys(signed_prekey, one_time_prekeys),
+            Request::FetchPrekeyBundle { user } => self.fetch_prekey_bundle(&user),
+            Request::SendDirect {
+                room,
+                user,
+                device,
+                payload,
+            } => self.send_direct(&room, &user, &device, payload),
+            Request::FetchDirect { limit } => self.fetch_direct(limit),
+            Request::AckDirect { messages } => self.ack_direct(&messages),
+            Request::FetchUsers { users } => self.fetch_users(&users),
+        };
+        result.unwrap_or_else(|response| *response)
+    }
+
+    /// Create a user owning `identity_key` and bind it to this connection.
+    fn register(
+        &mut self,
+        address: Address,
+        identity_key: IdentityKey,
+        device: Uuid,
+        signature: &Signature,
+    ) -> Reply {
+        self.verify_challenge(&identity_key, signature)?;
+
+        let user = User::new(address, identity_key);
+        self.db.save_user(&user)?;
+
+        debug!(user = %user.uuid, "registered");
+        self.bind_user(user.clone(), device)?;
+        Ok(Response::LoggedIn { user })
+    }
+
+    /// Bind an existing user to this connection.
+    fn login(&mut self, user_uuid: &Uuid, device: Uuid, signature: &Signature) -> Reply {
+        let user = self
+            .db
+            .find_user(user_uuid)?
+            .ok_or_else(|| Response::error(ErrorCode::NotFound, format!("no user {user_uuid}")))?;
+        self.verify_challenge(&user.identity_key, signature)?;
+
+        debug!(user = %user.uuid, %device, "logged in");
+        self.bind_user(user.clone(), device)?;
+        Ok(Response::LoggedIn { user })
+    }
+
+    /// Make `user` on `device` the user of this connection, and register a
+    /// session for it so it receives pushes, including the messages of its
+    /// rooms.
+    fn bind_user(&mut self, user: User, device: Uuid) -> std::result::Result<(), DbError> {
+        self.unbind_user();
+        self.db.register_device(&user.uuid, &device)?;
+        // Membership can not change while the session subscribes to its rooms.
+        let _guard = self.rooms_lock.lock().unwrap();
+        let presence = self.sessions.presence(&user.uuid);
+        let (session, pushes) = self.sessions.register(user.uuid, device);
+        self.session = Some(session);
+        self.pushes = Some(pushes);
+
+        let mut after = None;
+        loop {
+            let (rooms, next) = self.db.list_rooms_for_user(&user.uuid, 100, after)?;
+            self.sessions
+                .subscribe(session, rooms.iter().map(|room| room.uuid));
+            if next.is_none() {
+                break;
+            }
+            after = next;
+        }
+        self.announce_presence(&user.uuid, presence, self.sessions.peers(session));
+        self.user = Some(user);
+        self.device = Some(device);
+        Ok(())
+    }
+
+    /// Forget the logged in user, if any, and its session.
+    fn unbind_user(&mut self) {
+        self.stop_all_typing();
+        if let (Some(user), Some(session)) = (self.user.take(), self.session.take()) {
+            let presence = self.sessions.presence(&user.uuid);
+            let peers = self.sessions.peers(session);
+            self.sessions.unregister(session);
+            self.announce_presence(&user.uuid, presence, peers);
+        }
+        self.device = None;
+        self.pushes = None;
+    }
+
+    /// Resolve a user from its public address.
+    fn find_user(&self, address: &Address) -> Reply {
+        self.user()?;
+
+        match self.db.find_user_by_address(address)? {
+            Some(user) => Ok(Response::User { user }),
+            None => Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("no user with address {address}"),
+            ))),
+        }
+    }
+
+    fn fetch_users(&self, users: &[Uuid]) -> Reply {
+        self.user()?;
+        if users.len() > MAX_FETCHED_USERS {
+            return Err(Box::new(Response::error(
+                ErrorCode::BadRequest,
+                format!("at most {MAX_FETCHED_USERS} users per request"),
+            )));
+        }
+
+        let mut found = Vec::with_capacity(users.len());
+        for user in users {
+            found.extend(self.db.find_user(user)?);
+        }
+        Ok(Response::Users { users: found })
+    }
+
+    /// Check `signature` against the outstanding challenge. A challenge can
+    /// only be used once, whatever the outcome.
+    fn verify_challenge(
+        &mut self,
+        identity_key: &IdentityKey,
+        signature: &Signature,
+    ) -> std::result::Result<(), Rejection> {
+        let challenge = self
+            .challenge
+            .take()
+            .ok_or_else(|| Response::error(ErrorCode::BadRequest, "no challenge requested"))?;
+        challenge.verify(identity_key, signature).map_err(|err| {
+            Box::new(Response::error(
+                ErrorCode::AuthenticationFailed,
+                err.to_string(),
+            ))
+        })
+    }
+
+    /// The user logged in on this connection.
+    fn user(&self) -> std::result::Result<&User, Rejection> {
+        self.user.as_ref().ok_or_else(|| {
+            Box::new(Response::error(
+                ErrorCode::Unauthenticated,
+                "login required",
+            ))
+        })
+    }
+
+    /// The user logged in on this connection and the device it logged in from.
+    fn device(&self) -> std::result::Result<(&User, Uuid), Rejection> {
+        let user = self.user()?;
+        let device = self.device.expect("logged in users have a device");
+        Ok((user, device))
+    }
+}
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        self.unbind_user();
+    }
+}
+
+/// Receive the next frame pushed to a session, if there is a session.
+async fn next_push(pushes: &mut Option<mpsc::Receiver<Frame>>) -> Option<Frame> {
+    match pushes {
+        Some(pushes) => pushes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Report a database error to the peer.
+///
+/// Failures of the server itself are logged, and reported without details.
+impl From<DbError> for Response {
+    fn from(err: DbError) -> Self {
+        let code = match &err {
+            DbError::AddressTaken(_) => ErrorCode::AddressTaken,
+            DbError::Invalid(_) => ErrorCode::BadRequest,
+            DbError::Corrupt(_)
+            | DbError::SchemaTooNew { .. }
+            | DbError::Config(_)
+            | DbError::Io(_) => {
+                error!(cause = %err, "database failure");
+                return Response::error(ErrorCode::Internal, "internal server error");
+            }
+        };
+        Response::error(code, err.to_string())
+    }
+}
+
+impl From<DbError> for Rejection {
+    fn from(err: DbError) -> Self {
+        Box::new(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ConfigName, ConfigValue, Db, RocksDb};
+    use crate::testing::{envelope, text};
+    use futures::{SinkExt, StreamExt};
+    use shared::auth::{identity_key, SigningKey};
+    use shared::keys::{OneTimePrekey, SignedPrekey};
+    use shared::protocol::{
+        Cursor, FrameCodec, Hello, HistoryQuery, Presence, Push, ReactionCount, Receipt, RequestId,
+        RoomEvent, PROTOCOL_VERSION,
+    };
+    use shared::types::{Message, Room};
+    use std::net::SocketAddr;
+    use tempfile::TempDir;
+    use tokio_util::codec::Framed;
+
+    type Client = Framed<TcpStream, FrameCodec>;
+
+    fn hello() -> Hello {
+        Hello::new(
+            SoftwareInfo {
+                name: "test".to_string(),
+                version: "0.0.1".to_string(),
+            },
+            Features::COMPRESSION,
+        )
+    }
+
+    fn open_db() -> Arc<dyn DbConnection> {
+        let temp_dir = TempDir::new().unwrap();
+        let config = HashMap::from([(ConfigName::Path, ConfigValue::Path(temp_dir.into_path()))]);
+        Arc::from(RocksDb::open(&config).expect("Db should be opened"))
+    }
+
+    async fn start_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, open_db(), 10, std::future::pending::<()>()));
+        addr
+    }
+
+    async fn request(client: &mut Client, id: RequestId, request: Request) -> Response {
+        client.send(Frame::Request { id, request }).await.unwrap();
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                Frame::Response {
+                    id: response_id,
+                    response,
+                } if response_id == id => return response,
+                // Other clients of the test come and go at any time.
+                Frame::Push(Push::Presence { .. }) => continue,
+                other => panic!("unexpected frame: {other:?}"),
+            }
+        }
+    }
+
+    async fn challenge(client: &mut Client) -> Challenge {
+        match request(client, 0, Request::Challenge).await {
+            Response::Challenge(challenge) => challenge,
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    async fn connect(addr: SocketAddr, hello: Hello) -> (Client, Frame) {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = Framed::new(socket, FrameCodec::new());
+        client.send(Frame::Hello(hello)).await.unwrap();
+        let reply = client.next().await.unwrap().unwrap();
+        (client, reply)
+    }
+
+    #[tokio::test]
+    async fn test_handler_serves_client_until_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run(listener, open_db(), 1, shutdown_rx));
+
+        let (mut client, welcome) = connect(addr, hello()).await;
+        match welcome {
+            Frame::Welcome(welcome) => {
+                assert_eq!(welcome.version, PROTOCOL_VERSION);
+                assert_eq!(welcome.features, Features::empty());
+            }
+            other => panic!("unexpected frame: {other:?}"),
+        }
+        client
+            .send(Frame::Request {
+                id: 1,
+                request: Request::Ping,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            Frame::Response {
+                id: 1,
+                response: Response::Pong
+            }
+        ));
+
+        shutdown_tx.send(()).unwrap();
+        // The handler closes the connection once shutdown is signalled and
+        // `run` completes after all handlers are gone.
+        assert!(client.next().await.is_none());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handler_rejects_incompatible_version() {
+        let addr = start_server().await;
+
+        let mut too_new = hello();
+        too_new.version = PROTOCOL_VERSION + 1;
+        too_new.min_version = PROTOCOL_VERSION + 1;
+        let (mut client, reply) = connect(addr, too_new).await;
+        assert!(matches!(
+            reply,
+            Frame::HandshakeError(HandshakeError::UnsupportedVersion { .. })
+        ));
+        assert!(client.next().await.is_none());
+    }
+
+    fn other_key() -> SigningKey {
+        SigningKey::from_bytes(&[8; 32])
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let addr = start_server().await;
+        let key = SigningKey::from_bytes(&[7; 32]);
+
+        let (mut client, _) = connect(addr, hello()).await;
+        let send = Request::SendMessage {
+            envelope: envelope(Uuid::new_v4(), device(1), "hi"),
+            reply_to: None,
+            thread_root: None,
+        };
+        assert!(matches!(
+            request(&mut client, 1, send.clone()).await,
+            Response::Error {
+                code: ErrorCode::Unauthenticated,
+                ..
+            }
+        ));
+
+        let signature = challenge(&mut client).await.sign(&key);
+        let registered = match request(
+            &mut client,
+            2,
+            Request::Register {
+                address: "alice".to_string(),
+                identity_key: identity_key(&key),
+                device: Uuid::new_v4(),
+                signature,
+            },
+        )
+        .await
+        {
+            Response::LoggedIn { user } => user,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        assert_eq!(registered.address, "alice");
+        assert!(!matches!(
+            request(&mut client, 3, send).await,
+            Response::Error {
+                code: ErrorCode::Unauthenticated,
+                ..
+            }
+        ));
+
+        match request(
+            &mut client,
+            7,
+            Request::FindUser {
+                address: "alice".to_string(),
+            },
+        )
+        .await
+        {
+            Response::User { user } => assert_eq!(user, registered),
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        // A new connection logs in as the registered user.
+        let (mut client, _) = connect(addr, hello()).await;
+        let signature = challenge(&mut client).await.sign(&other_key());
+        assert!(matches!(
+            request(
+                &mut client,
+                8,
+                Request::Register {
+                    address: "alice".to_string(),
+                    identity_key: identity_key(&other_key()),
+                    device: Uuid::new_v4(),
+                    signature,
+                },
+            )
+            .await,
+            Response::Error {
+                code: ErrorCode::AddressTaken,
+                ..
+            }
+        ));
+
+        let signature = challenge(&mut client).await.sign(&key);
+        let login = Request::Login {
+            user: registered.uuid,
+            device: Uuid::new_v4(),
+            signature,
+        };
+        match request(&mut client, 4, login.clone()).await {
+            Response::LoggedIn { user } => assert_eq!(user, registered),
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        // Challenges are single-use.
+        assert!(matches!(
+            request(&mut client, 5, login).await,
+            Response::Error {
+                code: ErrorCode::BadRequest,
+                ..
+            }
+        ));
+
+        // Unknown users are reported, not crashed on.
+        let signature = challenge(&mut client).await.sign(&key);
+        assert!(matches!(
+            request(
+                &mut client,
+                9,
+                Request::Login {
+                    user: Uuid::new_v4(),
+                    device: Uuid::new_v4(),
+                    signature,
+                }
+            )
+            .await,
+            Response::Error {
+                code: ErrorCode::NotFound,
+                ..
+            }
+        ));
+
+        // A signature made with another key is refused.
+        let signature = challenge(&mut client).await.sign(&other_key());
+        assert!(matches!(
+            request(
+                &mut client,
+                6,
+                Request::Login {
+                    user: registered.uuid,
+                    device: Uuid::new_v4(),
+                    signature,
+                }
+            )
+            .await,
+            Response::Error {
+                code: ErrorCode::AuthenticationFailed,
+                ..
+            }
+        ));
+    }
+
+    /// The device `register` logs in from with the key derived from `seed`.
+    fn device(seed: u8) -> Uuid {
+        Uuid::from_bytes([seed; 16])
+    }
+
+    /// Connect and register a user whose key is derived from `seed`.
+    async fn register(addr: SocketAddr, address: &str, seed: u8) -> (Client, User) {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        let (mut client, _) = connect(addr, hello()).await;
+        let signature = challenge(&mut client).await.sign(&key);
+        let register = Request::Register {
+            address: address.to_string(),
+            identity_key: identity_key(&key),
+            device: device(seed),
+            signature,
+        };
+        match request(&mut client, 1, register).await {
+            Response::LoggedIn { user } => (client, user),
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    async fn room_request(client: &mut Client, request: Request) -> Room {
+        match self::request(client, 1, request).await {
+            Response::Room { room } => room,
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    async fn room_push(client: &mut Client) -> (Room, RoomEvent) {
+        match client.next().await.unwrap().unwrap() {
+            Frame::Push(Push::Room { room, event }) => (room, event),
+            other => panic!("unexpected frame: {other:?}"),
+        }
+    }
+
+    fn error_code(response: Response) -> ErrorCode {
+        match response {
+            Response::Error { code, .. } => code,
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_room_lifecycle() {
+        let addr = start_server().await;
+        let (mut alice, alice_user) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let (mut carol, carol_user) = register(addr, "carol", 3).await;
+
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: " general ".to_string(),
+            },
+        )
+        .await;
+        assert_eq!(room.name, "general");
+        assert!(room.owners.contains(&alice_user.uuid));
+
+        // Non-members can not tell the room exists.
+        let rename = Request::RenameRoom {
+            room: room.uuid,
+            name: "random".to_string(),
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, rename.clone()).await),
+            ErrorCode::NotFound
+        );
+
+        let add = Request::AddMembers {
+            room: room.uuid,
+            members: vec![bob_user.uuid, carol_user.uuid],
+        };
+        let room = room_request(&mut alice, add).await;
+        assert_eq!(room.members.len(), 3);
+        let (pushed, event) = room_push(&mut bob).await;
+        assert_eq!(pushed.members, room.members);
+        assert_eq!(
+            event,
+            RoomEvent::MembersAdded {
+                members: vec![bob_user.uuid, carol_user.uuid],
+                by: alice_user.uuid,
+            }
+        );
+        room_push(&mut carol).await;
+
+        // Any member may rename, only owners may kick.
+        let room = room_request(&mut bob, rename).await;
+        assert_eq!(room.name, "random");
+        let (pushed, event) = room_push(&mut alice).await;
+        assert_eq!(pushed.name, "random");
+        assert_eq!(event, RoomEvent::Renamed { by: bob_user.uuid });
+        room_push(&mut carol).await;
+
+        let kick = |member| Request::KickMember {
+            room: room.uuid,
+            member,
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, kick(carol_user.uuid)).await),
+            ErrorCode::Forbidden
+        );
+        assert_eq!(
+            error_code(request(&mut alice, 1, kick(alice_user.uuid)).await),
+            ErrorCode::Forbidden
+        );
+        let room = room_request(&mut alice, kick(carol_user.uuid)).await;
+        assert!(!room.members.contains(&carol_user.uuid));
+        for client in [&mut bob, &mut carol] {
+            let (_, event) = room_push(client).await;
+            assert_eq!(
+                event,
+                RoomEvent::MemberKicked {
+                    member: carol_user.uuid,
+                    by: alice_user.uuid,
+                }
+            );
+        }
+
+        // The last owner can not abandon other members.
+        let leave = Request::LeaveRoom { room: room.uuid };
+        assert_eq!(
+            error_code(request(&mut alice, 1, leave.clone()).await),
+            ErrorCode::Forbidden
+        );
+        room_request(&mut bob, leave).await;
+        room_push(&mut alice).await;
+
+        let list = Request::ListRooms {
+            limit: 10,
+            after: None,
+        };
+        match request(&mut alice, 1, list.clone()).await {
+            Response::Rooms { rooms, next } => {
+                assert_eq!(rooms.len(), 1);
+                assert_eq!(rooms[0].room.uuid, room.uuid);
+                assert!(next.is_none());
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+        assert!(matches!(
+            request(&mut bob, 1, list).await,
+            Response::Rooms { rooms, .. } if rooms.is_empty()
+        ));
+
+        let delete = Request::DeleteRoom { room: room.uuid };
+        let deleted = room_request(&mut alice, delete.clone()).await;
+        assert_eq!(deleted.members.len(), 1);
+        assert_eq!(
+            error_code(request(&mut alice, 1, delete).await),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let addr = start_server().await;
+        let (mut alice, _) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let (mut carol, _) = register(addr, "carol", 3).await;
+
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        room_request(
+            &mut alice,
+            Request::AddMembers {
+                room: room.uuid,
+                members: vec![bob_user.uuid],
+            },
+        )
+        .await;
+        room_push(&mut bob).await;
+
+        // A session opened later subscribes to the rooms of its user.
+        let (mut bob2, _) = connect(addr, hello()).await;
+        let signature = challenge(&mut bob2)
+            .await
+            .sign(&SigningKey::from_bytes(&[2; 32]));
+        let login = Request::Login {
+            user: bob_user.uuid,
+            device: Uuid::new_v4(),
+            signature,
+        };
+        assert!(matches!(
+            request(&mut bob2, 1, login).await,
+            Response::LoggedIn { .. }
+        ));
+
+        let send = Request::SendMessage {
+            envelope: envelope(room.uuid, device(1), "hi"),
+            reply_to: None,
+            thread_root: None,
+        };
+        let sent = match request(&mut alice, 1, send.clone()).await {
+            Response::MessageSent { message } => message,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        for client in [&mut bob, &mut bob2] {
+            match client.next().await.unwrap().unwrap() {
+                Frame::Push(Push::Message {
+                    room: in_room,
+                    message,
+                }) => {
+                    assert_eq!(in_room, room.uuid);
+                    assert_eq!(message.uuid, sent);
+                    assert_eq!(text(&message), "hi");
+                }
+                other => panic!("unexpected frame: {other:?}"),
+            }
+        }
+
+        // Envelopes come from the device of the connection.
+        assert_eq!(
+            error_code(request(&mut bob, 1, send).await),
+            ErrorCode::BadRequest
+        );
+        let empty = Request::SendMessage {
+            envelope: envelope(room.uuid, device(2), ""),
+            reply_to: None,
+            thread_root: None,
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, empty).await),
+            ErrorCode::BadRequest
+        );
+        let mut headless = envelope(room.uuid, device(2), "hi");
+        headless.header.clear();
+        let mut oversized = envelope(room.uuid, device(2), "hi");
+        oversized.header = vec![0; 257];
+        let mut postdated = envelope(room.uuid, device(2), "hi");
+        postdated.timestamp += std::time::Duration::from_secs(60 * 60);
+        for envelope in [headless, oversized, postdated] {
+            let send = Request::SendMessage {
+                envelope,
+                reply_to: None,
+                thread_root: None,
+            };
+            assert_eq!(
+                error_code(request(&mut bob, 1, send).await),
+                ErrorCode::BadRequest
+            );
+        }
+        let send = Request::SendMessage {
+            envelope: envelope(room.uuid, device(3), "hi"),
+            reply_to: None,
+            thread_root: None,
+        };
+        assert_eq!(
+            error_code(request(&mut carol, 1, send).await),
+            ErrorCode::NotFound
+        );
+
+        let history = |query| Request::FetchHistory {
+            room: room.uuid,
+            limit: 10,
+            query,
+        };
+        let newer = match request(&mut bob, 1, history(HistoryQuery::Latest)).await {
+            Response::History {
+                messages,
+                older,
+                newer,
+                ..
+            } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].uuid, sent);
+                assert!(older.is_none());
+                newer.unwrap()
+            }
+            other => panic!("unexpected response: {other:?}"),
+        };
+
+        // Catching up from the newest known message.
+        let send = Request::SendMessage {
+            envelope: envelope(room.uuid, device(1), "again"),
+            reply_to: None,
+            thread_root: None,
+        };
+        let again = match request(&mut alice, 1, send).await {
+            Response::MessageSent { message } => message,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        assert!(matches!(
+            bob.next().await.unwrap().unwrap(),
+            Frame::Push(Push::Message { .. })
+        ));
+        match request(&mut bob, 1, history(HistoryQuery::After(newer))).await {
+            Response::History { messages, .. } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].uuid, again);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+        match request(&mut bob, 1, history(HistoryQuery::Around(sent))).await {
+            Response::History { messages, .. } => {
+                let uuids: Vec<Uuid> = messages.iter().map(|m| m.uuid).collect();
+                assert_eq!(uuids, [again, sent]);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        assert_eq!(
+            error_code(request(&mut carol, 1, history(HistoryQuery::Latest)).await),
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            error_code(request(&mut bob, 1, history(HistoryQuery::Around(Uuid::new_v4()))).await),
+            ErrorCode::NotFound
+        );
+        let garbage = Cursor::from_bytes(vec![1, 2, 3]);
+        assert_eq!(
+            error_code(request(&mut bob, 1, history(HistoryQuery::Before(garbage))).await),
+            ErrorCode::BadRequest
+        );
+    }
+
+    /// Connect and log in as `user` from `device`, with the key derived from
+    /// `seed`.
+    async fn login(addr: SocketAddr, user: &User, seed: u8, device: Uuid) -> Client {
+        let (mut client, _) = connect(addr, hello()).await;
+        let signature = challenge(&mut client)
+            .await
+            .sign(&SigningKey::from_bytes(&[seed; 32]));
+        let login = Request::Login {
+            user: user.uuid,
+            device,
+            signature,
+        };
+        match request(&mut client, 1, login).await {
+            Response::LoggedIn { .. } => client,
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    /// Sync and collect the uuids of the messages received before catching up.
+    async fn sync(client: &mut Client) -> Vec<Uuid> {
+        sync_changes(client).await.0
+    }
+
+    /// Sync and collect the uuids of the messages, of the edited messages,
+    /// then of the deleted messages, received before catching up.
+    async fn sync_changes(client: &mut Client) -> (Vec<Uuid>, Vec<Uuid>, Vec<Uuid>) {
+        client
+            .send(Frame::Request {
+                id: 1,
+                request: Request::Sync,
+            })
+            .await
+            .unwrap();
+        let (mut messages, mut edited, mut deleted) = (Vec::new(), Vec::new(), Vec::new());
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                Frame::Push(Push::Message { message, .. }) => messages.push(message.uuid),
+                Frame::Push(Push::MessageEdited { message, .. }) => edited.push(message.uuid),
+                Frame::Push(Push::MessageDeleted { message, .. }) => deleted.push(message),
+                Frame::Response {
+                    response: Response::CaughtUp,
+                    ..
+                } => return (messages, edited, deleted),
+                other => panic!("unexpected frame: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let addr = start_server().await;
+        let (mut alice, alice_user) = register(addr, "alice", 1).await;
+        let (_, bob_user) = register(addr, "bob", 2).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        let add = Request::AddMembers {
+            room: room.uuid,
+            members: vec![bob_user.uuid],
+        };
+        room_request(&mut alice, add).await;
+
+        // Messages sent before a device first logged in are left to history.
+        let send = |text: &str| Request::SendMessage {
+            envelope: envelope(room.uuid, device(1), text),
+            reply_to: None,
+            thread_root: None,
+        };
+        let edit = |message| Request::EditMessage {
+            message,
+            envelope: envelope(room.uuid, device(1), "edited"),
+        };
+        request(&mut alice, 1, send("before")).await;
+        let laptop = Uuid::new_v4();
+        let mut bob = login(addr, &bob_user, 2, laptop).await;
+        assert!(sync(&mut bob).await.is_empty());
+        drop(bob);
+
+        let mut sent = Vec::new();
+        for text in ["one", "two", "three"] {
+            match request(&mut alice, 1, send(text)).await {
+                Response::MessageSent { message } => sent.push(message),
+                other => panic!("unexpected response: {other:?}"),
+            }
+            // Messages of the same millisecond are ordered by uuid.
+            time::sleep(Duration::from_millis(2)).await;
+        }
+
+        let mut bob = login(addr, &bob_user, 2, laptop).await;
+        assert_eq!(sync(&mut bob).await, sent);
+        let ack = |message| Request::AckDelivery {
+            room: room.uuid,
+            message,
+        };
+        assert!(matches!(
+            request(&mut bob, 1, ack(sent[1])).await,
+            Response::Acknowledged
+        ));
+        assert_eq!(sync(&mut bob).await, sent[2..]);
+
+        // Acknowledging an older message does not rewind the device.
+        assert!(matches!(
+            request(&mut bob, 1, ack(sent[0])).await,
+            Response::Acknowledged
+        ));
+        let mut bob = login(addr, &bob_user, 2, laptop).await;
+        assert_eq!(sync(&mut bob).await, sent[2..]);
+
+        // Other devices keep their own position.
+        let mut bob_phone = login(addr, &bob_user, 2, Uuid::new_v4()).await;
+        assert!(sync(&mut bob_phone).await.is_empty());
+
+        // Edits made since the last sync of acknowledged messages come along,
+        // once.
+        let mut alice = login(addr, &alice_user, 1, device(1)).await;
+        for message in [sent[0], sent[2]] {
+            assert!(matches!(
+                request(&mut alice, 1, edit(message)).await,
+                Response::Acknowledged
+            ));
+        }
+        let mut bob = login(addr, &bob_user, 2, laptop).await;
+        assert_eq!(
+            sync_changes(&mut bob).await,
+            (sent[2..].to_vec(), vec![sent[0]], Vec::new())
+        );
+        assert_eq!(
+            sync_changes(&mut bob).await,
+            (sent[2..].to_vec(), Vec::new(), Vec::new())
+        );
+
+        // So do deletions, which replace the edit of a message.
+        drop(bob);
+        for message in [sent[0], sent[1]] {
+            let delete = Request::DeleteMessage {
+                room: room.uuid,
+                message,
+            };
+            assert!(matches!(
+                request(&mut alice, 1, delete).await,
+                Response::Acknowledged
+            ));
+            time::sleep(Duration::from_millis(2)).await;
+        }
+        let mut bob = login(addr, &bob_user, 2, laptop).await;
+        assert_eq!(
+            sync_changes(&mut bob).await,
+            (sent[2..].to_vec(), Vec::new(), vec![sent[0], sent[1]])
+        );
+        assert_eq!(
+            sync_changes(&mut bob).await,
+            (sent[2..].to_vec(), Vec::new(), Vec::new())
+        );
+
+        assert_eq!(
+            error_code(request(&mut bob, 1, ack(Uuid::new_v4())).await),
+            ErrorCode::NotFound
+        );
+    }
+
+    /// Unread count of the first room of the user.
+    async fn unread(client: &mut Client) -> u32 {
+        let list = Request::ListRooms {
+            limit: 10,
+            after: None,
+        };
+        match request(client, 1, list).await {
+            Response::Rooms { rooms, .. } => rooms[0].unread,
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receipts() {
+        let addr = start_server().await;
+        let (mut alice, alice_user) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        let add = Request::AddMembers {
+            room: room.uuid,
+            members: vec![bob_user.uuid],
+        };
+        room_request(&mut alice, add).await;
+        room_push(&mut bob).await;
+
+        let mut sent = Vec::new();
+        for text in ["one", "two", "three"] {
+            let send = Request::SendMessage {
+                envelope: envelope(room.uuid, device(1), text),
+                reply_to: None,
+                thread_root: None,
+            };
+            match request(&mut alice, 1, send).await {
+                Response::MessageSent { message } => sent.push(message),
+                other => panic!("unexpected response: {other:?}"),
+            }
+            bob.next().await.unwrap().unwrap();
+            time::sleep(Duration::from_millis(2)).await;
+        }
+
+        assert_eq!(unread(&mut alice).await, 0);
+        assert_eq!(unread(&mut bob).await, 3);
+
+        let read = Request::MarkRead {
+            room: room.uuid,
+            message: sent[1],
+        };
+        assert!(matches!(
+            request(&mut bob, 1, read.clone()).await,
+            Response::Acknowledged
+        ));
+        assert_eq!(unread(&mut bob).await, 1);
+        match alice.next().await.unwrap().unwrap() {
+            Frame::Push(Push::Receipt {
+                user,
+                message,
+                receipt,
+                ..
+            }) => {
+                assert_eq!(user, bob_user.uuid);
+                assert_eq!(message, sent[1]);
+                assert_eq!(receipt, Receipt::Read);
+            }
+            other => panic!("unexpected frame: {other:?}"),
+        }
+
+        let ack = Request::AckDelivery {
+            room: room.uuid,
+            message: sent[2],
+        };
+        request(&mut bob, 1, ack).await;
+        assert!(matches!(
+            alice.next().await.unwrap().unwrap(),
+            Frame::Push(Push::Receipt {
+                receipt: Receipt::Delivered,
+                ..
+            })
+        ));
+
+        // Reading again does not notify anyone, so the next frame Alice gets
+        // is her response.
+        request(&mut bob, 1, read).await;
+        match request(&mut alice, 1, Request::FetchReceipts { room: room.uuid }).await {
+            Response::Receipts { read } => {
+                assert_eq!(read[&alice_user.uuid], sent[2]);
+                assert_eq!(read[&bob_user.uuid], sent[1]);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        // Deleted messages are not unread.
+        let delete = Request::DeleteMessage {
+            room: room.uuid,
+            message: sent[2],
+        };
+        request(&mut alice, 1, delete).await;
+        assert!(matches!(
+            bob.next().await.unwrap().unwrap(),
+            Frame::Push(Push::MessageDeleted { .. })
+        ));
+        assert_eq!(unread(&mut bob).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_edit_message() {
+        let addr = start_server().await;
+        let (mut alice, _) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        let add = Request::AddMembers {
+            room: room.uuid,
+            members: vec![bob_user.uuid],
+        };
+        room_request(&mut alice, add).await;
+        room_push(&mut bob).await;
+
+        let send = Request::SendMessage {
+            envelope: envelope(room.uuid, device(1), "helo"),
+            reply_to: None,
+            thread_root: None,
+        };
+        let message = match request(&mut alice, 1, send).await {
+            Response::MessageSent { message } => message,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        bob.next().await.unwrap().unwrap();
+
+        let edit = |device, text: &str| Request::EditMessage {
+            message,
+            envelope: envelope(room.uuid, device, text),
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, edit(device(2), "hijacked")).await),
+            ErrorCode::Forbidden
+        );
+        assert!(matches!(
+            request(&mut alice, 1, edit(device(1), "hello")).await,
+            Response::Acknowledged
+        ));
+        match bob.next().await.unwrap().unwrap() {
+            Frame::Push(Push::MessageEdited {
+                message: edited, ..
+            }) => {
+                assert_eq!(edited.uuid, message);
+                assert!(edited.edited.is_some());
+            }
+            other => panic!("unexpected frame: {other:?}"),
+        }
+
+        let latest = Request::FetchHistory {
+            room: room.uuid,
+            limit: 10,
+            query: HistoryQuery::Latest,
+        };
+        match request(&mut bob, 1, latest).await {
+            Response::History { messages, .. } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(text(&messages[0]), "hello");
+                assert!(messages[0].edited.is_some());
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+        let revisions = Request::FetchRevisions {
+            room: room.uuid,
+            message,
+        };
+        match request(&mut bob, 1, revisions).await {
+            Response::Revisions { revisions } => {
+                assert_eq!(revisions.len(), 1);
+                assert_eq!(text(&revisions[0]), "helo");
+                assert!(revisions[0].edited.is_none());
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_message() {
+        let addr = start_server().await;
+        let (mut alice, _) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        let add = Request::AddMembers {
+            room: room.uuid,
+            members: vec![bob_user.uuid],
+        };
+        room_request(&mut alice, add).await;
+        room_push(&mut bob).await;
+
+        let send = |device, text: &str| Request::SendMessage {
+            envelope: envelope(room.uuid, device, text),
+            reply_to: None,
+            thread_root: None,
+        };
+        let from_alice = match request(&mut alice, 1, send(device(1), "mine")).await {
+            Response::MessageSent { message } => message,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        bob.next().await.unwrap().unwrap();
+        // Messages of the same millisecond are ordered by uuid.
+        time::sleep(Duration::from_millis(2)).await;
+        let from_bob = match request(&mut bob, 1, send(device(2), "oops")).await {
+            Response::MessageSent { message } => message,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        alice.next().await.unwrap().unwrap();
+
+        let delete = |message| Request::DeleteMessage {
+            room: room.uuid,
+            message,
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, delete(from_alice)).await),
+            ErrorCode::Forbidden
+        );
+        // Room owners may delete the messages of others.
+        assert!(matches!(
+            request(&mut alice, 1, delete(from_bob)).await,
+            Response::Acknowledged
+        ));
+        match bob.next().await.unwrap().unwrap() {
+            Frame::Push(Push::MessageDeleted { message, by, .. }) => {
+                assert_eq!(message, from_bob);
+                assert_ne!(by, bob_user.uuid);
+            }
+            other => panic!("unexpected frame: {other:?}"),
+        }
+
+        let latest = Request::FetchHistory {
+            room: room.uuid,
+            limit: 10,
+            query: HistoryQuery::Latest,
+        };
+        match request(&mut bob, 1, latest).await {
+            Response::History { messages, .. } => {
+                let deleted: Vec<_> = messages.iter().map(Message::is_deleted).collect();
+                assert_eq!(deleted, [true, false]);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+        let edit = Request::EditMessage {
+            message: from_bob,
+            envelope: envelope(room.uuid, device(2), "undo"),
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, edit).await),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_threads() {
+        let addr = start_server().await;
+        let (mut alice, _) = register(addr, "alice", 1).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        let send = |text: &str, reply_to, thread_root| Request::SendMessage {
+            envelope: envelope(room.uuid, device(1), text),
+            reply_to,
+            thread_root,
+        };
+        let sent = |response| match response {
+            Response::MessageSent { message } => message,
+            other => panic!("unexpected response: {other:?}"),
+        };
+
+        let root = sent(request(&mut alice, 1, send("root", None, None)).await);
+        let quote = sent(request(&mut alice, 1, send("quote", Some(root), None)).await);
+        let mut thread = Vec::new();
+        for text in ["one", "two", "three"] {
+            // Messages of the same millisecond are ordered by uuid.
+            time::sleep(Duration::from_millis(2)).await;
+            let reply = send(text, thread.last().copied(), Some(root));
+            thread.push(sent(request(&mut alice, 1, reply).await));
+        }
+
+        let nested = send("nested", None, Some(thread[0]));
+        assert_eq!(
+            error_code(request(&mut alice, 1, nested).await),
+            ErrorCode::BadRequest
+        );
+        let outside = send("outside", Some(thread[0]), None);
+        assert_eq!(
+            error_code(request(&mut alice, 1, outside).await),
+            ErrorCode::BadRequest
+        );
+        let inside = send("inside", Some(quote), Some(root));
+        assert_eq!(
+            error_code(request(&mut alice, 1, inside).await),
+            ErrorCode::BadRequest
+        );
+
+        let fetch = |after| Request::FetchThread {
+            room: room.uuid,
+            root,
+            limit: 2,
+            after,
+        };
+        let (messages, next) = match request(&mut alice, 1, fetch(None)).await {
+            Response::Thread {
+                root: thread_root,
+                messages,
+                next,
+                ..
+            } => {
+                assert_eq!(thread_root.uuid, root);
+                (messages, next)
+            }
+            other => panic!("unexpected response: {other:?}"),
+        };
+        assert_eq!(
+            messages.iter().map(|m| m.uuid).collect::<Vec<_>>(),
+            thread[..2]
+        );
+        assert_eq!(messages[1].reply_to, Some(thread[0]));
+        match request(&mut alice, 1, fetch(next)).await {
+            Response::Thread { messages, next, .. } => {
+                assert_eq!(
+                    messages.iter().map(|m| m.uuid).collect::<Vec<_>>(),
+                    thread[2..]
+                );
+                assert!(next.is_some());
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reactions() {
+        let addr = start_server().await;
+        let (mut alice, _) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        let add = Request::AddMembers {
+            room: room.uuid,
+            members: vec![bob_user.uuid],
+        };
+        room_request(&mut alice, add).await;
+        room_push(&mut bob).await;
+        let send = Request::SendMessage {
+            envelope: envelope(room.uuid, device(1), "hello"),
+            reply_to: None,
+            thread_root: None,
+        };
+        let message = match request(&mut alice, 1, send).await {
+            Response::MessageSent { message } => message,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        bob.next().await.unwrap().unwrap();
+
+        let add = |emoji: &str| Request::AddReaction {
+            room: room.uuid,
+            message,
+            emoji: emoji.to_string(),
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, add("not one")).await),
+            ErrorCode::BadRequest
+        );
+        request(&mut bob, 1, add("👍")).await;
+        match alice.next().await.unwrap().unwrap() {
+            Frame::Push(Push::Reaction {
+                user, emoji, added, ..
+            }) => {
+                assert_eq!(user, bob_user.uuid);
+                assert_eq!(emoji, "👍");
+                assert!(added);
+            }
+            other => panic!("unexpected frame: {other:?}"),
+        }
+        request(&mut alice, 1, add("👍")).await;
+        bob.next().await.unwrap().unwrap();
+        request(&mut alice, 1, add("🎉")).await;
+        bob.next().await.unwrap().unwrap();
+
+        let latest = Request::FetchHistory {
+            room: room.uuid,
+            limit: 10,
+            query: HistoryQuery::Latest,
+        };
+        match request(&mut bob, 1, latest).await {
+            Response::History { reactions, .. } => {
+                let count = |emoji: &str, count, mine| ReactionCount {
+                    emoji: emoji.to_string(),
+                    count,
+                    mine,
+                };
+                assert_eq!(
+                    reactions[&message],
+                    [count("🎉", 1, false), count("👍", 2, true)]
+                );
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        let remove = Request::RemoveReaction {
+            room: room.uuid,
+            message,
+            emoji: "👍".to_string(),
+        };
+        request(&mut bob, 1, remove.clone()).await;
+        assert!(matches!(
+            alice.next().await.unwrap().unwrap(),
+            Frame::Push(Push::Reaction { added: false, .. })
+        ));
+        // Withdrawing it again changes nothing, so nobody is notified.
+        request(&mut bob, 1, remove).await;
+        assert!(matches!(
+            request(&mut alice, 1, Request::Ping).await,
+            Response::Pong
+        ));
+    }
+
+    async fn typing_push(client: &mut Client) -> (Uuid, bool) {
+        match client.next().await.unwrap().unwrap() {
+            Frame::Push(Push::Typing { user, typing, .. }) => (user, typing),
+            other => panic!("unexpected frame: {other:?}"),
+        }
+    }
+
+    async fn presence_push(client: &mut Client) -> (Uuid, Presence) {
+        match client.next().await.unwrap().unwrap() {
+            Frame::Push(Push::Presence { user, presence }) => (user, presence),
+            other => panic!("unexpected frame: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_typing_and_presence() {
+        let addr = start_server().await;
+        let (mut alice, _) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        let typing = |typing| Request::SetTyping {
+            room: room.uuid,
+            typing,
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, typing(true)).await),
+            ErrorCode::NotFound
+        );
+
+        let add = Request::AddMembers {
+            room: room.uuid,
+            members: vec![bob_user.uuid],
+        };
+        room_request(&mut alice, add).await;
+        room_push(&mut bob).await;
+
+        let stranger = Uuid::new_v4();
+        let fetch = Request::FetchPresence {
+            users: vec![bob_user.uuid, stranger],
+        };
+        match request(&mut alice, 1, fetch).await {
+            Response::Presence { presence } => {
+                assert_eq!(presence[&bob_user.uuid], Presence::Online);
+                assert_eq!(presence[&stranger], Presence::Offline);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        // Repeating the typing only postpones its expiry.
+        request(&mut bob, 1, typing(true)).await;
+        request(&mut bob, 1, typing(true)).await;
+        assert_eq!(typing_push(&mut alice).await, (bob_user.uuid, true));
+        assert_eq!(typing_push(&mut alice).await, (bob_user.uuid, false));
+
+        request(&mut bob, 1, typing(true)).await;
+        assert_eq!(typing_push(&mut alice).await, (bob_user.uuid, true));
+        let send = Request::SendMessage {
+            envelope: envelope(room.uuid, device(2), "hi"),
+            reply_to: None,
+            thread_root: None,
+        };
+        request(&mut bob, 1, send).await;
+        assert!(matches!(
+            alice.next().await.unwrap().unwrap(),
+            Frame::Push(Push::Message { .. })
+        ));
+        assert_eq!(typing_push(&mut alice).await, (bob_user.uuid, false));
+
+        let away = Request::SetPresence {
+            presence: Presence::Away,
+        };
+        request(&mut bob, 1, away).await;
+        assert_eq!(
+            presence_push(&mut alice).await,
+            (bob_user.uuid, Presence::Away)
+        );
+
+        // A second device of Bob brings him back online.
+        let mut bob2 = login(addr, &bob_user, 2, Uuid::new_v4()).await;
+        assert_eq!(
+            presence_push(&mut alice).await,
+            (bob_user.uuid, Presence::Online)
+        );
+        request(&mut bob, 1, typing(true)).await;
+        assert_eq!(typing_push(&mut alice).await, (bob_user.uuid, true));
+        assert_eq!(typing_push(&mut bob2).await, (bob_user.uuid, true));
+
+        // Disconnecting stops the typing, and Bob is online until his last
+        // device goes.
+        drop(bob);
+        assert_eq!(typing_push(&mut alice).await, (bob_user.uuid, false));
+        drop(bob2);
+        assert_eq!(
+            presence_push(&mut alice).await,
+            (bob_user.uuid, Presence::Offline)
+        );
+
+        let offline = Request::SetPresence {
+            presence: Presence::Offline,
+        };
+        assert_eq!(
+            error_code(request(&mut alice, 1, offline).await),
+            ErrorCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_db_error_codes() {
+        let code = |err: DbError| match Response::from(err) {
+            Response::Error { code, .. } => code,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        assert_eq!(
+            code(DbError::Invalid("time".to_string())),
+            ErrorCode::BadRequest
+        );
+        assert_eq!(
+            code(DbError::AddressTaken("alice".to_string())),
+            ErrorCode::AddressTaken
+        );
+        assert_eq!(
+            code(DbError::Corrupt("bad".to_string())),
+            ErrorCode::Internal
+        );
+        assert_eq!(
+            code(DbError::SchemaTooNew {
+                found: 2,
+                supported: 1
+            }),
+            ErrorCode::Internal
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prekeys() {
+        let addr = start_server().await;
+        let (mut alice, alice_user) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let alice_key = SigningKey::from_bytes(&[1; 32]);
+
+        let fetch = Request::FetchPrekeyBundle {
+            user: alice_user.uuid,
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, fetch.clone()).await),
+            ErrorCode::NotFound
+        );
+
+        // Only prekeys signed by the identity of the user are accepted.
+        let forged = Request::UploadPrekeys {
+            signed_prekey: Some(SignedPrekey::new(
+                1,
+                [9; 32],
+                &SigningKey::from_bytes(&[2; 32]),
+            )),
+            one_time_prekeys: Vec::new(),
+        };
+        assert_eq!(
+            error_code(request(&mut alice, 1, forged).await),
+            ErrorCode::BadRequest
+        );
+
+        let signed_prekey = SignedPrekey::new(1, [9; 32], &alice_key);
+        let upload = Request::UploadPrekeys {
+            signed_prekey: Some(signed_prekey.clone()),
+            one_time_prekeys: (1..=2)
+                .map(|id| OneTimePrekey {
+                    id,
+                    key: [id as u8; 32],
+                })
+                .collect(),
+        };
+        match request(&mut alice, 1, upload).await {
+            Response::PrekeysUploaded { one_time_prekeys } => assert_eq!(one_time_prekeys, 2),
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        // Each one-time prekey is handed out once, then bundles go without.
+        for expected in [Some(1), Some(2), None] {
+            match request(&mut bob, 1, fetch.clone()).await {
+                Response::PrekeyBundles { bundles } => {
+                    let [bundle] = &bundles[..] else {
+                        panic!("unexpected bundles: {bundles:?}");
+                    };
+                    assert_eq!(bundle.verify(), Ok(()));
+                    assert_eq!(bundle.device, device(1));
+                    assert_eq!(bundle.identity_key, alice_user.identity_key);
+                    assert_eq!(bundle.signed_prekey, signed_prekey);
+                    assert_eq!(
+                        bundle.one_time_prekey.as_ref().map(|prekey| prekey.id),
+                        expected
+                    );
+                }
+                other => panic!("unexpected response: {other:?}"),
+            }
+        }
+
+        let count = Request::UploadPrekeys {
+            signed_prekey: None,
+            one_time_prekeys: Vec::new(),
+        };
+        match request(&mut alice, 1, count).await {
+            Response::PrekeysUploaded { one_time_prekeys } => assert_eq!(one_time_prekeys, 0),
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        // Each device publishes prekeys of its own.
+        let mut alice_phone = login(addr, &alice_user, 1, device(3)).await;
+        let phone_prekey = SignedPrekey::new(1, [8; 32], &alice_key);
+        let upload = Request::UploadPrekeys {
+            signed_prekey: Some(phone_prekey.clone()),
+            one_time_prekeys: vec![OneTimePrekey {
+                id: 1,
+                key: [8; 32],
+            }],
+        };
+        match request(&mut alice_phone, 1, upload).await {
+            Response::PrekeysUploaded { one_time_prekeys } => assert_eq!(one_time_prekeys, 1),
+            other => panic!("unexpected response: {other:?}"),
+        }
+        match request(&mut bob, 1, fetch.clone()).await {
+            Response::PrekeyBundles { mut bundles } => {
+                bundles.sort_by_key(|bundle| bundle.device);
+                let prekeys: Vec<_> = bundles
+                    .into_iter()
+                    .map(|bundle| (bundle.device, bundle.signed_prekey, bundle.one_time_prekey))
+                    .collect();
+                let phone_one_time = OneTimePrekey {
+                    id: 1,
+                    key: [8; 32],
+                };
+                assert_eq!(
+                    prekeys,
+                    [
+                        (device(1), signed_prekey, None),
+                        (device(3), phone_prekey, Some(phone_one_time)),
+                    ]
+                );
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        // Fetching the bundles of a user is limited, not those of others.
+        let mut limited = false;
+        for _ in 0..10 {
+            match request(&mut bob, 1, fetch.clone()).await {
+                Response::PrekeyBundles { .. } => continue,
+                response => {
+                    assert_eq!(error_code(response), ErrorCode::RateLimited);
+                    limited = true;
+                    break;
+                }
+            }
+        }
+        assert!(limited);
+        let fetch_other = Request::FetchPrekeyBundle {
+            user: bob_user.uuid,
+        };
+        assert_eq!(
+            error_code(request(&mut bob, 1, fetch_other).await),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_direct_messages() {
+        let addr = start_server().await;
+        let (mut alice, _) = register(addr, "alice", 1).await;
+        let (mut bob, bob_user) = register(addr, "bob", 2).await;
+        let (mut carol, _) = register(addr, "carol", 3).await;
+        let room = room_request(
+            &mut alice,
+            Request::CreateRoom {
+                name: "general".to_string(),
+            },
+        )
+        .await;
+        room_request(
+            &mut alice,
+            Request::AddMembers {
+                room: room.uuid,
+                members: vec![bob_user.uuid],
+            },
+        )
+        .await;
+        room_push(&mut bob).await;
+
+        let send = |payload: &[u8]| Request::SendDirect {
+            room: room.uuid,
+            user: bob_user.uuid,
+            device: device(2),
+            payload: payload.to_vec(),
+        };
+        // Only devices that published prekeys receive direct messages.
+        assert_eq!(
+            error_code(request(&mut alice, 1, send(b"hi")).await),
+            ErrorCode::NotFound
+        );
+        let upload = Request::UploadPrekeys {
+            signed_prekey: Some(SignedPrekey::new(
+                1,
+                [9; 32],
+                &SigningKey::from_bytes(&[2; 32]),
+            )),
+            one_time_prekeys: Vec::new(),
+        };
+        request(&mut bob, 1, upload).await;
+
+        assert!(matches!(
+            request(&mut alice, 1, send(b"hi")).await,
+            Response::Acknowledged
+        ));
+        let pushed = match bob.next().await.unwrap().unwrap() {
+            Frame::Push(Push::Direct { message }) => message,
+            other => panic!("unexpected frame: {other:?}"),
+        };
+        assert_eq!(pushed.room, room.uuid);
+        assert_eq!(pushed.sender_device, device(1));
+        assert_eq!(pushed.recipient_device, device(2));
+        assert_eq!(pushed.payload, b"hi");
+
+        // Both users must be members of the room, and payloads not empty.
+        assert_eq!(
+            error_code(request(&mut carol, 1, send(b"hi")).await),
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            error_code(request(&mut alice, 1, send(b"")).await),
+            ErrorCode::BadRequest
+        );
+
+        // Messages wait for their device until it acknowledges them.
+        let fetch = Request::FetchDirect { limit: 10 };
+        match request(&mut bob, 1, fetch.clone()).await {
+            Response::DirectMessages { messages } => {
+                assert_eq!(messages, std::slice::from_ref(&pushed))
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+        let mut laptop = login(addr, &bob_user, 2, Uuid::new_v4()).await;
+        match request(&mut laptop, 1, fetch.clone()).await {
+            Response::DirectMessages { messages } => assert!(messages.is_empty()),
+            other => panic!("unexpected response: {other:?}"),
+        }
+        let ack = Request::AckDirect {
+            messages: vec![pushed.uuid],
+        };
+        assert!(matches!(
+            request(&mut bob, 1, ack).await,
+            Response::Acknowledged
+        ));
+        match request(&mut bob, 1, fetch).await {
+            Response::DirectMessages { messages } => assert!(messages.is_empty()),
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_users() {
+        let addr = start_server().await;
+        let (mut alice, alice_user) = register(addr, "alice", 1).await;
+        let (_, bob_user) = register(addr, "bob", 2).await;
+
+        let fetch = Request::FetchUsers {
+            users: vec![bob_user.uuid, Uuid::new_v4(), alice_user.uuid],
+        };
+        match request(&mut alice, 1, fetch).await {
+            Response::Users { users } => assert_eq!(users, vec![bob_user, alice_user]),
+            other => panic!("unexpected response: {other:?}"),
+        }
+        let fetch = Request::FetchUsers {
+            users: vec![Uuid::new_v4(); MAX_FETCHED_USERS + 1],
+        };
+        assert_eq!(
+            error_code(request(&mut alice, 2, fetch).await),
+            ErrorCode::BadRequest
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Delivery of the messages, edits and deletions a device missed while it was
+//! offline.
+
+use super::{Handler, Rejection, Reply, Result};
+use crate::db::{Deletion, EditCursor, HistoryDirection, MessageCursor};
+use shared::protocol::{Frame, Push, Receipt, Response};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Number of messages read from the database at once while syncing.
+const SYNC_BATCH: usize = 100;
+
+/// Progress of a `Sync` request.
+struct SyncState {
+    user: Uuid,
+    device: Uuid,
+    /// When this sync started.
+    started: SystemTime,
+    /// Where rooms the device never acknowledged a message of start.
+    start: MessageCursor,
+    /// Edits and deletions made after it may have missed the device: those
+    /// since its last sync that caught up, or since it was registered.
+    edits_since: EditCursor,
+    /// Rooms not synced yet.
+    rooms: Vec<Uuid>,
+    /// Room being synced and how far.
+    room: Option<(Uuid, RoomSync)>,
+}
+
+/// Progress of the sync of a room.
+enum RoomSync {
+    /// Edits of the messages up to `acknowledged` are synced up to `after`.
+    Edits {
+        acknowledged: MessageCursor,
+        after: EditCursor,
+    },
+    /// Deletions of the messages up to `acknowledged` are synced up to
+    /// `after`.
+    Deletions {
+        acknowledged: MessageCursor,
+        after: EditCursor,
+    },
+    /// Messages are synced up to this one.
+    Messages(MessageCursor),
+}
+
+impl Handler {
+    /// Write every message the device has not acknowledged, and the edits it
+    /// may have missed of those it did, then return the response to the
+    /// `Sync` request.
+    ///
+    /// Fails only if the connection does.
+    pub(super) async fn sync(&mut self) -> Result<Response> {
+        let mut sync = match self.start_sync() {
+            Ok(sync) => sync,
+            Err(response) => return Ok(*response),
+        };
+        loop {
+            match self.next_undelivered(&mut sync) {
+                Ok(Some(pushes)) => {
+                    for push in pushes {
+                        self.connection.write_frame(Frame::Push(push)).await?;
+                    }
+                }
+                Ok(None) => return Ok(Response::CaughtUp),
+                Err(response) => return Ok(*response),
+            }
+        }
+    }
+
+    pub(super) fn ack_delivery(&mut self, room_uuid: &Uuid, message_uuid: &Uuid) -> Reply {
+        let (user, device) = self.device()?;
+        let room = self.member_room(user, room_uuid)?;
+        let message = self.room_message(&room.uuid, message_uuid)?;
+
+        // Acknowledgements arriving out of order never move the cursor back.
+        let cursor = MessageCursor::of(&message)?;
+        if self
+            .db
+            .advance_delivery_cursor(&room.uuid, &user.uuid, &device, &cursor)?
+        {
+            self.push_receipt(&room.uuid, &user.uuid, &message.uuid, Receipt::Delivered);
+        }
+        Ok(Response::Acknowledged)
+    }
+
+    fn start_sync(&self) -> std::result::Result<SyncState, Rejection> {
+        let (user, device) = self.device()?;
+        let started = SystemTime::now();
+        let registered = self.db.register_device(&user.uuid, &device)?;
+        let synced = self.db.find_device_synced(&user.uuid, &device)?;
+
+        let mut rooms = Vec::new();
+        let mut after = None;
+        loop {
+            let (page, next) = self.db.list_rooms_for_user(&user.uuid, SYNC_BATCH, after)?;
+            rooms.extend(page.iter().map(|room| room.uuid));
+            if next.is_none() {
+                break;
+            }
+            after = next;
+        }
+        Ok(SyncState {
+            user: user.uuid,
+            device,
+            started,
+            start: MessageCursor::before(registered)?,
+            edits_since: EditCursor::before(synced.unwrap_or(registered))?,
+            rooms,
+            room: None,
+        })
+    }
+
+    /// Read the next batch of pushes to sync, or `None` once caught up.
+    ///
+    /// Edits, then deletions, of messages the device acknowledged come first
+    /// in each room, as the messages it did not are read in their last
+    /// revision, or as tombstones.
+    fn next_undelivered(
+        &self,
+        sync: &mut SyncState,
+    ) -> std::result::Result<Option<Vec<Push>>, Rejection> {
+        loop {
+            let (room, progress) = match sync.room.take() {
+                Some(room) => room,
+                None => {
+                    let Some(room) = sync.rooms.pop() else {
+                        self.db
+                            .save_device_synced(&sync.user, &sync.device, sync.started)?;
+                        return Ok(None);
+                    };
+                    let progress =
+                        match self
+                            .db
+                            .find_delivery_cursor(&room, &sync.user, &sync.device)?
+                        {
+                            Some(acknowledged) => RoomSync::Edits {
+                                acknowledged,
+                                after: sync.edits_since,
+                            },
+                            None => RoomSync::Messages(sync.start),
+                        };
+                    (room, progress)
+                }
+            };
+            let mut pushes = Vec::new();
+            match progress {
+                RoomSync::Edits {
+                    acknowledged,
+                    after,
+                } => {
+                    let (messages, next) = self.db.find_edits(&room, SYNC_BATCH, after)?;
+                    sync.room = Some(match next {
+                        Some(after) => (
+                            room,
+                            RoomSync::Edits {
+                                acknowledged,
+                                after,
+                            },
+                        ),
+                        None => (
+                            room,
+                            RoomSync::Deletions {
+                                acknowledged,
+                                after: sync.edits_since,
+                            },
+                        ),
+                    });
+                    for message in messages {
+                        if MessageCursor::of(&message)? <= acknowledged {
+                            pushes.push(Push::MessageEdited { room, message });
+                        }
+                    }
+                }
+                RoomSync::Deletions {
+                    acknowledged,
+                    after,
+                } => {
+                    let (deletions, next) = self.db.find_deletions(&room, SYNC_BATCH, after)?;
+                    sync.room = Some(match next {
+                        Some(after) => (
+                            room,
+                            RoomSync::Deletions {
+                                acknowledged,
+                                after,
+                            },
+                        ),
+                        None => (room, RoomSync::Messages(acknowledged)),
+                    });
+                    for Deletion { tombstone, by } in deletions {
+                        if MessageCursor::of(&tombstone)? <= acknowledged {
+                            pushes.push(Push::MessageDeleted {
+                                room,
+                                message: tombstone.uuid,
+                                by,
+                            });
+                        }
+                    }
+                }
+                RoomSync::Messages(after) => {
+                    let (messages, next) = self.db.find_messages(
+                        &room,
+                        SYNC_BATCH,
+                        Some(after),
+                        HistoryDirection::Forward,
+                    )?;
+                    sync.room = next.map(|next| (room, RoomSync::Messages(next)));
+                    pushes.extend(
+                        messages
+                            .into_iter()
+                            .map(|message| Push::Message { room, message }),
+                    );
+                }
+            }
+            if !pushes.is_empty() {
+                return Ok(Some(pushes));
+            }
+        }
+    }
+}
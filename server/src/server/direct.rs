@@ -0,0 +1,86 @@
+//! Direct messages between two devices, which clients use to hand each other
+//! the sender keys of their rooms.
+
+use super::{Handler, Reply};
+use shared::protocol::{ErrorCode, Push, Response};
+use shared::types::DirectMessage;
+use std::time::SystemTime;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Maximum length of the payload of a direct message.
+const MAX_PAYLOAD_LEN: usize = 64 * 1024;
+
+/// Maximum number of direct messages of a user waiting for a device, so no
+/// sender can fill the queue of a device for the others.
+const MAX_WAITING: usize = 1000;
+
+/// Maximum number of direct messages returned by one `FetchDirect` request.
+const MAX_DIRECT_PAGE: u32 = 100;
+
+impl Handler {
+    /// Queue a direct message for a device of another member of a room, and
+    /// push it to the device if online.
+    pub(super) fn send_direct(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        device: &Uuid,
+        payload: Vec<u8>,
+    ) -> Reply {
+        let (user, sender_device) = self.device()?;
+        let room = self.member_room(user, room_uuid)?;
+        if payload.is_empty() || payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Box::new(Response::error(
+                ErrorCode::BadRequest,
+                format!("payload must be 1 to {MAX_PAYLOAD_LEN} bytes"),
+            )));
+        }
+        // Only devices that published prekeys can have a session to decrypt
+        // the payload with.
+        let published = self
+            .db
+            .find_signed_prekeys(user_uuid)?
+            .iter()
+            .any(|(published, _)| published == device);
+        if !room.members.contains(user_uuid) || !published {
+            return Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("no device {device} of {user_uuid} in room {room_uuid}"),
+            )));
+        }
+
+        let message = DirectMessage {
+            uuid: Uuid::new_v4(),
+            room: room.uuid,
+            sender: user.uuid,
+            sender_device,
+            recipient: *user_uuid,
+            recipient_device: *device,
+            payload,
+            created: SystemTime::now(),
+        };
+        self.db.save_direct_message(&message, MAX_WAITING)?;
+        debug!(direct = %message.uuid, recipient = %user_uuid, %device, "sent direct message");
+        self.sessions
+            .push_device(user_uuid, device, &Push::Direct { message });
+        Ok(Response::Acknowledged)
+    }
+
+    pub(super) fn fetch_direct(&self, limit: u32) -> Reply {
+        let (user, device) = self.device()?;
+        let messages = self.db.find_direct_messages(
+            &user.uuid,
+            &device,
+            limit.min(MAX_DIRECT_PAGE) as usize,
+        )?;
+        Ok(Response::DirectMessages { messages })
+    }
+
+    pub(super) fn ack_direct(&self, messages: &[Uuid]) -> Reply {
+        let (user, device) = self.device()?;
+        self.db
+            .delete_direct_messages(&user.uuid, &device, messages)?;
+        Ok(Response::Acknowledged)
+    }
+}
@@ -0,0 +1,92 @@
+//! Prekeys published by users to start end-to-end encrypted sessions.
+//!
+//! The server checks the signature of signed prekeys, so a client can not
+//! publish a prekey its identity did not sign, but clients must still verify
+//! every bundle they fetch: they do not trust the server.
+
+use super::{Handler, Reply};
+use crate::rate_limit::RateLimiter;
+use shared::keys::{OneTimePrekey, PrekeyBundle, SignedPrekey};
+use shared::protocol::{ErrorCode, Response};
+use tokio::time::Duration;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Maximum number of one-time prekeys in one upload.
+const MAX_PREKEYS_PER_UPLOAD: usize = 100;
+
+/// Maximum number of one-time prekeys stored for a device.
+const MAX_ONE_TIME_PREKEYS: usize = 1000;
+
+/// Maximum number of times a user fetches the bundles of another user per
+/// `BUNDLE_FETCH_WINDOW`, so no one drains the one-time prekeys of others.
+const MAX_BUNDLE_FETCHES: u32 = 10;
+
+const BUNDLE_FETCH_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limiter of bundle fetches, by the user fetching and the user fetched.
+pub(super) fn bundle_fetch_limiter() -> RateLimiter<(Uuid, Uuid)> {
+    RateLimiter::new(MAX_BUNDLE_FETCHES, BUNDLE_FETCH_WINDOW)
+}
+
+impl Handler {
+    pub(super) fn upload_prekeys(
+        &self,
+        signed_prekey: Option<SignedPrekey>,
+        one_time_prekeys: Vec<OneTimePrekey>,
+    ) -> Reply {
+        let (user, device) = self.device()?;
+        if one_time_prekeys.len() > MAX_PREKEYS_PER_UPLOAD {
+            return Err(Box::new(Response::error(
+                ErrorCode::BadRequest,
+                format!("at most {MAX_PREKEYS_PER_UPLOAD} one-time prekeys per upload"),
+            )));
+        }
+
+        if let Some(prekey) = signed_prekey {
+            prekey.verify(&user.identity_key).map_err(|e| {
+                Response::error(ErrorCode::BadRequest, format!("signed prekey: {e}"))
+            })?;
+            self.db.save_signed_prekey(&user.uuid, &device, &prekey)?;
+            debug!(user = %user.uuid, %device, prekey = prekey.id, "signed prekey rotated");
+        }
+        let left = self.db.save_one_time_prekeys(
+            &user.uuid,
+            &device,
+            &one_time_prekeys,
+            MAX_ONE_TIME_PREKEYS,
+        )?;
+        Ok(Response::PrekeysUploaded {
+            one_time_prekeys: left as u32,
+        })
+    }
+
+    pub(super) fn fetch_prekey_bundle(&self, user_uuid: &Uuid) -> Reply {
+        let requester = self.user()?;
+        if !self.bundle_fetches.hit((requester.uuid, *user_uuid)) {
+            return Err(Box::new(Response::error(
+                ErrorCode::RateLimited,
+                format!("too many prekey bundles of {user_uuid} fetched"),
+            )));
+        }
+        let not_found =
+            || Response::error(ErrorCode::NotFound, format!("no prekeys for {user_uuid}"));
+        let user = self.db.find_user(user_uuid)?.ok_or_else(not_found)?;
+        let signed_prekeys = self.db.find_signed_prekeys(user_uuid)?;
+        if signed_prekeys.is_empty() {
+            return Err(Box::new(not_found()));
+        }
+
+        let mut bundles = Vec::with_capacity(signed_prekeys.len());
+        for (device, signed_prekey) in signed_prekeys {
+            bundles.push(PrekeyBundle {
+                user: user.uuid,
+                device,
+                identity_key: user.identity_key,
+                signed_prekey,
+                one_time_prekey: self.db.take_one_time_prekey(user_uuid, &device)?,
+            });
+        }
+        Ok(Response::PrekeyBundles { bundles })
+    }
+}
@@ -0,0 +1,310 @@
+//! Message requests.
+
+use super::{Handler, Rejection, Reply};
+use crate::db::{HistoryDirection, MessageCursor};
+use shared::protocol::{Cursor, ErrorCode, HistoryQuery, Push, Response};
+use shared::types::{Envelope, Message};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// Maximum number of messages returned by one `FetchHistory` request.
+const MAX_HISTORY_PAGE: u32 = 100;
+
+/// Maximum length of the header of an envelope, well above what sender keys
+/// and ratchets put there.
+const MAX_HEADER_LEN: usize = 256;
+
+/// How far ahead of the clock of the server the timestamp of an envelope may
+/// be, to allow for the clocks of devices drifting.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(10 * 60);
+
+impl Handler {
+    /// Persist a message and push it to the online members of its room.
+    pub(super) fn send_message(
+        &mut self,
+        envelope: Envelope,
+        reply_to: Option<Uuid>,
+        thread_root: Option<Uuid>,
+    ) -> Reply {
+        let (user, device) = self.device()?;
+        validate_envelope(&envelope, &device)?;
+        let room = self.member_room(user, &envelope.room)?;
+        self.validate_thread(&room.uuid, reply_to.as_ref(), thread_root.as_ref())?;
+        let mut message = Message::new(envelope, user);
+        message.reply_to = reply_to;
+        message.thread_root = thread_root;
+        self.db.save_message(&room, &message)?;
+        // Senders have read what they sent.
+        self.db
+            .advance_read_position(&room.uuid, &user.uuid, &MessageCursor::of(&message)?)?;
+
+        debug!(room = %room.uuid, message = %message.uuid, "sent message");
+        let uuid = message.uuid;
+        let push = Push::Message {
+            room: room.uuid,
+            message,
+        };
+        self.sessions.push_room(&room.uuid, &push, self.session);
+        self.stop_typing(&room.uuid);
+        Ok(Response::MessageSent { message: uuid })
+    }
+
+    /// Replace the envelope of a message of the logged in user, and push the
+    /// new revision to the online members of its room.
+    pub(super) fn edit_message(&mut self, message_uuid: &Uuid, envelope: Envelope) -> Reply {
+        let (user, device) = self.device()?;
+        validate_envelope(&envelope, &device)?;
+        let room = self.member_room(user, &envelope.room)?;
+        let message = self.room_message(&room.uuid, message_uuid)?;
+        if message.owner != user.uuid {
+            return Err(Box::new(Response::error(
+                ErrorCode::Forbidden,
+                "only the sender can edit a message",
+            )));
+        }
+        if message.is_deleted() {
+            return Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("message {message_uuid} was deleted"),
+            )));
+        }
+
+        let message = message.edit(envelope);
+        if !self.db.edit_message(&room.uuid, &message)? {
+            return Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("no message {message_uuid}"),
+            )));
+        }
+
+        debug!(room = %room.uuid, message = %message.uuid, "edited message");
+        let push = Push::MessageEdited {
+            room: room.uuid,
+            message,
+        };
+        self.sessions.push_room(&room.uuid, &push, self.session);
+        Ok(Response::Acknowledged)
+    }
+
+    /// Replace a message with a tombstone, and push the deletion to the online
+    /// members of its room.
+    pub(super) fn delete_message(&mut self, room_uuid: &Uuid, message_uuid: &Uuid) -> Reply {
+        let user = self.user()?;
+        let room = self.member_room(user, room_uuid)?;
+        let message = self.room_message(&room.uuid, message_uuid)?;
+        if message.owner != user.uuid && !room.owners.contains(&user.uuid) {
+            return Err(Box::new(Response::error(
+                ErrorCode::Forbidden,
+                "only the sender or a room owner can delete a message",
+            )));
+        }
+        if message.is_deleted() {
+            return Ok(Response::Acknowledged);
+        }
+
+        if !self
+            .db
+            .delete_message(&room.uuid, &message.tombstone(), &user.uuid)?
+        {
+            return Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("no message {message_uuid}"),
+            )));
+        }
+
+        debug!(room = %room.uuid, message = %message.uuid, "deleted message");
+        self.purge_deleted();
+        let push = Push::MessageDeleted {
+            room: room.uuid,
+            message: message.uuid,
+            by: user.uuid,
+        };
+        self.sessions.push_room(&room.uuid, &push, self.session);
+        Ok(Response::Acknowledged)
+    }
+
+    pub(super) fn fetch_revisions(&self, room_uuid: &Uuid, message_uuid: &Uuid) -> Reply {
+        let user = self.user()?;
+        let room = self.member_room(user, room_uuid)?;
+        let message = self.room_message(&room.uuid, message_uuid)?;
+        let revisions = self.db.find_revisions(&message.uuid)?;
+        Ok(Response::Revisions { revisions })
+    }
+
+    /// Check that a message of a room can reply to `reply_to` and be posted in
+    /// the thread of `thread_root`.
+    fn validate_thread(
+        &self,
+        room_uuid: &Uuid,
+        reply_to: Option<&Uuid>,
+        thread_root: Option<&Uuid>,
+    ) -> Result<(), Rejection> {
+        if let Some(root) = thread_root {
+            if self.room_message(room_uuid, root)?.thread_root.is_some() {
+                return Err(Box::new(Response::error(
+                    ErrorCode::BadRequest,
+                    "threads can not be nested",
+                )));
+            }
+        }
+        if let Some(parent) = reply_to {
+            let parent = self.room_message(room_uuid, parent)?;
+            if parent.thread_root.as_ref() != thread_root && Some(&parent.uuid) != thread_root {
+                return Err(Box::new(Response::error(
+                    ErrorCode::BadRequest,
+                    "replies belong to the thread of the message they reply to",
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load a message of a room.
+    pub(super) fn room_message(
+        &self,
+        room_uuid: &Uuid,
+        message_uuid: &Uuid,
+    ) -> Result<Message, Rejection> {
+        self.db
+            .find_message(room_uuid, message_uuid)?
+            .ok_or_else(|| {
+                Box::new(Response::error(
+                    ErrorCode::NotFound,
+                    format!("no message {message_uuid}"),
+                ))
+            })
+    }
+
+    /// Purge deleted messages from storage in the background.
+    pub(super) fn purge_deleted(&self) {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = db.purge_deleted() {
+                error!(cause = %err, "failed to purge deleted messages");
+            }
+        });
+    }
+
+    pub(super) fn fetch_history(&self, room_uuid: &Uuid, limit: u32, query: HistoryQuery) -> Reply {
+        let user = self.user()?;
+        let room = self.member_room(user, room_uuid)?;
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE) as usize;
+
+        let (messages, older, newer) = match query {
+            HistoryQuery::Latest => {
+                let (messages, older) =
+                    self.db
+                        .find_messages(&room.uuid, limit, None, HistoryDirection::Backward)?;
+                let newer = messages.first().map(MessageCursor::of).transpose()?;
+                (messages, older, newer)
+            }
+            HistoryQuery::Before(cursor) => {
+                let (messages, older) = self.db.find_messages(
+                    &room.uuid,
+                    limit,
+                    Some(decode_cursor(&cursor)?),
+                    HistoryDirection::Backward,
+                )?;
+                let newer = messages.first().map(MessageCursor::of).transpose()?;
+                (messages, older, newer)
+            }
+            HistoryQuery::After(cursor) => {
+                let after = decode_cursor(&cursor)?;
+                let (messages, _) = self.db.find_messages(
+                    &room.uuid,
+                    limit,
+                    Some(after),
+                    HistoryDirection::Forward,
+                )?;
+                let newer = match messages.last() {
+                    Some(message) => MessageCursor::of(message)?,
+                    None => after,
+                };
+                (messages, None, Some(newer))
+            }
+            HistoryQuery::Around(message) => {
+                let (messages, older) = self
+                    .db
+                    .find_messages_around(&room.uuid, &message, limit)?
+                    .ok_or_else(|| {
+                        Response::error(ErrorCode::NotFound, format!("no message {message}"))
+                    })?;
+                let newer = messages.first().map(MessageCursor::of).transpose()?;
+                (messages, older, newer)
+            }
+        };
+        let reactions = self.reaction_counts(&messages, &user.uuid)?;
+        Ok(Response::History {
+            messages,
+            older: older.map(encode_cursor),
+            newer: newer.map(encode_cursor),
+            reactions,
+        })
+    }
+
+    pub(super) fn fetch_thread(
+        &self,
+        room_uuid: &Uuid,
+        root_uuid: &Uuid,
+        limit: u32,
+        after: Option<&Cursor>,
+    ) -> Reply {
+        let user = self.user()?;
+        let room = self.member_room(user, room_uuid)?;
+        let root = self.room_message(&room.uuid, root_uuid)?;
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE) as usize;
+        let after = after.map(decode_cursor).transpose()?;
+
+        let (messages, _) = self.db.find_thread(
+            &room.uuid,
+            &root.uuid,
+            limit,
+            after,
+            HistoryDirection::Forward,
+        )?;
+        let next = match messages.last() {
+            Some(message) => Some(MessageCursor::of(message)?),
+            None => after,
+        };
+        let reactions =
+            self.reaction_counts(std::iter::once(&root).chain(&messages), &user.uuid)?;
+        Ok(Response::Thread {
+            root,
+            messages,
+            next: next.map(encode_cursor),
+            reactions,
+        })
+    }
+}
+
+fn encode_cursor(cursor: MessageCursor) -> Cursor {
+    Cursor::from_bytes(bincode::serialize(&cursor).expect("MessageCursor is serializable"))
+}
+
+fn decode_cursor(cursor: &Cursor) -> Result<MessageCursor, Rejection> {
+    bincode::deserialize(cursor.as_bytes())
+        .map_err(|_| Box::new(Response::error(ErrorCode::BadRequest, "invalid cursor")))
+}
+
+/// Reject envelopes that could not have been encrypted on the device of the
+/// connection, now. The server can not check anything past that.
+fn validate_envelope(envelope: &Envelope, device: &Uuid) -> Result<(), Rejection> {
+    let invalid = |message: &str| Err(Box::new(Response::error(ErrorCode::BadRequest, message)));
+    if envelope.sender_device != *device {
+        return invalid("envelopes must be sent from the device that encrypted them");
+    }
+    if envelope.ciphertext.is_empty() {
+        return invalid("empty envelope");
+    }
+    // Only envelopes of messages stored before encryption have no header.
+    if envelope.header.is_empty() || envelope.header.len() > MAX_HEADER_LEN {
+        return invalid(&format!(
+            "envelope header must be 1 to {MAX_HEADER_LEN} bytes"
+        ));
+    }
+    if envelope.timestamp > SystemTime::now() + MAX_CLOCK_SKEW {
+        return invalid("envelope timestamp is in the future");
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+//! Typing notifications and presence.
+//!
+//! Neither is stored: both live as long as the sessions producing them, and
+//! are only pushed to the sessions sharing a room with the user.
+
+use super::{Handler, Reply};
+use crate::sessions::SessionId;
+use shared::protocol::{ErrorCode, Presence, Push, Response};
+use std::collections::HashSet;
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// A user stops typing unless it repeats `SetTyping` within this time.
+#[cfg(not(test))]
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Short enough for tests to wait for it.
+#[cfg(test)]
+const TYPING_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Maximum number of users in one `FetchPresence` request.
+const MAX_PRESENCE_USERS: usize = 256;
+
+impl Handler {
+    pub(super) fn set_typing(&mut self, room: &Uuid, typing: bool) -> Reply {
+        let user = self.user()?.uuid;
+        // Sessions are subscribed to the rooms of their user, which spares a
+        // database lookup on every keystroke.
+        if !self.sessions.is_subscribed(room, self.session()) {
+            return Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("no room {room}"),
+            )));
+        }
+
+        if !typing {
+            self.stop_typing(room);
+        } else if self
+            .typing
+            .insert(*room, Instant::now() + TYPING_TIMEOUT)
+            .is_none()
+        {
+            self.push_typing(room, &user, true);
+        }
+        Ok(Response::Acknowledged)
+    }
+
+    /// Stop the typing of the logged in user in `room`, if it types there.
+    pub(super) fn stop_typing(&mut self, room: &Uuid) {
+        if self.typing.remove(room).is_none() {
+            return;
+        }
+        if let Some(user) = &self.user {
+            self.push_typing(room, &user.uuid, false);
+        }
+    }
+
+    /// Stop the typing of the logged in user everywhere.
+    pub(super) fn stop_all_typing(&mut self) {
+        let rooms: Vec<Uuid> = self.typing.keys().copied().collect();
+        for room in rooms {
+            self.stop_typing(&room);
+        }
+    }
+
+    /// Time at which the next typing expires, if the user types anywhere.
+    pub(super) fn typing_deadline(&self) -> Option<Instant> {
+        self.typing.values().min().copied()
+    }
+
+    /// Stop the typing that was not repeated in time.
+    pub(super) fn expire_typing(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Uuid> = self
+            .typing
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(room, _)| *room)
+            .collect();
+        for room in expired {
+            self.stop_typing(&room);
+        }
+    }
+
+    fn push_typing(&self, room: &Uuid, user: &Uuid, typing: bool) {
+        let push = Push::Typing {
+            room: *room,
+            user: *user,
+            typing,
+        };
+        self.sessions.push_room(room, &push, self.session);
+    }
+
+    pub(super) fn set_presence(&mut self, presence: Presence) -> Reply {
+        let user = self.user()?.uuid;
+        if presence == Presence::Offline {
+            return Err(Box::new(Response::error(
+                ErrorCode::BadRequest,
+                "connected devices can not be offline",
+            )));
+        }
+
+        let session = self.session();
+        let before = self.sessions.presence(&user);
+        self.sessions.set_presence(session, presence);
+        self.announce_presence(&user, before, self.sessions.peers(session));
+        Ok(Response::Acknowledged)
+    }
+
+    pub(super) fn fetch_presence(&self, users: Vec<Uuid>) -> Reply {
+        self.user()?;
+        if users.len() > MAX_PRESENCE_USERS {
+            return Err(Box::new(Response::error(
+                ErrorCode::BadRequest,
+                format!("at most {MAX_PRESENCE_USERS} users per request"),
+            )));
+        }
+
+        let peers = self.sessions.peers(self.session());
+        let presence = users
+            .into_iter()
+            .map(|user| {
+                let visible = self
+                    .sessions
+                    .sessions_of([&user])
+                    .iter()
+                    .any(|session| peers.contains(session));
+                let presence = if visible {
+                    self.sessions.presence(&user)
+                } else {
+                    Presence::Offline
+                };
+                (user, presence)
+            })
+            .collect();
+        Ok(Response::Presence { presence })
+    }
+
+    /// Push the presence of `user` to `peers` of other users, unless it is
+    /// still `before`.
+    pub(super) fn announce_presence(
+        &self,
+        user: &Uuid,
+        before: Presence,
+        mut peers: HashSet<SessionId>,
+    ) {
+        let presence = self.sessions.presence(user);
+        if presence != before {
+            for session in self.sessions.sessions_of([user]) {
+                peers.remove(&session);
+            }
+            let push = Push::Presence {
+                user: *user,
+                presence,
+            };
+            self.sessions.send(peers, &push, None);
+        }
+    }
+
+    /// The session of the logged in user.
+    fn session(&self) -> SessionId {
+        self.session.expect("logged in users have a session")
+    }
+}
@@ -0,0 +1,92 @@
+//! Reactions to messages.
+
+use super::{Handler, Rejection, Reply};
+use crate::db::DbError;
+use shared::protocol::{ErrorCode, Push, ReactionCount, Response, MAX_EMOJI_LEN};
+use shared::types::Message;
+use std::collections::{BTreeMap, HashMap};
+use tracing::debug;
+use uuid::Uuid;
+
+impl Handler {
+    /// Add, or withdraw, a reaction of the logged in user, and push the change
+    /// to the online members of the room.
+    pub(super) fn react(
+        &self,
+        room_uuid: &Uuid,
+        message_uuid: &Uuid,
+        emoji: String,
+        added: bool,
+    ) -> Reply {
+        let user = self.user()?;
+        validate_emoji(&emoji)?;
+        let room = self.member_room(user, room_uuid)?;
+        let message = self.room_message(&room.uuid, message_uuid)?;
+        if message.is_deleted() {
+            return Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("message {message_uuid} was deleted"),
+            )));
+        }
+
+        let changed = if added {
+            self.db.add_reaction(&message.uuid, &user.uuid, &emoji)?
+        } else {
+            self.db.remove_reaction(&message.uuid, &user.uuid, &emoji)?
+        };
+        if changed {
+            debug!(room = %room.uuid, message = %message.uuid, %emoji, added, "reacted");
+            let push = Push::Reaction {
+                room: room.uuid,
+                message: message.uuid,
+                user: user.uuid,
+                emoji,
+                added,
+            };
+            self.sessions.push_room(&room.uuid, &push, self.session);
+        }
+        Ok(Response::Acknowledged)
+    }
+
+    /// Reaction counts of `messages`, for the messages with reactions, as seen
+    /// by `user_uuid`.
+    pub(super) fn reaction_counts<'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a Message>,
+        user_uuid: &Uuid,
+    ) -> Result<HashMap<Uuid, Vec<ReactionCount>>, DbError> {
+        let mut counts = HashMap::new();
+        for message in messages {
+            // Ordered by emoji, so clients show them in a stable order.
+            let mut by_emoji: BTreeMap<String, ReactionCount> = BTreeMap::new();
+            for (user, emoji) in self.db.find_reactions(&message.uuid)? {
+                let count = by_emoji
+                    .entry(emoji)
+                    .or_insert_with_key(|emoji| ReactionCount {
+                        emoji: emoji.clone(),
+                        count: 0,
+                        mine: false,
+                    });
+                count.count += 1;
+                count.mine |= user == *user_uuid;
+            }
+            if !by_emoji.is_empty() {
+                counts.insert(message.uuid, by_emoji.into_values().collect());
+            }
+        }
+        Ok(counts)
+    }
+}
+
+fn validate_emoji(emoji: &str) -> Result<(), Rejection> {
+    if emoji.is_empty()
+        || emoji.len() > MAX_EMOJI_LEN
+        || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(Box::new(Response::error(
+            ErrorCode::BadRequest,
+            format!("emoji must be 1 to {MAX_EMOJI_LEN} bytes long, without spaces"),
+        )));
+    }
+    Ok(())
+}
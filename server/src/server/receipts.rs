@@ -0,0 +1,71 @@
+//! Read receipts and unread counts.
+
+use super::{Handler, Reply};
+use crate::db::{DbError, HistoryDirection, MessageCursor};
+use shared::protocol::{Push, Receipt, Response};
+use shared::types::Room;
+use uuid::Uuid;
+
+/// Unread messages are counted up to this number.
+const MAX_UNREAD: usize = 100;
+
+impl Handler {
+    pub(super) fn mark_read(&mut self, room_uuid: &Uuid, message_uuid: &Uuid) -> Reply {
+        let user = self.user()?;
+        let room = self.member_room(user, room_uuid)?;
+        let message = self.room_message(&room.uuid, message_uuid)?;
+
+        let cursor = MessageCursor::of(&message)?;
+        if self
+            .db
+            .advance_read_position(&room.uuid, &user.uuid, &cursor)?
+        {
+            self.push_receipt(&room.uuid, &user.uuid, &message.uuid, Receipt::Read);
+        }
+        Ok(Response::Acknowledged)
+    }
+
+    pub(super) fn fetch_receipts(&self, room_uuid: &Uuid) -> Reply {
+        let user = self.user()?;
+        let room = self.member_room(user, room_uuid)?;
+        let read = self
+            .db
+            .find_read_positions(&room.uuid)?
+            .into_iter()
+            .map(|(user, cursor)| (user, cursor.message))
+            .collect();
+        Ok(Response::Receipts { read })
+    }
+
+    /// Number of messages of others in `room` that `user_uuid` did not read,
+    /// up to `MAX_UNREAD`. Deleted messages do not count.
+    pub(super) fn unread(&self, room: &Room, user_uuid: &Uuid) -> Result<u32, DbError> {
+        let read = self.db.find_read_position(&room.uuid, user_uuid)?;
+        let (messages, _) =
+            self.db
+                .find_messages(&room.uuid, MAX_UNREAD, read, HistoryDirection::Forward)?;
+        let unread = messages
+            .iter()
+            .filter(|message| message.owner != *user_uuid && !message.is_deleted())
+            .count();
+        Ok(unread as u32)
+    }
+
+    /// Tell the online members of a room, and the other sessions of the user,
+    /// that `user_uuid` received or read the room up to `message_uuid`.
+    pub(super) fn push_receipt(
+        &self,
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        message_uuid: &Uuid,
+        receipt: Receipt,
+    ) {
+        let push = Push::Receipt {
+            room: *room_uuid,
+            user: *user_uuid,
+            message: *message_uuid,
+            receipt,
+        };
+        self.sessions.push_room(room_uuid, &push, self.session);
+    }
+}
@@ -0,0 +1,205 @@
+//! Room lifecycle requests.
+
+use super::{Handler, Rejection, Reply};
+use crate::db::DbError;
+use shared::protocol::{ErrorCode, Push, Response, RoomEvent, RoomSummary};
+use shared::types::{Room, User};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Maximum length of a room name, in bytes.
+const MAX_ROOM_NAME_LENGTH: usize = 256;
+
+/// Maximum number of rooms returned by one `ListRooms` request.
+const MAX_ROOMS_PAGE: u32 = 100;
+
+impl Handler {
+    pub(super) fn create_room(&mut self, name: String) -> Reply {
+        let user = self.user()?;
+        let room = Room::new(validate_name(&name)?, user);
+        self.db.save_room(&room)?;
+        self.sessions.join(room.uuid, &room.members);
+
+        debug!(room = %room.uuid, "created room");
+        self.notify(&room, RoomEvent::Created, &[]);
+        Ok(Response::Room { room })
+    }
+
+    pub(super) fn rename_room(&mut self, room_uuid: &Uuid, name: String) -> Reply {
+        let name = validate_name(&name)?.to_string();
+        self.update_room(room_uuid, |user, room| {
+            room.name = name;
+            Ok(RoomEvent::Renamed { by: user.uuid })
+        })
+    }
+
+    pub(super) fn add_members(&mut self, room_uuid: &Uuid, members: Vec<Uuid>) -> Reply {
+        self.user()?;
+        for member in &members {
+            if self.db.find_user(member)?.is_none() {
+                return Err(Box::new(Response::error(
+                    ErrorCode::NotFound,
+                    format!("no user {member}"),
+                )));
+            }
+        }
+
+        self.update_room(room_uuid, |user, room| {
+            let added = members
+                .into_iter()
+                .filter(|member| room.members.insert(*member))
+                .collect();
+            Ok(RoomEvent::MembersAdded {
+                members: added,
+                by: user.uuid,
+            })
+        })
+    }
+
+    pub(super) fn list_rooms(&self, limit: u32, after: Option<Uuid>) -> Reply {
+        let user = self.user()?;
+        let limit = limit.clamp(1, MAX_ROOMS_PAGE) as usize;
+        let (rooms, next) = self.db.list_rooms_for_user(&user.uuid, limit, after)?;
+        let rooms = rooms
+            .into_iter()
+            .map(|room| {
+                let unread = self.unread(&room, &user.uuid)?;
+                Ok(RoomSummary { room, unread })
+            })
+            .collect::<Result<_, DbError>>()?;
+        Ok(Response::Rooms { rooms, next })
+    }
+
+    /// Leave a room. The room is deleted once its last member left.
+    pub(super) fn leave_room(&mut self, room_uuid: &Uuid) -> Reply {
+        self.update_room(room_uuid, |user, room| {
+            let last_owner = room.owners.len() == 1 && room.owners.contains(&user.uuid);
+            if last_owner && room.members.len() > 1 {
+                return Err(Box::new(Response::error(
+                    ErrorCode::Forbidden,
+                    "the last owner can not leave a room with other members",
+                )));
+            }
+            room.members.remove(&user.uuid);
+            room.owners.remove(&user.uuid);
+            Ok(RoomEvent::MemberLeft { member: user.uuid })
+        })
+    }
+
+    pub(super) fn kick_member(&mut self, room_uuid: &Uuid, member: &Uuid) -> Reply {
+        self.update_room(room_uuid, |user, room| {
+            require_owner(user, room)?;
+            if !room.members.contains(member) {
+                return Err(Box::new(Response::error(
+                    ErrorCode::NotFound,
+                    format!("{member} is not a member of the room"),
+                )));
+            }
+            if room.owners.contains(member) {
+                return Err(Box::new(Response::error(
+                    ErrorCode::Forbidden,
+                    "owners can not be kicked",
+                )));
+            }
+            room.members.remove(member);
+            Ok(RoomEvent::MemberKicked {
+                member: *member,
+                by: user.uuid,
+            })
+        })
+    }
+
+    pub(super) fn delete_room(&mut self, room_uuid: &Uuid) -> Reply {
+        let user = self.user()?.clone();
+        let rooms_lock = self.rooms_lock.clone();
+        let _guard = rooms_lock.lock().unwrap();
+
+        let room = self.member_room(&user, room_uuid)?;
+        require_owner(&user, &room)?;
+        self.db.delete_room(&room.uuid)?;
+        self.sessions.close_room(&room.uuid);
+        self.purge_deleted();
+
+        debug!(room = %room.uuid, "deleted room");
+        self.notify(&room, RoomEvent::Deleted { by: user.uuid }, &[]);
+        Ok(Response::Room { room })
+    }
+
+    /// Apply `change` to a room the logged in user is a member of, then save
+    /// the room and notify its members, including those `change` removed.
+    fn update_room(
+        &mut self,
+        room_uuid: &Uuid,
+        change: impl FnOnce(&User, &mut Room) -> Result<RoomEvent, Rejection>,
+    ) -> Reply {
+        let user = self.user()?.clone();
+        let rooms_lock = self.rooms_lock.clone();
+        let _guard = rooms_lock.lock().unwrap();
+
+        let mut room = self.member_room(&user, room_uuid)?;
+        let before = room.members.clone();
+        let event = change(&user, &mut room)?;
+        let removed: Vec<Uuid> = before.difference(&room.members).copied().collect();
+        if room.members.is_empty() {
+            self.db.delete_room(&room.uuid)?;
+            self.sessions.close_room(&room.uuid);
+            self.purge_deleted();
+        } else {
+            self.db.save_room(&room)?;
+            self.sessions
+                .join(room.uuid, room.members.difference(&before));
+            self.sessions.leave(room.uuid, &removed);
+        }
+
+        debug!(room = %room.uuid, ?event, "updated room");
+        self.notify(&room, event, &removed);
+        Ok(Response::Room { room })
+    }
+
+    /// Load a room `user` is a member of.
+    ///
+    /// Rooms the user is not a member of are reported as missing, so their
+    /// existence is not revealed.
+    pub(super) fn member_room(&self, user: &User, room_uuid: &Uuid) -> Result<Room, Rejection> {
+        match self.db.find_room(room_uuid)? {
+            Some(room) if room.members.contains(&user.uuid) => Ok(room),
+            _ => Err(Box::new(Response::error(
+                ErrorCode::NotFound,
+                format!("no room {room_uuid}"),
+            ))),
+        }
+    }
+
+    /// Push `event` to the other sessions of the members of `room`, and of the
+    /// `removed` users.
+    fn notify(&self, room: &Room, event: RoomEvent, removed: &[Uuid]) {
+        let push = Push::Room {
+            room: room.clone(),
+            event,
+        };
+        self.sessions
+            .push(room.members.iter().chain(removed), &push, self.session);
+    }
+}
+
+fn require_owner(user: &User, room: &Room) -> Result<(), Rejection> {
+    if room.owners.contains(&user.uuid) {
+        Ok(())
+    } else {
+        Err(Box::new(Response::error(
+            ErrorCode::Forbidden,
+            "only owners can do this",
+        )))
+    }
+}
+
+fn validate_name(name: &str) -> Result<&str, Rejection> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_ROOM_NAME_LENGTH {
+        return Err(Box::new(Response::error(
+            ErrorCode::BadRequest,
+            format!("room name must be 1 to {MAX_ROOM_NAME_LENGTH} bytes long"),
+        )));
+    }
+    Ok(name)
+}
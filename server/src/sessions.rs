@@ -0,0 +1,379 @@
+use dashmap::DashMap;
+use shared::protocol::{Frame, Presence, Push};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Maximum number of frames queued for a single session.
+///
+/// A session whose queue is full is dropped, see `Sessions`.
+const SESSION_QUEUE_CAPACITY: usize = 256;
+
+/// Identifies one connection of a logged in user.
+pub(crate) type SessionId = u64;
+
+#[derive(Debug)]
+struct Session {
+    user: Uuid,
+    device: Uuid,
+    sender: mpsc::Sender<Frame>,
+    /// Either `Online` or `Away`.
+    presence: Presence,
+    /// Rooms the session is subscribed to, the reverse of `Sessions::rooms`.
+    rooms: HashSet<Uuid>,
+}
+
+/// Registry of the sessions of logged in users.
+///
+/// Each session owns a bounded queue of frames, which its connection handler
+/// drains and writes to the peer. Frames are pushed either to users, reaching
+/// every session of the user, or to rooms, reaching every session subscribed
+/// to the room.
+///
+/// A session whose queue is full is unregistered rather than missing pushes:
+/// its connection handler then closes the connection once the queue is
+/// drained, and the client catches up with `Sync` when it reconnects.
+///
+/// The maps are only ever locked one at a time, so they can not deadlock.
+#[derive(Debug, Default)]
+pub(crate) struct Sessions {
+    next_id: AtomicU64,
+    sessions: DashMap<SessionId, Session>,
+    users: DashMap<Uuid, HashSet<SessionId>>,
+    /// Sessions of the members of each room.
+    rooms: DashMap<Uuid, HashSet<SessionId>>,
+}
+
+impl Sessions {
+    /// Register a new session of `user` on `device`.
+    ///
+    /// Returns the id of the session and the receiving end of its queue. The
+    /// session is not subscribed to any room yet, see `subscribe`.
+    pub(crate) fn register(&self, user: Uuid, device: Uuid) -> (SessionId, mpsc::Receiver<Frame>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel(SESSION_QUEUE_CAPACITY);
+        self.sessions.insert(
+            id,
+            Session {
+                user,
+                device,
+                sender,
+                presence: Presence::Online,
+                rooms: HashSet::new(),
+            },
+        );
+        self.users.entry(user).or_default().insert(id);
+        (id, receiver)
+    }
+
+    /// Remove a session registered with `register` from every room and user.
+    pub(crate) fn unregister(&self, session: SessionId) {
+        let Some((_, Session { user, rooms, .. })) = self.sessions.remove(&session) else {
+            return;
+        };
+        self.users.remove_if_mut(&user, |_, sessions| {
+            sessions.remove(&session);
+            sessions.is_empty()
+        });
+        for room in rooms {
+            self.rooms.remove_if_mut(&room, |_, sessions| {
+                sessions.remove(&session);
+                sessions.is_empty()
+            });
+        }
+    }
+
+    /// Presence of `user`: online if any of its sessions is, away if all of
+    /// them are, and offline without sessions.
+    pub(crate) fn presence(&self, user: &Uuid) -> Presence {
+        let sessions = self.sessions_of([user]);
+        if sessions.is_empty() {
+            return Presence::Offline;
+        }
+        let online = sessions.iter().any(|id| {
+            self.sessions
+                .get(id)
+                .is_some_and(|session| session.presence == Presence::Online)
+        });
+        if online {
+            Presence::Online
+        } else {
+            Presence::Away
+        }
+    }
+
+    /// Set the presence of a single session, either `Online` or `Away`.
+    pub(crate) fn set_presence(&self, session: SessionId, presence: Presence) {
+        debug_assert_ne!(presence, Presence::Offline);
+        if let Some(mut session) = self.sessions.get_mut(&session) {
+            session.presence = presence;
+        }
+    }
+
+    /// Subscribe `session` to `rooms`.
+    pub(crate) fn subscribe(&self, session: SessionId, rooms: impl IntoIterator<Item = Uuid>) {
+        let rooms: Vec<_> = rooms.into_iter().collect();
+        for room in &rooms {
+            self.rooms.entry(*room).or_default().insert(session);
+        }
+        if let Some(mut session) = self.sessions.get_mut(&session) {
+            session.rooms.extend(rooms);
+        }
+    }
+
+    /// Subscribe every session of `users` to `room`.
+    pub(crate) fn join<'a>(&self, room: Uuid, users: impl IntoIterator<Item = &'a Uuid>) {
+        let sessions = self.sessions_of(users);
+        if sessions.is_empty() {
+            return;
+        }
+        self.rooms
+            .entry(room)
+            .or_default()
+            .extend(sessions.iter().copied());
+        self.index_room(room, sessions, true);
+    }
+
+    /// Unsubscribe every session of `users` from `room`.
+    pub(crate) fn leave<'a>(&self, room: Uuid, users: impl IntoIterator<Item = &'a Uuid>) {
+        let sessions = self.sessions_of(users);
+        self.rooms.remove_if_mut(&room, |_, subscribed| {
+            subscribed.retain(|session| !sessions.contains(session));
+            subscribed.is_empty()
+        });
+        self.index_room(room, sessions, false);
+    }
+
+    /// Add `room` to, or remove it from, the rooms of `sessions`.
+    fn index_room(&self, room: Uuid, sessions: HashSet<SessionId>, subscribed: bool) {
+        for id in sessions {
+            if let Some(mut session) = self.sessions.get_mut(&id) {
+                if subscribed {
+                    session.rooms.insert(room);
+                } else {
+                    session.rooms.remove(&room);
+                }
+            }
+        }
+    }
+
+    /// Whether `session` is subscribed to `room`, that is whether its user is
+    /// a member of the room.
+    pub(crate) fn is_subscribed(&self, room: &Uuid, session: SessionId) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|sessions| sessions.contains(&session))
+    }
+
+    /// The other sessions subscribed to any room `session` is subscribed to.
+    pub(crate) fn peers(&self, session: SessionId) -> HashSet<SessionId> {
+        let rooms = match self.sessions.get(&session) {
+            Some(session) => session.rooms.clone(),
+            None => return HashSet::new(),
+        };
+        let mut peers = HashSet::new();
+        for room in rooms {
+            if let Some(sessions) = self.rooms.get(&room) {
+                peers.extend(sessions.iter().copied());
+            }
+        }
+        peers.remove(&session);
+        peers
+    }
+
+    /// Unsubscribe all sessions from a deleted room.
+    pub(crate) fn close_room(&self, room: &Uuid) {
+        if let Some((room, sessions)) = self.rooms.remove(room) {
+            self.index_room(room, sessions, false);
+        }
+    }
+
+    /// Push `push` to every session of `users`, except `skip`.
+    pub(crate) fn push<'a>(
+        &self,
+        users: impl IntoIterator<Item = &'a Uuid>,
+        push: &Push,
+        skip: Option<SessionId>,
+    ) {
+        self.send(self.sessions_of(users), push, skip);
+    }
+
+    /// Push `push` to every session of `device` of `user`.
+    pub(crate) fn push_device(&self, user: &Uuid, device: &Uuid, push: &Push) {
+        let sessions = self
+            .sessions_of([user])
+            .into_iter()
+            .filter(|id| {
+                self.sessions
+                    .get(id)
+                    .is_some_and(|session| session.device == *device)
+            })
+            .collect();
+        self.send(sessions, push, None);
+    }
+
+    /// Push `push` to every session subscribed to `room`, except `skip`.
+    pub(crate) fn push_room(&self, room: &Uuid, push: &Push, skip: Option<SessionId>) {
+        let sessions = match self.rooms.get(room) {
+            Some(sessions) => sessions.clone(),
+            None => return,
+        };
+        self.send(sessions, push, skip);
+    }
+
+    /// Sessions of `users`.
+    pub(crate) fn sessions_of<'a>(
+        &self,
+        users: impl IntoIterator<Item = &'a Uuid>,
+    ) -> HashSet<SessionId> {
+        let mut sessions = HashSet::new();
+        for user in users {
+            if let Some(ids) = self.users.get(user) {
+                sessions.extend(ids.iter().copied());
+            }
+        }
+        sessions
+    }
+
+    /// Push `push` to `sessions`, except `skip`.
+    pub(crate) fn send(&self, sessions: HashSet<SessionId>, push: &Push, skip: Option<SessionId>) {
+        for id in sessions {
+            if Some(id) == skip {
+                continue;
+            }
+            let Some(session) = self.sessions.get(&id) else {
+                continue;
+            };
+            let sent = session.sender.try_send(Frame::Push(push.clone()));
+            if let Err(TrySendError::Full(_)) = sent {
+                warn!(user = %session.user, session = id, "session queue is full, dropping session");
+                drop(session);
+                self.unregister(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::envelope;
+
+    fn message(room: Uuid) -> Push {
+        Push::Message {
+            room,
+            message: shared::types::Message::new(
+                envelope(room, Uuid::new_v4(), "hi"),
+                &shared::types::User::new("alice".to_string(), [1; 32]),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_push_room_follows_subscriptions() {
+        let sessions = Sessions::default();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let room = Uuid::new_v4();
+        let (alice1, mut alice1_rx) = sessions.register(alice, Uuid::new_v4());
+        let (alice2, mut alice2_rx) = sessions.register(alice, Uuid::new_v4());
+        let (bob1, mut bob1_rx) = sessions.register(bob, Uuid::new_v4());
+        sessions.subscribe(alice1, [room]);
+        sessions.subscribe(alice2, [room]);
+
+        sessions.push_room(&room, &message(room), Some(alice1));
+        assert!(alice1_rx.try_recv().is_err());
+        assert!(alice2_rx.try_recv().is_ok());
+        assert!(bob1_rx.try_recv().is_err());
+
+        sessions.join(room, [&bob]);
+        sessions.push_room(&room, &message(room), None);
+        assert!(alice1_rx.try_recv().is_ok());
+        assert!(alice2_rx.try_recv().is_ok());
+        assert!(bob1_rx.try_recv().is_ok());
+
+        sessions.leave(room, [&alice]);
+        sessions.unregister(bob1);
+        sessions.push_room(&room, &message(room), None);
+        assert!(alice1_rx.try_recv().is_err());
+        assert!(alice2_rx.try_recv().is_err());
+        assert!(sessions.rooms.is_empty());
+        assert!(sessions.users.get(&bob).is_none());
+    }
+
+    #[test]
+    fn test_presence_follows_sessions() {
+        let sessions = Sessions::default();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let room = Uuid::new_v4();
+        assert_eq!(sessions.presence(&alice), Presence::Offline);
+
+        let (alice1, _alice1_rx) = sessions.register(alice, Uuid::new_v4());
+        let (alice2, _alice2_rx) = sessions.register(alice, Uuid::new_v4());
+        let (bob1, _bob1_rx) = sessions.register(bob, Uuid::new_v4());
+        assert_eq!(sessions.presence(&alice), Presence::Online);
+        assert!(sessions.peers(alice1).is_empty());
+
+        sessions.subscribe(alice1, [room]);
+        sessions.subscribe(bob1, [room]);
+        assert!(sessions.is_subscribed(&room, alice1));
+        assert!(!sessions.is_subscribed(&room, alice2));
+        assert_eq!(sessions.peers(alice1), HashSet::from([bob1]));
+
+        sessions.set_presence(alice1, Presence::Away);
+        assert_eq!(sessions.presence(&alice), Presence::Online);
+        sessions.set_presence(alice2, Presence::Away);
+        assert_eq!(sessions.presence(&alice), Presence::Away);
+        sessions.unregister(alice2);
+        sessions.unregister(alice1);
+        assert_eq!(sessions.presence(&alice), Presence::Offline);
+        assert!(sessions.peers(bob1).is_empty());
+    }
+
+    #[test]
+    fn test_peers_follow_rooms() {
+        let sessions = Sessions::default();
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice1, _alice1_rx) = sessions.register(alice, Uuid::new_v4());
+        let (bob1, _bob1_rx) = sessions.register(bob, Uuid::new_v4());
+        let (carol1, _carol1_rx) = sessions.register(carol, Uuid::new_v4());
+        sessions.subscribe(alice1, [first, second]);
+        sessions.join(first, [&bob]);
+        sessions.join(second, [&carol]);
+        assert_eq!(sessions.peers(alice1), HashSet::from([bob1, carol1]));
+        assert_eq!(sessions.peers(bob1), HashSet::from([alice1]));
+
+        sessions.leave(first, [&bob]);
+        assert_eq!(sessions.peers(alice1), HashSet::from([carol1]));
+        assert!(sessions.peers(bob1).is_empty());
+
+        sessions.close_room(&second);
+        assert!(sessions.peers(alice1).is_empty());
+        assert!(sessions.sessions.get(&carol1).unwrap().rooms.is_empty());
+    }
+
+    #[test]
+    fn test_full_queue_drops_session() {
+        let sessions = Sessions::default();
+        let room = Uuid::new_v4();
+        let (session, mut rx) = sessions.register(Uuid::new_v4(), Uuid::new_v4());
+        sessions.subscribe(session, [room]);
+
+        for _ in 0..SESSION_QUEUE_CAPACITY + 10 {
+            sessions.push_room(&room, &message(room), None);
+        }
+        let mut received = 0;
+        while rx.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, SESSION_QUEUE_CAPACITY);
+        // The queue is closed once drained, and the session forgotten.
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+        assert!(sessions.sessions.get(&session).is_none());
+    }
+}
@@ -0,0 +1,22 @@
+//! Fixtures shared by the tests of the server.
+
+use shared::types::{Envelope, Message};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// An envelope from `device` in `room` whose ciphertext is `text`, which
+/// neither the server nor the database tell apart from a real one.
+pub(crate) fn envelope(room: Uuid, device: Uuid, text: &str) -> Envelope {
+    Envelope {
+        sender_device: device,
+        room,
+        header: vec![0],
+        ciphertext: text.as_bytes().to_vec(),
+        timestamp: SystemTime::now(),
+    }
+}
+
+/// The text of a message with an envelope of [`envelope`].
+pub(crate) fn text(message: &Message) -> &str {
+    std::str::from_utf8(&message.envelope.as_ref().unwrap().ciphertext).unwrap()
+}
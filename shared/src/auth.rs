@@ -0,0 +1,77 @@
+//! Challenge/response authentication with a user's long-term identity key.
+//!
+//! The server hands out a random [`Challenge`] per connection. The client
+//! proves possession of the private half of its [`IdentityKey`] by signing
+//! it, so no password or other shared secret ever leaves the device.
+
+use crate::types::IdentityKey;
+use ed25519_dalek::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+
+pub use ed25519_dalek::{Signature, SigningKey};
+
+/// Domain separation tag, so a signature over a challenge can not be
+/// mistaken for a signature over anything else made with the same key.
+const CONTEXT: &[u8] = b"e-charlar auth challenge v1";
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AuthError {
+    #[error("malformed identity key")]
+    MalformedKey,
+    #[error("signature does not match the identity key")]
+    BadSignature,
+}
+
+/// Single-use nonce issued by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Challenge(pub [u8; 32]);
+
+impl Challenge {
+    pub fn sign(&self, key: &SigningKey) -> Signature {
+        key.sign(&self.payload())
+    }
+
+    pub fn verify(
+        &self,
+        identity_key: &IdentityKey,
+        signature: &Signature,
+    ) -> Result<(), AuthError> {
+        ed25519_dalek::VerifyingKey::from_bytes(identity_key)
+            .map_err(|_| AuthError::MalformedKey)?
+            .verify(&self.payload(), signature)
+            .map_err(|_| AuthError::BadSignature)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        [CONTEXT, &self.0].concat()
+    }
+}
+
+/// Public identity key of a signing key, as stored in [`crate::types::User`].
+pub fn identity_key(key: &SigningKey) -> IdentityKey {
+    key.verifying_key().to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_signed_challenge() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let challenge = Challenge([1; 32]);
+        let signature = challenge.sign(&key);
+
+        assert_eq!(challenge.verify(&identity_key(&key), &signature), Ok(()));
+        assert_eq!(
+            Challenge([2; 32]).verify(&identity_key(&key), &signature),
+            Err(AuthError::BadSignature)
+        );
+
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert_eq!(
+            challenge.verify(&identity_key(&other), &signature),
+            Err(AuthError::BadSignature)
+        );
+    }
+}
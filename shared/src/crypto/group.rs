@@ -0,0 +1,720 @@
+//! Sender keys, for rooms with many members.
+//!
+//! Instead of encrypting every message once per member, each device of a
+//! room encrypts with a chain of its own, the sender key, and hands the
+//! current state of that chain to every other member over their pairwise
+//! [`Session`]s. Members can then decrypt any later message of the device,
+//! and messages are signed so members can not forge each other's.
+//!
+//! Whenever a member leaves or is removed, every device rotates its sender
+//! key and hands the new one to the remaining members only, so the removed
+//! member can not decrypt subsequent messages.
+
+use super::{open, seal, Chain, CryptoError, EncryptedMessage, Result, Session, MAX_SKIP};
+use crate::auth::{Signature, SigningKey};
+use crate::types::{Content, Envelope, IdentityKey};
+use ed25519_dalek::{Signer, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Domain separation tag of the signatures of group messages.
+const CONTEXT: &[u8] = b"e-charlar sender key v1";
+
+/// Maximum number of sender keys kept per device, so messages sent right
+/// before a rotation still decrypt.
+const MAX_SENDER_KEYS: usize = 5;
+
+/// Maximum number of keys of skipped messages kept per sender key.
+const MAX_SKIPPED_SENDER_KEYS: usize = 500;
+
+/// A device sending in a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SenderId {
+    pub user: Uuid,
+    pub device: Uuid,
+}
+
+/// The sender key of a device as handed to other members, starting at the
+/// next message the device sends.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SenderKeyDistribution {
+    pub room: Uuid,
+    pub key_id: u32,
+    pub index: u32,
+    pub chain_key: [u8; 32],
+    /// Public key the messages of the chain are signed with.
+    pub signing_key: IdentityKey,
+}
+
+impl fmt::Debug for SenderKeyDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SenderKeyDistribution")
+            .field("room", &self.room)
+            .field("key_id", &self.key_id)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A message encrypted with a sender key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupMessage {
+    pub key_id: u32,
+    pub index: u32,
+    pub ciphertext: Vec<u8>,
+    pub signature: Signature,
+}
+
+/// What the header of an envelope carries for a group message.
+#[derive(Serialize, Deserialize)]
+struct GroupHeader {
+    key_id: u32,
+    index: u32,
+    signature: Signature,
+}
+
+/// Sender key of the local device.
+#[derive(Clone, Serialize, Deserialize)]
+struct OwnSenderKey {
+    key_id: u32,
+    chain: Chain,
+    signing_key: SigningKey,
+}
+
+impl OwnSenderKey {
+    fn generate() -> Self {
+        Self {
+            key_id: rand::random(),
+            chain: Chain::new(rand::random()),
+            signing_key: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+}
+
+/// Sender key of another device.
+#[derive(Clone, Serialize, Deserialize)]
+struct SenderKey {
+    key_id: u32,
+    chain: Chain,
+    verifying_key: VerifyingKey,
+    /// Keys of skipped messages, by index.
+    skipped: VecDeque<(u32, [u8; 32])>,
+}
+
+impl SenderKey {
+    fn decrypt(&mut self, room: &Uuid, message: &GroupMessage) -> Result<Vec<u8>> {
+        self.verifying_key
+            .verify(
+                &signed_payload(room, message.key_id, message.index, &message.ciphertext),
+                &message.signature,
+            )
+            .map_err(|_| CryptoError::Undecryptable)?;
+
+        let message_key = match self
+            .skipped
+            .iter()
+            .position(|(index, _)| *index == message.index)
+        {
+            Some(position) => self.skipped.remove(position).unwrap().1,
+            None => {
+                if message.index < self.chain.index {
+                    return Err(CryptoError::Duplicate);
+                }
+                if message.index > self.chain.index.saturating_add(MAX_SKIP) {
+                    return Err(CryptoError::TooManySkipped);
+                }
+                while self.chain.index < message.index {
+                    let index = self.chain.index;
+                    self.skipped.push_back((index, self.chain.step()));
+                    if self.skipped.len() > MAX_SKIPPED_SENDER_KEYS {
+                        self.skipped.pop_front();
+                    }
+                }
+                self.chain.step()
+            }
+        };
+        open(
+            &message_key,
+            &associated_data(room, message.key_id, message.index),
+            &message.ciphertext,
+        )
+    }
+}
+
+/// The sender keys of one room, as seen by one device.
+///
+/// Group sessions serialize, so clients can persist them between runs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupSession {
+    room: Uuid,
+    sender: SenderId,
+    /// Identity key of each member, which the pairwise sessions sender keys
+    /// arrive over must be with.
+    members: HashMap<Uuid, IdentityKey>,
+    own: OwnSenderKey,
+    /// Sender keys of other devices, newest first.
+    received: HashMap<SenderId, VecDeque<SenderKey>>,
+}
+
+impl GroupSession {
+    /// Start encrypting in `room` as `sender`. Every other device of
+    /// `members`, including those of the user of `sender`, needs the
+    /// [`distribution`](Self::distribution) of the session.
+    pub fn new(room: Uuid, sender: SenderId, members: HashMap<Uuid, IdentityKey>) -> Self {
+        Self {
+            room,
+            sender,
+            members,
+            own: OwnSenderKey::generate(),
+            received: HashMap::new(),
+        }
+    }
+
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            room: self.room,
+            key_id: self.own.key_id,
+            index: self.own.chain.index,
+            chain_key: self.own.chain.key,
+            signing_key: self.own.signing_key.verifying_key().to_bytes(),
+        }
+    }
+
+    /// Encrypt the distribution of this session for the peer of `session`.
+    pub fn distribute(&self, session: &mut Session) -> Result<EncryptedMessage> {
+        let distribution = bincode::serialize(&self.distribution())
+            .map_err(|e| CryptoError::MalformedContent(e.to_string()))?;
+        session.encrypt(&distribution)
+    }
+
+    /// Decrypt and accept the distribution `sender` sent over `session`.
+    ///
+    /// The peer of `session` must own the identity key of `sender.user`, so
+    /// a member can not hand out a sender key in the name of another.
+    pub fn receive_distribution(
+        &mut self,
+        sender: SenderId,
+        session: &mut Session,
+        message: &EncryptedMessage,
+    ) -> Result<()> {
+        match self.members.get(&sender.user) {
+            Some(identity_key) if identity_key == session.remote_identity_key() => {}
+            Some(_) => return Err(CryptoError::WrongSender),
+            None => return Err(CryptoError::NotAMember),
+        }
+        let distribution = bincode::deserialize(&session.decrypt(message)?)
+            .map_err(|e| CryptoError::MalformedContent(e.to_string()))?;
+        self.accept_distribution(sender, &distribution)
+    }
+
+    /// Accept the sender key of another device of the room, once its sender
+    /// is authenticated.
+    fn accept_distribution(
+        &mut self,
+        sender: SenderId,
+        distribution: &SenderKeyDistribution,
+    ) -> Result<()> {
+        if distribution.room != self.room {
+            return Err(CryptoError::WrongRoom);
+        }
+        if !self.members.contains_key(&sender.user) {
+            return Err(CryptoError::NotAMember);
+        }
+        let verifying_key = VerifyingKey::from_bytes(&distribution.signing_key)
+            .map_err(|_| CryptoError::MalformedKey)?;
+
+        let keys = self.received.entry(sender).or_default();
+        if keys.iter().any(|key| key.key_id == distribution.key_id) {
+            // Already known, and possibly further along.
+            return Ok(());
+        }
+        keys.push_front(SenderKey {
+            key_id: distribution.key_id,
+            chain: Chain {
+                key: distribution.chain_key,
+                index: distribution.index,
+            },
+            verifying_key,
+            skipped: VecDeque::new(),
+        });
+        keys.truncate(MAX_SENDER_KEYS);
+        Ok(())
+    }
+
+    /// Follow a change of the members of the room.
+    ///
+    /// Returns the users whose devices need the distribution of this session:
+    /// the new members, or every member when the sender key rotated because a
+    /// member is gone. The sender keys of departed members are dropped, so
+    /// their messages no longer decrypt.
+    ///
+    /// A member whose identity key changed counts as departed and added back:
+    /// the sender keys handed out under the former key are dropped too.
+    pub fn update_members(&mut self, members: HashMap<Uuid, IdentityKey>) -> Vec<Uuid> {
+        let departed = self
+            .members
+            .iter()
+            .any(|(user, identity_key)| members.get(user) != Some(identity_key));
+        let mut added: Vec<Uuid> = members
+            .iter()
+            .filter(|(user, identity_key)| self.members.get(user) != Some(identity_key))
+            .map(|(user, _)| *user)
+            .collect();
+        let previous = std::mem::replace(&mut self.members, members);
+        self.received
+            .retain(|sender, _| previous.get(&sender.user) == self.members.get(&sender.user));
+
+        if departed {
+            self.own = OwnSenderKey::generate();
+            self.members.keys().copied().collect()
+        } else {
+            added.sort();
+            added
+        }
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> GroupMessage {
+        let key_id = self.own.key_id;
+        let index = self.own.chain.index;
+        let message_key = self.own.chain.step();
+        let ciphertext = seal(
+            &message_key,
+            &associated_data(&self.room, key_id, index),
+            plaintext,
+        );
+        let signature =
+            self.own
+                .signing_key
+                .sign(&signed_payload(&self.room, key_id, index, &ciphertext));
+        GroupMessage {
+            key_id,
+            index,
+            ciphertext,
+            signature,
+        }
+    }
+
+    /// Decrypt a message of another device. The session is left unchanged
+    /// when it fails.
+    pub fn decrypt(&mut self, sender: &SenderId, message: &GroupMessage) -> Result<Vec<u8>> {
+        let key = self
+            .received
+            .get_mut(sender)
+            .and_then(|keys| keys.iter_mut().find(|key| key.key_id == message.key_id))
+            .ok_or(CryptoError::UnknownSenderKey)?;
+        let mut next = key.clone();
+        let plaintext = next.decrypt(&self.room, message)?;
+        *key = next;
+        Ok(plaintext)
+    }
+
+    pub fn encrypt_content(&mut self, content: &Content) -> Result<GroupMessage> {
+        let plaintext = bincode::serialize(content)
+            .map_err(|e| CryptoError::MalformedContent(e.to_string()))?;
+        Ok(self.encrypt(&plaintext))
+    }
+
+    pub fn decrypt_content(
+        &mut self,
+        sender: &SenderId,
+        message: &GroupMessage,
+    ) -> Result<Content> {
+        let plaintext = self.decrypt(sender, message)?;
+        bincode::deserialize(&plaintext).map_err(|e| CryptoError::MalformedContent(e.to_string()))
+    }
+
+    /// Encrypt `content` into an envelope for the room.
+    pub fn seal(&mut self, content: &Content) -> Result<Envelope> {
+        let message = self.encrypt_content(content)?;
+        let header = GroupHeader {
+            key_id: message.key_id,
+            index: message.index,
+            signature: message.signature,
+        };
+        Ok(Envelope {
+            sender_device: self.sender.device,
+            room: self.room,
+            header: bincode::serialize(&header)
+                .map_err(|e| CryptoError::MalformedEnvelope(e.to_string()))?,
+            ciphertext: message.ciphertext,
+            timestamp: SystemTime::now(),
+        })
+    }
+
+    /// Decrypt an envelope `user` sent in the room.
+    pub fn open(&mut self, user: Uuid, envelope: &Envelope) -> Result<Content> {
+        if envelope.room != self.room {
+            return Err(CryptoError::WrongRoom);
+        }
+        let header: GroupHeader = bincode::deserialize(&envelope.header)
+            .map_err(|e| CryptoError::MalformedEnvelope(e.to_string()))?;
+        let sender = SenderId {
+            user,
+            device: envelope.sender_device,
+        };
+        let message = GroupMessage {
+            key_id: header.key_id,
+            index: header.index,
+            ciphertext: envelope.ciphertext.clone(),
+            signature: header.signature,
+        };
+        self.decrypt_content(&sender, &message)
+    }
+}
+
+impl fmt::Debug for GroupSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupSession")
+            .field("room", &self.room)
+            .field("sender", &self.sender)
+            .finish_non_exhaustive()
+    }
+}
+
+fn associated_data(room: &Uuid, key_id: u32, index: u32) -> Vec<u8> {
+    [
+        &room.as_bytes()[..],
+        &key_id.to_be_bytes(),
+        &index.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn signed_payload(room: &Uuid, key_id: u32, index: u32, ciphertext: &[u8]) -> Vec<u8> {
+    [CONTEXT, &associated_data(room, key_id, index), ciphertext].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::identity_key;
+    use crate::crypto::x3dh::tests::bundle;
+
+    struct Member {
+        id: SenderId,
+        identity_key: IdentityKey,
+        session: GroupSession,
+    }
+
+    /// Identity of the member at `index` of a room.
+    fn identity(index: usize) -> SigningKey {
+        SigningKey::from_bytes(&[index as u8 + 1; 32])
+    }
+
+    /// Identity keys of `members`, by user.
+    fn users(members: &[Member]) -> HashMap<Uuid, IdentityKey> {
+        members
+            .iter()
+            .map(|member| (member.id.user, member.identity_key))
+            .collect()
+    }
+
+    /// Members of a room, who exchanged their sender keys.
+    fn room(count: usize) -> (Uuid, Vec<Member>) {
+        let room = Uuid::new_v4();
+        let ids: Vec<(SenderId, IdentityKey)> = (0..count)
+            .map(|index| {
+                let id = SenderId {
+                    user: Uuid::new_v4(),
+                    device: Uuid::new_v4(),
+                };
+                (id, identity_key(&identity(index)))
+            })
+            .collect();
+        let users: HashMap<Uuid, IdentityKey> =
+            ids.iter().map(|(id, key)| (id.user, *key)).collect();
+        let mut members: Vec<Member> = ids
+            .iter()
+            .map(|(id, key)| Member {
+                id: *id,
+                identity_key: *key,
+                session: GroupSession::new(room, *id, users.clone()),
+            })
+            .collect();
+        exchange(&mut members);
+        (room, members)
+    }
+
+    /// Hand the sender key of every member to every other member.
+    fn exchange(members: &mut [Member]) {
+        let distributions: Vec<_> = members
+            .iter()
+            .map(|member| (member.id, member.session.distribution()))
+            .collect();
+        for member in members.iter_mut() {
+            for (sender, distribution) in &distributions {
+                if *sender != member.id {
+                    member
+                        .session
+                        .accept_distribution(*sender, distribution)
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_messages() {
+        let (_, mut members) = room(3);
+        let sender = members[0].id;
+        let messages: Vec<_> = (0..4)
+            .map(|i| members[0].session.encrypt(format!("{i}").as_bytes()))
+            .collect();
+
+        for member in &mut members[1..] {
+            // Out of order, and without a message that never arrives.
+            for i in [3, 0, 2] {
+                assert_eq!(
+                    member.session.decrypt(&sender, &messages[i]).unwrap(),
+                    format!("{i}").as_bytes()
+                );
+            }
+            assert!(matches!(
+                member.session.decrypt(&sender, &messages[2]),
+                Err(CryptoError::Duplicate)
+            ));
+        }
+        // Messages only decrypt as coming from their sender.
+        let other = members[2].id;
+        assert!(matches!(
+            members[1].session.decrypt(&other, &messages[1]),
+            Err(CryptoError::UnknownSenderKey)
+        ));
+    }
+
+    #[test]
+    fn test_envelopes() {
+        let (_, mut members) = room(2);
+        let alice = members[0].id;
+        let envelope = members[0]
+            .session
+            .seal(&Content::Text("hello".to_string()))
+            .unwrap();
+        assert_eq!(envelope.sender_device, alice.device);
+        assert!(!envelope
+            .ciphertext
+            .windows(b"hello".len())
+            .any(|window| window == b"hello"));
+
+        assert!(matches!(
+            members[1].session.open(alice.user, &envelope).unwrap(),
+            Content::Text(text) if text == "hello"
+        ));
+        let mut elsewhere = envelope.clone();
+        elsewhere.room = Uuid::new_v4();
+        assert!(matches!(
+            members[1].session.open(alice.user, &elsewhere),
+            Err(CryptoError::WrongRoom)
+        ));
+        let mut truncated = envelope;
+        truncated.header.pop();
+        assert!(matches!(
+            members[1].session.open(alice.user, &truncated),
+            Err(CryptoError::MalformedEnvelope(_))
+        ));
+    }
+
+    #[test]
+    fn test_members_can_not_forge_messages() {
+        let (room, mut members) = room(2);
+        let alice = members[0].id;
+        let message = members[0].session.encrypt(b"hello");
+
+        // Bob knows the chain of alice, but not her signing key.
+        let distribution = members[1].session.received[&alice][0].clone();
+        let mut chain = distribution.chain.clone();
+        let forged_key = chain.step();
+        let mut forged = GroupMessage {
+            ciphertext: seal(
+                &forged_key,
+                &associated_data(&room, message.key_id, message.index),
+                b"forged",
+            ),
+            ..message.clone()
+        };
+        forged.signature = SigningKey::from_bytes(&[9; 32]).sign(&signed_payload(
+            &room,
+            forged.key_id,
+            forged.index,
+            &forged.ciphertext,
+        ));
+        assert!(matches!(
+            members[1].session.decrypt(&alice, &forged),
+            Err(CryptoError::Undecryptable)
+        ));
+        assert_eq!(
+            members[1].session.decrypt(&alice, &message).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_rotate_when_a_member_is_removed() {
+        let (_, mut members) = room(3);
+        let carol = members.pop().unwrap();
+        let remaining = users(&members);
+        for member in &mut members {
+            let mut recipients = member.session.update_members(remaining.clone());
+            recipients.sort();
+            let mut expected: Vec<Uuid> = remaining.keys().copied().collect();
+            expected.sort();
+            assert_eq!(recipients, expected);
+        }
+        exchange(&mut members);
+
+        let alice = members[0].id;
+        let message = members[0].session.encrypt(b"after carol left");
+        assert_eq!(
+            members[1].session.decrypt(&alice, &message).unwrap(),
+            b"after carol left"
+        );
+        // Carol only has the sender key alice used before.
+        let mut carol_session = carol.session;
+        assert!(matches!(
+            carol_session.decrypt(&alice, &message),
+            Err(CryptoError::UnknownSenderKey)
+        ));
+
+        // Nor do the remaining members accept messages of carol.
+        let from_carol = carol_session.encrypt(b"still here");
+        assert!(matches!(
+            members[1].session.decrypt(&carol.id, &from_carol),
+            Err(CryptoError::UnknownSenderKey)
+        ));
+        assert!(matches!(
+            members[1]
+                .session
+                .accept_distribution(carol.id, &carol_session.distribution()),
+            Err(CryptoError::NotAMember)
+        ));
+    }
+
+    #[test]
+    fn test_new_members_read_from_their_arrival() {
+        let (room, mut members) = room(2);
+        let alice = members[0].id;
+        let before = members[0].session.encrypt(b"before");
+
+        let dave = SenderId {
+            user: Uuid::new_v4(),
+            device: Uuid::new_v4(),
+        };
+        let mut users = users(&members);
+        users.insert(dave.user, identity_key(&identity(3)));
+        let key_id = members[0].session.distribution().key_id;
+        assert_eq!(
+            members[0].session.update_members(users.clone()),
+            vec![dave.user]
+        );
+        // Adding a member does not rotate the sender key.
+        assert_eq!(members[0].session.distribution().key_id, key_id);
+
+        let mut dave_session = GroupSession::new(room, dave, users);
+        dave_session
+            .accept_distribution(alice, &members[0].session.distribution())
+            .unwrap();
+        let after = members[0].session.encrypt(b"after");
+        assert_eq!(dave_session.decrypt(&alice, &after).unwrap(), b"after");
+        assert!(matches!(
+            dave_session.decrypt(&alice, &before),
+            Err(CryptoError::Duplicate)
+        ));
+    }
+
+    #[test]
+    fn test_distribute_over_pairwise_session() {
+        let (room, members) = room(1);
+        let alice_identity = identity(0);
+        let bob_identity = identity(1);
+        let (bundle, signed_prekey, one_time_prekey) = bundle(&bob_identity, true);
+        let mut alice_pairwise = Session::initiate(&alice_identity, &bundle).unwrap();
+
+        let alice = members[0].id;
+        let bob = SenderId {
+            user: Uuid::new_v4(),
+            device: Uuid::new_v4(),
+        };
+        let mut alice_group = members.into_iter().next().unwrap().session;
+        let users = HashMap::from([
+            (alice.user, identity_key(&alice_identity)),
+            (bob.user, identity_key(&bob_identity)),
+        ]);
+        alice_group.update_members(users.clone());
+        let mut bob_group = GroupSession::new(room, bob, users);
+
+        let distribution = alice_group.distribute(&mut alice_pairwise).unwrap();
+        let mut bob_pairwise = Session::respond(
+            &bob_identity,
+            distribution.handshake.as_ref().unwrap(),
+            &signed_prekey,
+            one_time_prekey.as_ref(),
+        )
+        .unwrap();
+        bob_group
+            .receive_distribution(alice, &mut bob_pairwise, &distribution)
+            .unwrap();
+
+        let message = alice_group
+            .encrypt_content(&Content::Text("hello".to_string()))
+            .unwrap();
+        assert!(matches!(
+            bob_group.decrypt_content(&alice, &message).unwrap(),
+            Content::Text(text) if text == "hello"
+        ));
+    }
+
+    #[test]
+    fn test_sender_keys_only_come_from_their_sender() {
+        let (room, mut members) = room(3);
+        let alice = members[0].id;
+        let mallory = members[2].id;
+        let bob_identity = identity(1);
+        let (bundle, signed_prekey, one_time_prekey) = bundle(&bob_identity, true);
+
+        // Mallory hands bob her sender key in the name of alice.
+        let mut mallory_pairwise = Session::initiate(&identity(2), &bundle).unwrap();
+        let forged = members[2]
+            .session
+            .distribute(&mut mallory_pairwise)
+            .unwrap();
+        let mut bob_pairwise = Session::respond(
+            &bob_identity,
+            forged.handshake.as_ref().unwrap(),
+            &signed_prekey,
+            one_time_prekey.as_ref(),
+        )
+        .unwrap();
+        let alice_only = HashMap::from([(alice.user, members[0].identity_key)]);
+        let mut stranger = GroupSession::new(room, members[1].id, alice_only);
+        assert!(matches!(
+            members[1]
+                .session
+                .receive_distribution(alice, &mut bob_pairwise.clone(), &forged),
+            Err(CryptoError::WrongSender)
+        ));
+        assert!(matches!(
+            stranger.receive_distribution(mallory, &mut bob_pairwise.clone(), &forged),
+            Err(CryptoError::NotAMember)
+        ));
+        members[1]
+            .session
+            .receive_distribution(mallory, &mut bob_pairwise, &forged)
+            .unwrap();
+
+        // A new identity key of alice drops the sender keys she handed out
+        // before, and rotates the sender keys of the others.
+        let key_id = members[1].session.distribution().key_id;
+        let mut changed = users(&members);
+        changed.insert(alice.user, identity_key(&identity(9)));
+        let message = members[0].session.encrypt(b"before the change");
+        members[1].session.update_members(changed);
+        assert!(matches!(
+            members[1].session.decrypt(&alice, &message),
+            Err(CryptoError::UnknownSenderKey)
+        ));
+        assert_ne!(members[1].session.distribution().key_id, key_id);
+    }
+}
@@ -0,0 +1,240 @@
+//! End-to-end encryption of messages.
+//!
+//! A [`Session`] starts with X3DH key agreement against the [`PrekeyBundle`]
+//! of the peer, so it can start while the peer is offline, then encrypts
+//! every message with its own key from the Double Ratchet: a leaked key
+//! reveals neither earlier messages nor, once the peer replied, later ones.
+//!
+//! In rooms, a [`GroupSession`] encrypts each message once with the sender
+//! key of the device, which members hand each other over their sessions.
+//!
+//! Users compare their [`SafetyNumber`] to check that the identity keys the
+//! server handed out are really the ones of each other.
+//!
+//! Only ciphertext leaves a device. The server stores and forwards
+//! [`Envelope`]s it can not read.
+//!
+//! [`PrekeyBundle`]: crate::keys::PrekeyBundle
+//! [`Envelope`]: crate::types::Envelope
+
+mod group;
+mod ratchet;
+mod safety;
+mod x3dh;
+
+pub use group::{GroupMessage, GroupSession, SenderId, SenderKeyDistribution};
+pub use ratchet::{EncryptedMessage, Header, Session};
+pub use safety::SafetyNumber;
+pub use x3dh::{Handshake, PrekeyPair};
+
+use crate::auth::{AuthError, SigningKey};
+use crate::keys::PrekeyId;
+use crate::types::IdentityKey;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Application specific info of the derivation of message keys.
+const MESSAGE_INFO: &[u8] = b"e-charlar message keys v1";
+
+/// Maximum number of messages a single message may skip in its chain.
+const MAX_SKIP: u32 = 1000;
+
+/// Maximum number of keys of skipped messages kept per session. The oldest
+/// go first.
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+pub type Result<T> = std::result::Result<T, CryptoError>;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    #[error("malformed identity key")]
+    MalformedKey,
+    #[error("signed prekey does not match the identity key")]
+    BadSignature,
+    #[error("key agreement with a low order key")]
+    WeakKey,
+    #[error("handshake expects prekey {0}")]
+    WrongPrekey(PrekeyId),
+    #[error("a session can not send before it received a message")]
+    CanNotSend,
+    #[error("message could not be authenticated")]
+    Undecryptable,
+    #[error("message was already decrypted, or precedes the key")]
+    Duplicate,
+    #[error("message skips too many messages")]
+    TooManySkipped,
+    #[error("no sender key of this device decrypts the message")]
+    UnknownSenderKey,
+    #[error("sender key is for another room")]
+    WrongRoom,
+    #[error("sender is not a member of the room")]
+    NotAMember,
+    #[error("sender key arrived from another identity than its sender's")]
+    WrongSender,
+    #[error("malformed content: {0}")]
+    MalformedContent(String),
+    #[error("malformed envelope: {0}")]
+    MalformedEnvelope(String),
+    #[error("malformed safety number")]
+    MalformedSafetyNumber,
+}
+
+impl From<AuthError> for CryptoError {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::MalformedKey => CryptoError::MalformedKey,
+            AuthError::BadSignature => CryptoError::BadSignature,
+        }
+    }
+}
+
+/// X25519 secret of an identity, matching [`identity_agreement_key`].
+fn identity_secret(identity: &SigningKey) -> StaticSecret {
+    StaticSecret::from(identity.to_scalar_bytes())
+}
+
+/// X25519 form of an Ed25519 identity key, used in key agreement.
+pub fn identity_agreement_key(identity_key: &IdentityKey) -> Result<PublicKey> {
+    let key = ed25519_dalek::VerifyingKey::from_bytes(identity_key)
+        .map_err(|_| CryptoError::MalformedKey)?;
+    Ok(PublicKey::from(key.to_montgomery().to_bytes()))
+}
+
+/// Diffie-Hellman between `secret` and `public`, refusing low order keys
+/// that would make the output predictable.
+fn diffie_hellman(secret: &StaticSecret, public: &[u8; 32]) -> Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+        return Err(CryptoError::WeakKey);
+    }
+    Ok(shared.to_bytes())
+}
+
+/// Symmetric ratchet deriving one key per message.
+#[derive(Clone, Serialize, Deserialize)]
+struct Chain {
+    key: [u8; 32],
+    /// Index of the next message of the chain.
+    index: u32,
+}
+
+impl Chain {
+    fn new(key: [u8; 32]) -> Self {
+        Self { key, index: 0 }
+    }
+
+    /// Key of the next message, moving the chain past it.
+    fn step(&mut self) -> [u8; 32] {
+        let message_key = hmac(&self.key, &[1]);
+        self.key = hmac(&self.key, &[2]);
+        self.index += 1;
+        message_key
+    }
+}
+
+fn hmac(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Encrypt `plaintext` with a message key, authenticating `aad` along.
+fn seal(message_key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("ChaCha20Poly1305 encrypts any message that fits in memory")
+}
+
+fn open(message_key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .decrypt(
+            &nonce,
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| CryptoError::Undecryptable)
+}
+
+/// Cipher and nonce of a message key. Each key encrypts a single message, so
+/// the nonce can derive from it too.
+fn message_cipher(message_key: &[u8; 32]) -> (ChaCha20Poly1305, Nonce) {
+    let mut keys = [0; 44];
+    Hkdf::<Sha256>::new(None, message_key)
+        .expand(MESSAGE_INFO, &mut keys)
+        .expect("44 bytes is a valid HKDF-SHA256 output length");
+    (
+        ChaCha20Poly1305::new(Key::from_slice(&keys[..32])),
+        *Nonce::from_slice(&keys[32..]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::identity_key;
+
+    #[test]
+    fn test_identity_agreement_key() {
+        let identity = SigningKey::from_bytes(&[7; 32]);
+        assert_eq!(
+            identity_agreement_key(&identity_key(&identity)),
+            Ok(PublicKey::from(&identity_secret(&identity)))
+        );
+
+        // Both ends of an agreement between identities derive the same output.
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let other_public = identity_agreement_key(&identity_key(&other)).unwrap();
+        let public = identity_agreement_key(&identity_key(&identity)).unwrap();
+        assert_eq!(
+            diffie_hellman(&identity_secret(&identity), other_public.as_bytes()),
+            diffie_hellman(&identity_secret(&other), public.as_bytes())
+        );
+        assert_eq!(
+            diffie_hellman(&identity_secret(&identity), &[0; 32]),
+            Err(CryptoError::WeakKey)
+        );
+    }
+
+    /// The Diffie-Hellman of RFC 7748, section 6.1.
+    #[test]
+    fn test_diffie_hellman_vector() {
+        let alice = StaticSecret::from(bytes(
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+        ));
+        let bob = StaticSecret::from(bytes(
+            "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb",
+        ));
+        let alice_public =
+            bytes("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        let bob_public = bytes("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        assert_eq!(PublicKey::from(&alice).to_bytes(), alice_public);
+        assert_eq!(PublicKey::from(&bob).to_bytes(), bob_public);
+
+        let shared = bytes("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(diffie_hellman(&alice, &bob_public), Ok(shared));
+        assert_eq!(diffie_hellman(&bob, &alice_public), Ok(shared));
+    }
+
+    fn bytes(hex: &str) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+}
@@ -0,0 +1,493 @@
+//! The Double Ratchet, as specified by Signal.
+//!
+//! Every message is encrypted with a key of its own, taken from a sending
+//! chain. Each time the peer replies with a new ratchet key, both ends turn
+//! the ratchet and start new chains from a fresh Diffie-Hellman output.
+//!
+//! Messages may arrive late, out of order or not at all: the keys of skipped
+//! messages are kept, up to a limit, until their message shows up.
+
+use super::x3dh::{self, Handshake, PrekeyPair};
+use super::{diffie_hellman, open, seal, Chain, CryptoError, Result, MAX_SKIP, MAX_SKIPPED_KEYS};
+use crate::auth::SigningKey;
+use crate::keys::{AgreementKey, PrekeyBundle};
+use crate::types::{Content, IdentityKey};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Application specific info of the root key derivation.
+const ROOT_INFO: &[u8] = b"e-charlar ratchet v1";
+
+/// Public part of the ratchet state, sent in the clear with every message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    /// Current ratchet key of the sender.
+    pub ratchet_key: AgreementKey,
+    /// Number of messages in the previous sending chain of the sender.
+    pub previous_chain: u32,
+    /// Index of the message in its sending chain.
+    pub index: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; 40] {
+        let mut bytes = [0; 40];
+        bytes[..32].copy_from_slice(&self.ratchet_key);
+        bytes[32..36].copy_from_slice(&self.previous_chain.to_be_bytes());
+        bytes[36..].copy_from_slice(&self.index.to_be_bytes());
+        bytes
+    }
+}
+
+/// A message as it leaves the device of its sender.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedMessage {
+    /// Set until the sender receives a reply, so the peer can start the
+    /// session from any of the first messages.
+    pub handshake: Option<Handshake>,
+    pub header: Header,
+    pub ciphertext: Vec<u8>,
+}
+
+/// An end-to-end encrypted session with one device of a peer.
+///
+/// Sessions serialize, so clients can persist them between runs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    remote_identity_key: IdentityKey,
+    associated_data: Vec<u8>,
+    root_key: [u8; 32],
+    ratchet_secret: StaticSecret,
+    remote_ratchet_key: Option<AgreementKey>,
+    sending: Option<Chain>,
+    receiving: Option<Chain>,
+    previous_chain: u32,
+    skipped: VecDeque<SkippedKey>,
+    handshake: Option<Handshake>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    ratchet_key: AgreementKey,
+    index: u32,
+    message_key: [u8; 32],
+}
+
+impl Session {
+    /// Start a session with the owner of `bundle`. It can send right away.
+    pub fn initiate(identity: &SigningKey, bundle: &PrekeyBundle) -> Result<Self> {
+        Self::initiate_with(
+            identity,
+            bundle,
+            &StaticSecret::random(),
+            StaticSecret::random(),
+        )
+    }
+
+    fn initiate_with(
+        identity: &SigningKey,
+        bundle: &PrekeyBundle,
+        ephemeral: &StaticSecret,
+        ratchet_secret: StaticSecret,
+    ) -> Result<Self> {
+        let (agreement, handshake) = x3dh::initiate(identity, bundle, ephemeral)?;
+        let remote_ratchet_key = bundle.signed_prekey.key;
+        let (root_key, sending) = kdf_root(
+            &agreement.secret,
+            &diffie_hellman(&ratchet_secret, &remote_ratchet_key)?,
+        );
+        Ok(Self {
+            remote_identity_key: bundle.identity_key,
+            associated_data: agreement.associated_data,
+            root_key,
+            ratchet_secret,
+            remote_ratchet_key: Some(remote_ratchet_key),
+            sending: Some(Chain::new(sending)),
+            receiving: None,
+            previous_chain: 0,
+            skipped: VecDeque::new(),
+            handshake: Some(handshake),
+        })
+    }
+
+    /// Accept a session started with `handshake`, using the prekeys it names.
+    ///
+    /// The session can send once it decrypted a message. The one-time
+    /// prekey must then be forgotten, so the session can not be started
+    /// twice.
+    pub fn respond(
+        identity: &SigningKey,
+        handshake: &Handshake,
+        signed_prekey: &PrekeyPair,
+        one_time_prekey: Option<&PrekeyPair>,
+    ) -> Result<Self> {
+        let agreement = x3dh::respond(identity, handshake, signed_prekey, one_time_prekey)?;
+        Ok(Self {
+            remote_identity_key: handshake.identity_key,
+            associated_data: agreement.associated_data,
+            root_key: agreement.secret,
+            ratchet_secret: signed_prekey.secret().clone(),
+            remote_ratchet_key: None,
+            sending: None,
+            receiving: None,
+            previous_chain: 0,
+            skipped: VecDeque::new(),
+            handshake: None,
+        })
+    }
+
+    /// Identity key of the peer, as agreed when the session started.
+    pub fn remote_identity_key(&self) -> &IdentityKey {
+        &self.remote_identity_key
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<EncryptedMessage> {
+        let chain = self.sending.as_mut().ok_or(CryptoError::CanNotSend)?;
+        let header = Header {
+            ratchet_key: PublicKey::from(&self.ratchet_secret).to_bytes(),
+            previous_chain: self.previous_chain,
+            index: chain.index,
+        };
+        let message_key = chain.step();
+        let ciphertext = seal(&message_key, &self.header_data(&header), plaintext);
+        Ok(EncryptedMessage {
+            handshake: self.handshake.clone(),
+            header,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt a message of the peer. The session is left unchanged when it
+    /// fails.
+    pub fn decrypt(&mut self, message: &EncryptedMessage) -> Result<Vec<u8>> {
+        let mut next = self.clone();
+        let plaintext = next.ratchet_decrypt(message)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    pub fn encrypt_content(&mut self, content: &Content) -> Result<EncryptedMessage> {
+        let plaintext = bincode::serialize(content)
+            .map_err(|e| CryptoError::MalformedContent(e.to_string()))?;
+        self.encrypt(&plaintext)
+    }
+
+    pub fn decrypt_content(&mut self, message: &EncryptedMessage) -> Result<Content> {
+        let plaintext = self.decrypt(message)?;
+        bincode::deserialize(&plaintext).map_err(|e| CryptoError::MalformedContent(e.to_string()))
+    }
+
+    fn ratchet_decrypt(&mut self, message: &EncryptedMessage) -> Result<Vec<u8>> {
+        let header = &message.header;
+        let message_key = match self.skipped.iter().position(|skipped| {
+            skipped.ratchet_key == header.ratchet_key && skipped.index == header.index
+        }) {
+            Some(position) => self.skipped.remove(position).unwrap().message_key,
+            None => {
+                if self.remote_ratchet_key != Some(header.ratchet_key) {
+                    self.skip(header.previous_chain)?;
+                    self.turn(header.ratchet_key)?;
+                }
+                self.skip(header.index)?;
+                let chain = self
+                    .receiving
+                    .as_mut()
+                    .expect("turning the ratchet starts a receiving chain");
+                if header.index < chain.index {
+                    return Err(CryptoError::Duplicate);
+                }
+                chain.step()
+            }
+        };
+
+        let plaintext = open(&message_key, &self.header_data(header), &message.ciphertext)?;
+        // The peer got a message of this session, it no longer needs the
+        // handshake.
+        self.handshake = None;
+        Ok(plaintext)
+    }
+
+    /// Keep the keys of the messages of the receiving chain before `until`.
+    fn skip(&mut self, until: u32) -> Result<()> {
+        let Some(chain) = self.receiving.as_mut() else {
+            return Ok(());
+        };
+        if until > chain.index.saturating_add(MAX_SKIP) {
+            return Err(CryptoError::TooManySkipped);
+        }
+        let ratchet_key = self
+            .remote_ratchet_key
+            .expect("receiving chains come with a remote ratchet key");
+        while chain.index < until {
+            let index = chain.index;
+            self.skipped.push_back(SkippedKey {
+                ratchet_key,
+                index,
+                message_key: chain.step(),
+            });
+            if self.skipped.len() > MAX_SKIPPED_KEYS {
+                self.skipped.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    /// Start new chains for the new ratchet key of the peer.
+    fn turn(&mut self, remote_ratchet_key: AgreementKey) -> Result<()> {
+        self.previous_chain = self.sending.as_ref().map_or(0, |chain| chain.index);
+        self.remote_ratchet_key = Some(remote_ratchet_key);
+
+        let (root_key, receiving) = kdf_root(
+            &self.root_key,
+            &diffie_hellman(&self.ratchet_secret, &remote_ratchet_key)?,
+        );
+        self.ratchet_secret = StaticSecret::random();
+        let (root_key, sending) = kdf_root(
+            &root_key,
+            &diffie_hellman(&self.ratchet_secret, &remote_ratchet_key)?,
+        );
+        self.root_key = root_key;
+        self.receiving = Some(Chain::new(receiving));
+        self.sending = Some(Chain::new(sending));
+        Ok(())
+    }
+
+    /// Data authenticated along a message: the identities of the session,
+    /// then the header.
+    fn header_data(&self, header: &Header) -> Vec<u8> {
+        [&self.associated_data[..], &header.encode()].concat()
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("remote_identity_key", &self.remote_identity_key)
+            .finish_non_exhaustive()
+    }
+}
+
+/// New root key and chain key from the root key and a ratchet output.
+fn kdf_root(root_key: &[u8; 32], output: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut keys = [0; 64];
+    Hkdf::<Sha256>::new(Some(root_key), output)
+        .expand(ROOT_INFO, &mut keys)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    let (root_key, chain_key) = keys.split_at(32);
+    (root_key.try_into().unwrap(), chain_key.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::x3dh::tests::{bundle, hex};
+
+    /// A session of alice with bob, before bob received any message.
+    fn start(one_time: bool) -> (Session, SigningKey, PrekeyPair, Option<PrekeyPair>) {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let bob = SigningKey::from_bytes(&[2; 32]);
+        let (bundle, signed_prekey, one_time_prekey) = bundle(&bob, one_time);
+        let session = Session::initiate(&alice, &bundle).unwrap();
+        (session, bob, signed_prekey, one_time_prekey)
+    }
+
+    /// Sessions of alice and bob, after bob received the first message.
+    fn pair() -> (Session, Session) {
+        let (mut alice, bob, signed_prekey, one_time_prekey) = start(true);
+        let first = alice.encrypt(b"hello").unwrap();
+        let handshake = first.handshake.as_ref().unwrap();
+        let mut bob =
+            Session::respond(&bob, handshake, &signed_prekey, one_time_prekey.as_ref()).unwrap();
+        assert_eq!(bob.decrypt(&first).unwrap(), b"hello");
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_conversation() {
+        let (mut alice, mut bob) = pair();
+        assert_eq!(
+            bob.remote_identity_key(),
+            &crate::auth::identity_key(&SigningKey::from_bytes(&[1; 32]))
+        );
+        for turn in 0..3 {
+            for i in 0..turn + 1 {
+                let text = format!("bob {turn} {i}");
+                let message = bob.encrypt(text.as_bytes()).unwrap();
+                assert_eq!(alice.decrypt(&message).unwrap(), text.as_bytes());
+            }
+            let text = format!("alice {turn}");
+            let message = alice.encrypt(text.as_bytes()).unwrap();
+            assert_ne!(message.ciphertext, text.as_bytes());
+            assert_eq!(bob.decrypt(&message).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_responder_sends_after_receiving() {
+        let (_, bob, signed_prekey, _) = start(false);
+        let handshake = Handshake {
+            identity_key: crate::auth::identity_key(&SigningKey::from_bytes(&[1; 32])),
+            ephemeral_key: PrekeyPair::generate(3).public_key(),
+            signed_prekey: 1,
+            one_time_prekey: None,
+        };
+        let mut session = Session::respond(&bob, &handshake, &signed_prekey, None).unwrap();
+        assert!(matches!(
+            session.encrypt(b"hello"),
+            Err(CryptoError::CanNotSend)
+        ));
+    }
+
+    #[test]
+    fn test_handshake_until_reply() {
+        let (mut alice, bob, signed_prekey, one_time_prekey) = start(true);
+        let first = alice.encrypt(b"first").unwrap();
+        let second = alice.encrypt(b"second").unwrap();
+        assert_eq!(first.handshake, second.handshake);
+
+        // Bob may start the session from any message carrying the handshake.
+        let mut bob = Session::respond(
+            &bob,
+            second.handshake.as_ref().unwrap(),
+            &signed_prekey,
+            one_time_prekey.as_ref(),
+        )
+        .unwrap();
+        assert_eq!(bob.decrypt(&second).unwrap(), b"second");
+        assert_eq!(bob.decrypt(&first).unwrap(), b"first");
+
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert_eq!(reply.handshake, None);
+        alice.decrypt(&reply).unwrap();
+        assert_eq!(alice.encrypt(b"third").unwrap().handshake, None);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let (mut alice, mut bob) = pair();
+        let sent: Vec<_> = (0..5)
+            .map(|i| alice.encrypt(format!("{i}").as_bytes()).unwrap())
+            .collect();
+        for i in [4, 0, 2] {
+            assert_eq!(bob.decrypt(&sent[i]).unwrap(), format!("{i}").as_bytes());
+        }
+
+        // The ratchet turns while messages 1 and 3 are still on their way.
+        let reply = bob.encrypt(b"reply").unwrap();
+        alice.decrypt(&reply).unwrap();
+        let later = alice.encrypt(b"later").unwrap();
+        alice.encrypt(b"lost").unwrap();
+        let last = alice.encrypt(b"last").unwrap();
+        // Five messages, after the one starting the session.
+        assert_eq!(later.header.previous_chain, 6);
+        assert_eq!(bob.decrypt(&last).unwrap(), b"last");
+        assert_eq!(bob.decrypt(&later).unwrap(), b"later");
+        for i in [3, 1] {
+            assert_eq!(bob.decrypt(&sent[i]).unwrap(), format!("{i}").as_bytes());
+        }
+
+        // Replayed messages, skipped or not, do not decrypt twice.
+        for message in [&later, &last] {
+            assert!(matches!(bob.decrypt(message), Err(CryptoError::Duplicate)));
+        }
+        for message in &sent {
+            assert!(bob.decrypt(message).is_err());
+        }
+    }
+
+    #[test]
+    fn test_too_many_skipped() {
+        let (mut alice, mut bob) = pair();
+        for _ in 0..MAX_SKIP {
+            alice.encrypt(b"lost").unwrap();
+        }
+        let reachable = alice.encrypt(b"reachable").unwrap();
+        let too_far = alice.encrypt(b"too far").unwrap();
+
+        assert!(matches!(
+            bob.clone().decrypt(&too_far),
+            Err(CryptoError::TooManySkipped)
+        ));
+        assert_eq!(bob.decrypt(&reachable).unwrap(), b"reachable");
+        assert_eq!(bob.decrypt(&too_far).unwrap(), b"too far");
+    }
+
+    #[test]
+    fn test_tampered_message() {
+        let (mut alice, mut bob) = pair();
+        let message = alice.encrypt(b"hello").unwrap();
+
+        let mut tampered = message.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(matches!(
+            bob.decrypt(&tampered),
+            Err(CryptoError::Undecryptable)
+        ));
+        let mut tampered = message.clone();
+        tampered.header.previous_chain += 1;
+        assert!(matches!(
+            bob.decrypt(&tampered),
+            Err(CryptoError::Undecryptable)
+        ));
+        let mut forged = message.clone();
+        forged.header.ratchet_key = PrekeyPair::generate(3).public_key();
+        assert!(matches!(
+            bob.decrypt(&forged),
+            Err(CryptoError::Undecryptable)
+        ));
+
+        // Failures leave the session as it was.
+        assert_eq!(bob.decrypt(&message).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_persisted_session() {
+        let (mut alice, bob) = pair();
+        let mut bob: Session = bincode::deserialize(&bincode::serialize(&bob).unwrap()).unwrap();
+        let message = alice
+            .encrypt_content(&Content::Text("hi".to_string()))
+            .unwrap();
+        assert!(matches!(
+            bob.decrypt_content(&message).unwrap(),
+            Content::Text(text) if text == "hi"
+        ));
+    }
+
+    #[test]
+    fn test_vector() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let bob = SigningKey::from_bytes(&[2; 32]);
+        let signed_prekey = PublicKey::from(&StaticSecret::from([3; 32])).to_bytes();
+        let bundle = PrekeyBundle {
+            user: uuid::Uuid::nil(),
+            device: uuid::Uuid::nil(),
+            identity_key: crate::auth::identity_key(&bob),
+            signed_prekey: crate::keys::SignedPrekey::new(1, signed_prekey, &bob),
+            one_time_prekey: None,
+        };
+
+        let mut session = Session::initiate_with(
+            &alice,
+            &bundle,
+            &StaticSecret::from([5; 32]),
+            StaticSecret::from([6; 32]),
+        )
+        .unwrap();
+        let message = session.encrypt(b"hello").unwrap();
+        // Computed apart from this crate, following the Double Ratchet
+        // specification over the X3DH secret of `x3dh::tests::test_vector`,
+        // with the primitives of OpenSSL.
+        assert_eq!(
+            hex(&message.header.ratchet_key),
+            "f5b2d6e60f9477e310c2982daaa6c9136c108a1777c5947e448fa37d68174557"
+        );
+        assert_eq!(
+            hex(&message.ciphertext),
+            "6b063d1db6dbba965ff94f42cc81e7d2f972d59694"
+        );
+    }
+}
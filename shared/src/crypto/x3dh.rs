@@ -0,0 +1,268 @@
+//! X3DH key agreement, as specified by Signal.
+//!
+//! The initiator combines its identity and a fresh ephemeral key with the
+//! identity, signed prekey and, when one is left, one-time prekey of the
+//! responder. The responder derives the same secret from the [`Handshake`]
+//! once it comes online.
+
+use super::{diffie_hellman, identity_agreement_key, identity_secret, CryptoError, Result};
+use crate::auth::{identity_key, SigningKey};
+use crate::keys::{AgreementKey, OneTimePrekey, PrekeyBundle, PrekeyId, SignedPrekey};
+use crate::types::IdentityKey;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Application specific info of the key derivation.
+const INFO: &[u8] = b"e-charlar X3DH v1";
+
+/// A prekey with its private half, kept by the device that published it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrekeyPair {
+    pub id: PrekeyId,
+    secret: StaticSecret,
+}
+
+impl PrekeyPair {
+    pub fn generate(id: PrekeyId) -> Self {
+        Self {
+            id,
+            secret: StaticSecret::random(),
+        }
+    }
+
+    pub fn public_key(&self) -> AgreementKey {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// This prekey published as the signed prekey of `identity`.
+    pub fn signed(&self, identity: &SigningKey) -> SignedPrekey {
+        SignedPrekey::new(self.id, self.public_key(), identity)
+    }
+
+    /// This prekey published as a one-time prekey.
+    pub fn one_time(&self) -> OneTimePrekey {
+        OneTimePrekey {
+            id: self.id,
+            key: self.public_key(),
+        }
+    }
+
+    pub(super) fn secret(&self) -> &StaticSecret {
+        &self.secret
+    }
+}
+
+impl fmt::Debug for PrekeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrekeyPair")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What the responder needs to derive the secret of a session, sent along
+/// the first messages of the initiator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub identity_key: IdentityKey,
+    pub ephemeral_key: AgreementKey,
+    pub signed_prekey: PrekeyId,
+    pub one_time_prekey: Option<PrekeyId>,
+}
+
+/// Outcome of a key agreement.
+pub(super) struct Agreement {
+    pub(super) secret: [u8; 32],
+    /// Identity keys of the initiator and the responder, bound to every
+    /// message of the session.
+    pub(super) associated_data: Vec<u8>,
+}
+
+/// Agree on a secret with the owner of `bundle`, using `ephemeral` once.
+pub(super) fn initiate(
+    identity: &SigningKey,
+    bundle: &PrekeyBundle,
+    ephemeral: &StaticSecret,
+) -> Result<(Agreement, Handshake)> {
+    bundle.verify()?;
+    let remote_identity = identity_agreement_key(&bundle.identity_key)?;
+    let signed_prekey = &bundle.signed_prekey.key;
+
+    let mut outputs = vec![
+        diffie_hellman(&identity_secret(identity), signed_prekey)?,
+        diffie_hellman(ephemeral, remote_identity.as_bytes())?,
+        diffie_hellman(ephemeral, signed_prekey)?,
+    ];
+    if let Some(one_time_prekey) = &bundle.one_time_prekey {
+        outputs.push(diffie_hellman(ephemeral, &one_time_prekey.key)?);
+    }
+
+    let local_identity = identity_key(identity);
+    let agreement = Agreement {
+        secret: derive(&outputs),
+        associated_data: [local_identity, bundle.identity_key].concat(),
+    };
+    let handshake = Handshake {
+        identity_key: local_identity,
+        ephemeral_key: PublicKey::from(ephemeral).to_bytes(),
+        signed_prekey: bundle.signed_prekey.id,
+        one_time_prekey: bundle.one_time_prekey.as_ref().map(|prekey| prekey.id),
+    };
+    Ok((agreement, handshake))
+}
+
+/// Derive the secret of `handshake` with the prekeys it names.
+pub(super) fn respond(
+    identity: &SigningKey,
+    handshake: &Handshake,
+    signed_prekey: &PrekeyPair,
+    one_time_prekey: Option<&PrekeyPair>,
+) -> Result<Agreement> {
+    if handshake.signed_prekey != signed_prekey.id {
+        return Err(CryptoError::WrongPrekey(handshake.signed_prekey));
+    }
+    let one_time_prekey = match (handshake.one_time_prekey, one_time_prekey) {
+        (None, _) => None,
+        (Some(id), Some(prekey)) if prekey.id == id => Some(prekey),
+        (Some(id), _) => return Err(CryptoError::WrongPrekey(id)),
+    };
+    let remote_identity = identity_agreement_key(&handshake.identity_key)?;
+    let ephemeral = &handshake.ephemeral_key;
+
+    let mut outputs = vec![
+        diffie_hellman(signed_prekey.secret(), remote_identity.as_bytes())?,
+        diffie_hellman(&identity_secret(identity), ephemeral)?,
+        diffie_hellman(signed_prekey.secret(), ephemeral)?,
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        outputs.push(diffie_hellman(one_time_prekey.secret(), ephemeral)?);
+    }
+
+    Ok(Agreement {
+        secret: derive(&outputs),
+        associated_data: [handshake.identity_key, identity_key(identity)].concat(),
+    })
+}
+
+/// HKDF-SHA256 over the Diffie-Hellman outputs, prefixed with 32 `0xff`
+/// bytes as the specification asks for X25519.
+fn derive(outputs: &[[u8; 32]]) -> [u8; 32] {
+    let mut input = vec![0xff; 32];
+    for output in outputs {
+        input.extend_from_slice(output);
+    }
+    let mut secret = [0; 32];
+    Hkdf::<Sha256>::new(Some(&[0; 32]), &input)
+        .expand(INFO, &mut secret)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    secret
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// The prekeys of a responder, and the bundle it publishes with them.
+    pub(in crate::crypto) fn bundle(
+        identity: &SigningKey,
+        one_time: bool,
+    ) -> (PrekeyBundle, PrekeyPair, Option<PrekeyPair>) {
+        let signed_prekey = PrekeyPair::generate(1);
+        let one_time_prekey = one_time.then(|| PrekeyPair::generate(2));
+        let bundle = PrekeyBundle {
+            user: uuid::Uuid::new_v4(),
+            device: uuid::Uuid::new_v4(),
+            identity_key: identity_key(identity),
+            signed_prekey: signed_prekey.signed(identity),
+            one_time_prekey: one_time_prekey.as_ref().map(PrekeyPair::one_time),
+        };
+        (bundle, signed_prekey, one_time_prekey)
+    }
+
+    #[test]
+    fn test_agreement() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let bob = SigningKey::from_bytes(&[2; 32]);
+        for one_time in [true, false] {
+            let (bundle, signed_prekey, one_time_prekey) = bundle(&bob, one_time);
+            let (sent, handshake) = initiate(&alice, &bundle, &StaticSecret::random()).unwrap();
+            assert_eq!(handshake.one_time_prekey.is_some(), one_time);
+
+            let received =
+                respond(&bob, &handshake, &signed_prekey, one_time_prekey.as_ref()).unwrap();
+            assert_eq!(sent.secret, received.secret);
+            assert_eq!(sent.associated_data, received.associated_data);
+        }
+    }
+
+    #[test]
+    fn test_refuse_wrong_keys() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let bob = SigningKey::from_bytes(&[2; 32]);
+        let (mut bundle, signed_prekey, one_time_prekey) = bundle(&bob, true);
+
+        let (_, handshake) = initiate(&alice, &bundle, &StaticSecret::random()).unwrap();
+        assert!(matches!(
+            respond(&bob, &handshake, &signed_prekey, None),
+            Err(CryptoError::WrongPrekey(2))
+        ));
+        assert!(matches!(
+            respond(
+                &bob,
+                &handshake,
+                one_time_prekey.as_ref().unwrap(),
+                one_time_prekey.as_ref()
+            ),
+            Err(CryptoError::WrongPrekey(1))
+        ));
+
+        // A prekey substituted by the server is not signed by the identity.
+        bundle.signed_prekey.key = PrekeyPair::generate(1).public_key();
+        assert!(matches!(
+            initiate(&alice, &bundle, &StaticSecret::random()),
+            Err(CryptoError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn test_vector() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let bob = SigningKey::from_bytes(&[2; 32]);
+        let signed_prekey = PrekeyPair {
+            id: 1,
+            secret: StaticSecret::from([3; 32]),
+        };
+        let one_time_prekey = PrekeyPair {
+            id: 2,
+            secret: StaticSecret::from([4; 32]),
+        };
+        let bundle = PrekeyBundle {
+            user: uuid::Uuid::nil(),
+            device: uuid::Uuid::nil(),
+            identity_key: identity_key(&bob),
+            signed_prekey: signed_prekey.signed(&bob),
+            one_time_prekey: Some(one_time_prekey.one_time()),
+        };
+
+        let (agreement, handshake) =
+            initiate(&alice, &bundle, &StaticSecret::from([5; 32])).unwrap();
+        // Computed apart from this crate, following the X3DH specification with
+        // the X25519, Ed25519 and HKDF-SHA256 of OpenSSL. The specification
+        // publishes no vectors, and these depend on `INFO`.
+        assert_eq!(
+            hex(&agreement.secret),
+            "5b3d6ecfdce83422cf9924b571c7ceb394ebc378ce35e666b39700e8761895a3"
+        );
+        assert_eq!(
+            hex(&handshake.ephemeral_key),
+            "50a61409b1ddd0325e9b16b700e719e9772c07000b1bd7786e907c653d20495d"
+        );
+    }
+
+    pub(in crate::crypto) fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
@@ -0,0 +1,120 @@
+//! Prekeys users publish so peers can start end-to-end encrypted sessions
+//! with them while they are offline.
+//!
+//! A user owns one long-term Ed25519 [`IdentityKey`], which also serves as
+//! its X25519 identity in key agreement once converted to its Montgomery
+//! form. Besides it, the server stores for each device of a user, as each
+//! device holds sessions of its own:
+//!
+//! - one [`SignedPrekey`], an X25519 key rotated from time to time and signed
+//!   with the identity key so the server can not substitute it,
+//! - a pool of [`OneTimePrekey`]s, each handed out in at most one
+//!   [`PrekeyBundle`].
+
+use crate::auth::{AuthError, Signature, SigningKey};
+use crate::types::IdentityKey;
+use ed25519_dalek::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Public X25519 key used in key agreement.
+pub type AgreementKey = [u8; 32];
+
+/// Identifier chosen by the client for a prekey, unique among the prekeys of
+/// the same kind of its user.
+pub type PrekeyId = u32;
+
+/// Domain separation tag, so a signature over a prekey can not be mistaken
+/// for a signature over anything else made with the identity key.
+const CONTEXT: &[u8] = b"e-charlar signed prekey v1";
+
+/// Medium-term prekey, signed with the identity key of its user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedPrekey {
+    pub id: PrekeyId,
+    pub key: AgreementKey,
+    pub signature: Signature,
+}
+
+impl SignedPrekey {
+    pub fn new(id: PrekeyId, key: AgreementKey, identity: &SigningKey) -> Self {
+        Self {
+            id,
+            key,
+            signature: identity.sign(&Self::payload(id, &key)),
+        }
+    }
+
+    /// Check that the owner of `identity_key` signed this prekey.
+    pub fn verify(&self, identity_key: &IdentityKey) -> Result<(), AuthError> {
+        ed25519_dalek::VerifyingKey::from_bytes(identity_key)
+            .map_err(|_| AuthError::MalformedKey)?
+            .verify(&Self::payload(self.id, &self.key), &self.signature)
+            .map_err(|_| AuthError::BadSignature)
+    }
+
+    fn payload(id: PrekeyId, key: &AgreementKey) -> Vec<u8> {
+        [CONTEXT, &id.to_be_bytes(), key].concat()
+    }
+}
+
+/// Single-use prekey. The server deletes it once handed out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OneTimePrekey {
+    pub id: PrekeyId,
+    pub key: AgreementKey,
+}
+
+/// What a peer needs to start a session with `device` of `user`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrekeyBundle {
+    pub user: Uuid,
+    pub device: Uuid,
+    pub identity_key: IdentityKey,
+    pub signed_prekey: SignedPrekey,
+    /// `None` once the user ran out of one-time prekeys.
+    pub one_time_prekey: Option<OneTimePrekey>,
+}
+
+impl PrekeyBundle {
+    /// Check that the signed prekey belongs to the identity of the bundle.
+    ///
+    /// Clients must check this before using a bundle, and should compare
+    /// `identity_key` with the one they know for `user`.
+    pub fn verify(&self) -> Result<(), AuthError> {
+        self.signed_prekey.verify(&self.identity_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::identity_key;
+
+    #[test]
+    fn test_verify_signed_prekey() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let prekey = SignedPrekey::new(1, [3; 32], &key);
+        assert_eq!(prekey.verify(&identity_key(&key)), Ok(()));
+
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert_eq!(
+            prekey.verify(&identity_key(&other)),
+            Err(AuthError::BadSignature)
+        );
+
+        let substituted = SignedPrekey {
+            key: [4; 32],
+            ..prekey.clone()
+        };
+        assert_eq!(
+            substituted.verify(&identity_key(&key)),
+            Err(AuthError::BadSignature)
+        );
+        let renumbered = SignedPrekey { id: 2, ..prekey };
+        assert_eq!(
+            renumbered.verify(&identity_key(&key)),
+            Err(AuthError::BadSignature)
+        );
+    }
+}
@@ -1 +1,5 @@
+pub mod auth;
+pub mod crypto;
+pub mod keys;
+pub mod protocol;
 pub mod types;
@@ -0,0 +1,235 @@
+use crate::protocol::Frame;
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Default upper bound for the size of a single frame payload, 8 MiB.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Size of the big-endian `u32` length prefix.
+const HEADER_LENGTH: usize = 4;
+
+/// Error returned by [`FrameCodec`].
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("frame of {length} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge { length: usize, max: usize },
+    #[error("malformed frame: {0}")]
+    Decode(#[source] bincode::Error),
+    #[error("failed to encode frame: {0}")]
+    Encode(#[source] bincode::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Length-prefixed bincode codec for [`Frame`]s.
+///
+/// Each frame is written as a big-endian `u32` payload length followed by the
+/// bincode encoding of the frame. Frames whose payload exceeds
+/// `max_frame_length` are rejected in both directions.
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    max_frame_length: usize,
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    pub fn with_max_frame_length(max_frame_length: usize) -> Self {
+        Self {
+            max_frame_length: max_frame_length.min(u32::MAX as usize),
+        }
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, CodecError> {
+        if src.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_LENGTH];
+        header.copy_from_slice(&src[..HEADER_LENGTH]);
+        let length = u32::from_be_bytes(header) as usize;
+        if length > self.max_frame_length {
+            return Err(CodecError::FrameTooLarge {
+                length,
+                max: self.max_frame_length,
+            });
+        }
+
+        if src.len() < HEADER_LENGTH + length {
+            // Make room for the rest of the frame to avoid reallocating on
+            // every read.
+            src.reserve(HEADER_LENGTH + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_LENGTH);
+        let payload = src.split_to(length);
+        bincode::deserialize(&payload)
+            .map(Some)
+            .map_err(CodecError::Decode)
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let length = bincode::serialized_size(&frame).map_err(CodecError::Encode)? as usize;
+        if length > self.max_frame_length {
+            return Err(CodecError::FrameTooLarge {
+                length,
+                max: self.max_frame_length,
+            });
+        }
+
+        dst.reserve(HEADER_LENGTH + length);
+        dst.put_u32(length as u32);
+        bincode::serialize_into(dst.writer(), &frame).map_err(CodecError::Encode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Request, Response};
+    use crate::types::Envelope;
+    use std::time::SystemTime;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_round_trip() {
+        let envelope = Envelope {
+            sender_device: Uuid::new_v4(),
+            room: Uuid::new_v4(),
+            header: vec![1, 2],
+            ciphertext: b"hello".to_vec(),
+            timestamp: SystemTime::now(),
+        };
+        let mut codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                Frame::Request {
+                    id: 7,
+                    request: Request::SendMessage {
+                        envelope: envelope.clone(),
+                        reply_to: None,
+                        thread_root: None,
+                    },
+                },
+                &mut buf,
+            )
+            .unwrap();
+        codec
+            .encode(
+                Frame::Response {
+                    id: 7,
+                    response: Response::Pong,
+                },
+                &mut buf,
+            )
+            .unwrap();
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Frame::Request {
+                id: 7,
+                request:
+                    Request::SendMessage {
+                        envelope: decoded, ..
+                    },
+            }) => assert_eq!(decoded, envelope),
+            other => panic!("unexpected frame: {other:?}"),
+        }
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Response {
+                id: 7,
+                response: Response::Pong
+            })
+        ));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_waits_for_complete_frame() {
+        let mut codec = FrameCodec::new();
+        let mut encoded = BytesMut::new();
+        codec
+            .encode(
+                Frame::Request {
+                    id: 1,
+                    request: Request::Ping,
+                },
+                &mut encoded,
+            )
+            .unwrap();
+
+        let mut buf = BytesMut::new();
+        for byte in &encoded[..encoded.len() - 1] {
+            buf.put_u8(*byte);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+        buf.put_u8(encoded[encoded.len() - 1]);
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Request {
+                id: 1,
+                request: Request::Ping
+            })
+        ));
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut codec = FrameCodec::with_max_frame_length(16);
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(17);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CodecError::FrameTooLarge {
+                length: 17,
+                max: 16
+            })
+        ));
+
+        let frame = Frame::Request {
+            id: 1,
+            request: Request::FindUser {
+                address: "too long for sixteen bytes".to_string(),
+            },
+        };
+        assert!(matches!(
+            codec.encode(frame, &mut BytesMut::new()),
+            Err(CodecError::FrameTooLarge { max: 16, .. })
+        ));
+    }
+
+    #[test]
+    fn test_malformed_payload() {
+        let mut codec = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        buf.put_u32(4);
+        buf.put_u32(u32::MAX);
+        assert!(matches!(codec.decode(&mut buf), Err(CodecError::Decode(_))));
+    }
+}
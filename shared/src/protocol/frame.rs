@@ -0,0 +1,475 @@
+use crate::auth::{Challenge, Signature};
+use crate::keys::{OneTimePrekey, PrekeyBundle, SignedPrekey};
+use crate::protocol::{HandshakeError, Hello, Welcome};
+use crate::types::{Address, DirectMessage, Envelope, IdentityKey, Message, Room, User};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Identifier chosen by the client to pair a [`Response`] with its [`Request`].
+pub type RequestId = u64;
+
+/// Maximum length of the emoji of a reaction, in bytes.
+pub const MAX_EMOJI_LEN: usize = 32;
+
+/// A single unit of data exchanged between a client and the server.
+///
+/// bincode encodes variants by position, so the handshake variants must stay
+/// first and keep their layout in every protocol version: they are exchanged
+/// before both ends agree on a version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Frame {
+    Hello(Hello),
+    Welcome(Welcome),
+    HandshakeError(HandshakeError),
+    /// Client to server.
+    Request {
+        id: RequestId,
+        request: Request,
+    },
+    /// Server to client, answering the request with the same `id`.
+    Response {
+        id: RequestId,
+        response: Response,
+    },
+    /// Server to client, not solicited by any request.
+    Push(Push),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    Ping,
+    /// Ask for a fresh challenge to sign in `Register` or `Login`.
+    Challenge,
+    /// Create a new user owning `identity_key` and log in as that user.
+    ///
+    /// `device` is chosen by the client and identifies it among the devices of
+    /// the user, see `Login`.
+    Register {
+        address: Address,
+        identity_key: IdentityKey,
+        device: Uuid,
+        signature: Signature,
+    },
+    /// Log in as an existing user, from one of its devices.
+    ///
+    /// The server tracks which messages each device received, so a client
+    /// should keep using the same `device`.
+    Login {
+        user: Uuid,
+        device: Uuid,
+        signature: Signature,
+    },
+    /// Look up a user by its public address.
+    FindUser {
+        address: Address,
+    },
+    CreateRoom {
+        name: String,
+    },
+    RenameRoom {
+        room: Uuid,
+        name: String,
+    },
+    AddMembers {
+        room: Uuid,
+        members: Vec<Uuid>,
+    },
+    LeaveRoom {
+        room: Uuid,
+    },
+    /// Remove a member from a room. Owners only.
+    KickMember {
+        room: Uuid,
+        member: Uuid,
+    },
+    /// List the rooms of the logged in user, ordered by room uuid.
+    ///
+    /// Pass the `next` of the previous `Response::Rooms` as `after` to get the
+    /// following page.
+    ListRooms {
+        limit: u32,
+        after: Option<Uuid>,
+    },
+    /// Delete a room with all its messages. Owners only.
+    DeleteRoom {
+        room: Uuid,
+    },
+    /// Post a message in the room of `envelope`, or in the thread of
+    /// `thread_root` when set. The envelope must come from the device of this
+    /// connection.
+    ///
+    /// A message replying to `reply_to` is posted in the thread of that
+    /// message, if any, or starts no thread.
+    SendMessage {
+        envelope: Envelope,
+        reply_to: Option<Uuid>,
+        thread_root: Option<Uuid>,
+    },
+    /// Replace the envelope of a message sent by the logged in user. The
+    /// replaced revisions remain available with `FetchRevisions`.
+    EditMessage {
+        message: Uuid,
+        envelope: Envelope,
+    },
+    /// Replace a message and its revisions with a tombstone, for everyone.
+    /// Allowed to its sender and to the owners of its room.
+    DeleteMessage {
+        room: Uuid,
+        message: Uuid,
+    },
+    /// React to a message with an emoji of at most [`MAX_EMOJI_LEN`] bytes.
+    AddReaction {
+        room: Uuid,
+        message: Uuid,
+        emoji: String,
+    },
+    /// Withdraw a reaction of the logged in user.
+    RemoveReaction {
+        room: Uuid,
+        message: Uuid,
+        emoji: String,
+    },
+    /// Fetch the revisions of a message replaced by edits.
+    FetchRevisions {
+        room: Uuid,
+        message: Uuid,
+    },
+    /// Stream, as `Push::Message` frames, every message of the rooms of the
+    /// user that this device has not acknowledged, oldest first in each room.
+    /// Edits this device may have missed of the messages it acknowledged,
+    /// those made since its last sync that caught up, come first in each
+    /// room as `Push::MessageEdited` frames.
+    ///
+    /// The response, `Response::CaughtUp`, follows the last message.
+    Sync,
+    /// Acknowledge that this device received the messages of `room` up to and
+    /// including `message`.
+    AckDelivery {
+        room: Uuid,
+        message: Uuid,
+    },
+    /// Mark the messages of `room` up to and including `message` as read by
+    /// the user.
+    MarkRead {
+        room: Uuid,
+        message: Uuid,
+    },
+    /// Fetch the read position of every member of a room.
+    FetchReceipts {
+        room: Uuid,
+    },
+    /// Fetch up to `limit` messages posted in the thread of `root`, oldest
+    /// first and starting after `after`.
+    ///
+    /// Messages of threads are also part of the history of their room.
+    FetchThread {
+        room: Uuid,
+        root: Uuid,
+        limit: u32,
+        after: Option<Cursor>,
+    },
+    /// Fetch up to `limit` messages of a room.
+    FetchHistory {
+        room: Uuid,
+        limit: u32,
+        query: HistoryQuery,
+    },
+    /// Tell the members of a room whether the user is typing in it.
+    ///
+    /// The server stops the typing of a user that does not repeat it within a
+    /// few seconds, or sends a message.
+    SetTyping {
+        room: Uuid,
+        typing: bool,
+    },
+    /// Change the presence of this device. The user shows as away only when
+    /// all its devices are, and offline once none is connected.
+    SetPresence {
+        presence: Presence,
+    },
+    /// Fetch the presence of users. Users sharing no room with the logged in
+    /// user always show as offline.
+    FetchPresence {
+        users: Vec<Uuid>,
+    },
+    /// Publish prekeys of the logged in device: replace its signed prekey,
+    /// when set, and add one-time prekeys.
+    ///
+    /// An empty upload only asks how many one-time prekeys are left.
+    UploadPrekeys {
+        signed_prekey: Option<SignedPrekey>,
+        one_time_prekeys: Vec<OneTimePrekey>,
+    },
+    /// Fetch the prekeys needed to start a session with each device of
+    /// `user`. The server hands out each one-time prekey in a single bundle
+    /// only, and limits how often a user fetches the bundles of another.
+    FetchPrekeyBundle {
+        user: Uuid,
+    },
+    /// Send `payload` to `device` of `user`, a member of `room` like the
+    /// logged in user. The device receives it as a `Push::Direct` when online,
+    /// and with `FetchDirect` until it acknowledges it.
+    SendDirect {
+        room: Uuid,
+        user: Uuid,
+        device: Uuid,
+        payload: Vec<u8>,
+    },
+    /// Fetch up to `limit` of the direct messages to this device it did not
+    /// acknowledge, oldest first.
+    FetchDirect {
+        limit: u32,
+    },
+    /// Acknowledge direct messages this device received, so the server
+    /// forgets them.
+    AckDirect {
+        messages: Vec<Uuid>,
+    },
+    /// Look up users by uuid, to learn the identity keys of the members of a
+    /// room. Unknown users are left out.
+    FetchUsers {
+        users: Vec<Uuid>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Pong,
+    Challenge(Challenge),
+    LoggedIn {
+        user: User,
+    },
+    User {
+        user: User,
+    },
+    /// State of a room after the requested change.
+    Room {
+        room: Room,
+    },
+    /// A page of rooms, followed by more rooms unless `next` is `None`.
+    Rooms {
+        rooms: Vec<RoomSummary>,
+        next: Option<Uuid>,
+    },
+    MessageSent {
+        message: Uuid,
+    },
+    /// A page of history. Edited messages are in their latest revision.
+    ///
+    /// `older` fetches the preceding page with `HistoryQuery::Before`, and is
+    /// `None` once the oldest message was returned or when walking forward.
+    /// `newer` is the position of the newest message returned and fetches the
+    /// messages after it with `HistoryQuery::After`, including those sent
+    /// later.
+    ///
+    /// `reactions` maps the returned messages with reactions to their counts.
+    History {
+        messages: Vec<Message>,
+        older: Option<Cursor>,
+        newer: Option<Cursor>,
+        reactions: HashMap<Uuid, Vec<ReactionCount>>,
+    },
+    /// A page of a thread. `next` is the position of the last message
+    /// returned and fetches the messages after it, including those posted
+    /// later. `reactions` is as in `History`.
+    Thread {
+        root: Message,
+        messages: Vec<Message>,
+        next: Option<Cursor>,
+        reactions: HashMap<Uuid, Vec<ReactionCount>>,
+    },
+    /// Revisions of a message replaced by edits, oldest first.
+    Revisions {
+        revisions: Vec<Message>,
+    },
+    /// Every message pending for this device was sent.
+    CaughtUp,
+    /// The last message each member of a room read, for members who read any.
+    Receipts {
+        read: HashMap<Uuid, Uuid>,
+    },
+    /// Presence of each requested user.
+    Presence {
+        presence: HashMap<Uuid, Presence>,
+    },
+    /// Prekeys were uploaded. The user should upload more one-time prekeys
+    /// when few are left.
+    PrekeysUploaded {
+        one_time_prekeys: u32,
+    },
+    /// A bundle for each device of the user with a signed prekey.
+    PrekeyBundles {
+        bundles: Vec<PrekeyBundle>,
+    },
+    Acknowledged,
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Direct messages to this device, oldest first.
+    DirectMessages {
+        messages: Vec<DirectMessage>,
+    },
+    Users {
+        users: Vec<User>,
+    },
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// A room with what the logged in user has not read in it yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSummary {
+    pub room: Room,
+    /// Messages of others the user did not read, counted up to a limit set by
+    /// the server.
+    pub unread: u32,
+}
+
+/// Users who reacted to a message with the same emoji.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: u32,
+    /// Whether the logged in user is one of them.
+    pub mine: bool,
+}
+
+/// Part of the history of a room to fetch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HistoryQuery {
+    /// The newest messages, newest first.
+    Latest,
+    /// Messages older than the cursor, newest first.
+    Before(Cursor),
+    /// Messages newer than the cursor, oldest first.
+    After(Cursor),
+    /// Messages centered on the message with this uuid, newest first.
+    Around(Uuid),
+}
+
+/// Position in a paginated listing.
+///
+/// Cursors are produced by the server and must be passed back unchanged:
+/// their encoding is private to the server and may change at any time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor(Vec<u8>);
+
+impl Cursor {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Cursor(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Push {
+    Message {
+        room: Uuid,
+        message: Message,
+    },
+    /// A message was edited. `message` is its new revision.
+    MessageEdited {
+        room: Uuid,
+        message: Message,
+    },
+    /// A message was deleted, and replaced by a tombstone in history.
+    MessageDeleted {
+        room: Uuid,
+        message: Uuid,
+        by: Uuid,
+    },
+    /// `user` added, or withdrew, a reaction to a message.
+    Reaction {
+        room: Uuid,
+        message: Uuid,
+        user: Uuid,
+        emoji: String,
+        added: bool,
+    },
+    /// A room changed. `room` is its state after the change, or right before
+    /// it was deleted.
+    Room {
+        room: Room,
+        event: RoomEvent,
+    },
+    /// `user` received or read the messages of `room` up to `message`.
+    Receipt {
+        room: Uuid,
+        user: Uuid,
+        message: Uuid,
+        receipt: Receipt,
+    },
+    /// `user` started or stopped typing in `room`.
+    Typing {
+        room: Uuid,
+        user: Uuid,
+        typing: bool,
+    },
+    /// The presence of `user`, who shares a room with the receiver, changed.
+    Presence {
+        user: Uuid,
+        presence: Presence,
+    },
+    /// A direct message to this device.
+    Direct {
+        message: DirectMessage,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Receipt {
+    /// One of the devices of the user received the messages.
+    Delivered,
+    Read,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Presence {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomEvent {
+    Created,
+    Renamed { by: Uuid },
+    MembersAdded { members: Vec<Uuid>, by: Uuid },
+    MemberLeft { member: Uuid },
+    MemberKicked { member: Uuid, by: Uuid },
+    Deleted { by: Uuid },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The request is malformed or not allowed in the current state.
+    BadRequest,
+    /// The server does not handle this request.
+    Unsupported,
+    /// The request requires a logged in user.
+    Unauthenticated,
+    /// The challenge signature could not be verified.
+    AuthenticationFailed,
+    /// The requested entity does not exist.
+    NotFound,
+    /// The logged in user is not allowed to perform the request.
+    Forbidden,
+    /// The address is already registered by another user.
+    AddressTaken,
+    /// The server failed to process a valid request.
+    Internal,
+    /// The user sent this request too often, and should retry later.
+    RateLimited,
+}
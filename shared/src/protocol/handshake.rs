@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use std::ops::BitOr;
+
+/// Newest protocol version implemented by this crate.
+///
+/// Frames are bincode, which encodes enum variants by position and struct
+/// fields in order, so any change to a frame other than appending an enum
+/// variant is a new version. Version 2 inserted requests, responses, pushes
+/// and error codes amid the existing ones, replaced plaintext content with
+/// envelopes and returned the prekey bundles of every device of a user.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Oldest protocol version this crate is still able to speak. Frames of
+/// version 1 no longer decode, so it is not.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+/// Set of optional protocol features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    /// Frame payload compression.
+    pub const COMPRESSION: Features = Features(1 << 0);
+    /// Pairwise end-to-end encryption using X3DH and the Double Ratchet.
+    pub const E2EE_DOUBLE_RATCHET: Features = Features(1 << 1);
+    /// Group end-to-end encryption using sender keys.
+    pub const E2EE_SENDER_KEYS: Features = Features(1 << 2);
+    /// File, audio and video attachments.
+    pub const ATTACHMENTS: Features = Features(1 << 3);
+
+    pub const fn empty() -> Self {
+        Features(0)
+    }
+
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/// Name and version of the software on either end of a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoftwareInfo {
+    pub name: String,
+    pub version: String,
+}
+
+/// First frame sent by a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    /// Newest protocol version the client speaks.
+    pub version: u16,
+    /// Oldest protocol version the client speaks.
+    pub min_version: u16,
+    pub features: Features,
+    pub client: SoftwareInfo,
+}
+
+/// Server answer to an acceptable [`Hello`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Welcome {
+    /// Protocol version used for the rest of the connection.
+    pub version: u16,
+    /// Features supported by both ends.
+    pub features: Features,
+    pub server: SoftwareInfo,
+}
+
+/// Reason for the server to refuse a connection during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("unsupported protocol version, server speaks {min_version} to {version}")]
+    UnsupportedVersion { version: u16, min_version: u16 },
+    #[error("expected a hello frame")]
+    ExpectedHello,
+}
+
+impl Hello {
+    pub fn new(client: SoftwareInfo, features: Features) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features,
+            client,
+        }
+    }
+
+    /// Pick the newest protocol version and the features supported by both
+    /// the client and a server with the given `features`.
+    pub fn negotiate(
+        &self,
+        features: Features,
+        server: SoftwareInfo,
+    ) -> Result<Welcome, HandshakeError> {
+        let version = self.version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(HandshakeError::UnsupportedVersion {
+                version: PROTOCOL_VERSION,
+                min_version: MIN_PROTOCOL_VERSION,
+            });
+        }
+
+        Ok(Welcome {
+            version,
+            features: self.features.intersection(features),
+            server,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn software() -> SoftwareInfo {
+        SoftwareInfo {
+            name: "test".to_string(),
+            version: "0.0.1".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_common_features() {
+        let hello = Hello::new(
+            software(),
+            Features::COMPRESSION | Features::E2EE_DOUBLE_RATCHET,
+        );
+        let welcome = hello
+            .negotiate(
+                Features::E2EE_DOUBLE_RATCHET | Features::ATTACHMENTS,
+                software(),
+            )
+            .unwrap();
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.features, Features::E2EE_DOUBLE_RATCHET);
+        assert!(!welcome.features.contains(Features::COMPRESSION));
+    }
+
+    #[test]
+    fn test_negotiate_picks_newest_common_version() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 5,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: Features::empty(),
+            client: software(),
+        };
+        let welcome = hello.negotiate(Features::empty(), software()).unwrap();
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_negotiate_rejects_incompatible_version() {
+        let too_new = Hello {
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+            features: Features::empty(),
+            client: software(),
+        };
+        assert_eq!(
+            too_new
+                .negotiate(Features::empty(), software())
+                .unwrap_err(),
+            HandshakeError::UnsupportedVersion {
+                version: PROTOCOL_VERSION,
+                min_version: MIN_PROTOCOL_VERSION,
+            }
+        );
+
+        let too_old = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            min_version: 0,
+            features: Features::empty(),
+            client: software(),
+        };
+        assert!(too_old.negotiate(Features::empty(), software()).is_err());
+    }
+}
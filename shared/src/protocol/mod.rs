@@ -0,0 +1,21 @@
+//! Wire protocol spoken between chat clients and the server.
+//!
+//! Every value on the wire is a [`Frame`], serialized with bincode and
+//! prefixed by its length (see [`FrameCodec`]).
+//!
+//! A connection starts with a handshake: the client sends a [`Hello`] and the
+//! server answers with a [`Welcome`] or a [`HandshakeError`] before closing
+//! the connection. Only after a `Welcome` may requests be sent.
+
+mod codec;
+mod frame;
+mod handshake;
+
+pub use codec::{CodecError, FrameCodec, DEFAULT_MAX_FRAME_LENGTH};
+pub use frame::{
+    Cursor, ErrorCode, Frame, HistoryQuery, Presence, Push, ReactionCount, Receipt, Request,
+    RequestId, Response, RoomEvent, RoomSummary, MAX_EMOJI_LEN,
+};
+pub use handshake::{
+    Features, HandshakeError, Hello, SoftwareInfo, Welcome, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
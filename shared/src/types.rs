@@ -7,10 +7,14 @@ use uuid::Uuid;
 /// User public address.
 pub type Address = String;
 
+/// Long-term Ed25519 public key a user authenticates with.
+pub type IdentityKey = [u8; 32];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub uuid: Uuid,
     pub address: Address,
+    pub identity_key: IdentityKey,
     pub created: SystemTime,
 }
 
@@ -29,10 +33,11 @@ impl Hash for User {
 }
 
 impl User {
-    pub fn new(address: Address) -> Self {
+    pub fn new(address: Address, identity_key: IdentityKey) -> Self {
         Self {
             uuid: Uuid::new_v4(),
             address,
+            identity_key,
             created: SystemTime::now(),
         }
     }
@@ -59,14 +64,10 @@ impl Room {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum MessageType {
-    Text,
-    File,
-    Audio,
-    Video,
-}
-
+/// Payload of a message, as its sender wrote it.
+///
+/// Content only exists on the devices of the members of a room: it travels
+/// and is stored encrypted in an [`Envelope`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Content {
     Text(String),
@@ -75,23 +76,114 @@ pub enum Content {
     Video(String),
 }
 
+/// An encrypted message as the server sees it. The server stores and
+/// forwards envelopes without interpreting their header or ciphertext.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Device of the sender that encrypted the envelope.
+    pub sender_device: Uuid,
+    /// Room whose members can decrypt the envelope.
+    pub room: Uuid,
+    /// Header of the encryption, telling recipients which key decrypts it.
+    pub header: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    /// When the sender encrypted the envelope, by the clock of its device.
+    pub timestamp: SystemTime,
+}
+
+impl Envelope {
+    /// The envelope of a message stored before messages were encrypted, which
+    /// holds its `content` in plaintext. No encryption leaves the header
+    /// empty, and the device of the sender is unknown.
+    pub fn legacy(room: Uuid, content: &Content, timestamp: SystemTime) -> Self {
+        Self {
+            sender_device: Uuid::nil(),
+            room,
+            header: Vec::new(),
+            ciphertext: bincode::serialize(content).expect("Content is serializable"),
+            timestamp,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.header.is_empty()
+    }
+
+    /// The plaintext content of a [`legacy`](Self::legacy) envelope.
+    pub fn legacy_content(&self) -> Option<Content> {
+        if !self.is_legacy() {
+            return None;
+        }
+        bincode::deserialize(&self.ciphertext).ok()
+    }
+}
+
+/// A message from one device to another, about a room both users are members
+/// of, such as the sender key of a device encrypted over the pairwise session
+/// of the two. The server stores and forwards its payload without
+/// interpreting it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub uuid: Uuid,
+    pub room: Uuid,
+    pub sender: Uuid,
+    pub sender_device: Uuid,
+    pub recipient: Uuid,
+    pub recipient_device: Uuid,
+    pub payload: Vec<u8>,
+    pub created: SystemTime,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub uuid: Uuid,
-    pub message_type: MessageType,
     pub created: SystemTime,
     pub owner: Uuid,
-    pub content: Content,
+    /// The encrypted content, `None` once the message was deleted.
+    pub envelope: Option<Envelope>,
+    /// When this revision replaced the previous one, `None` for the original.
+    pub edited: Option<SystemTime>,
+    /// The message this one replies to.
+    pub reply_to: Option<Uuid>,
+    /// The message starting the thread this one was posted in.
+    pub thread_root: Option<Uuid>,
 }
 
 impl Message {
-    pub fn new_text(text: &str, sender: &User) -> Self {
+    pub fn new(envelope: Envelope, sender: &User) -> Self {
         Self {
             uuid: Uuid::new_v4(),
-            message_type: MessageType::Text,
             created: SystemTime::now(),
             owner: sender.uuid,
-            content: Content::Text(text.to_string()),
+            envelope: Some(envelope),
+            edited: None,
+            reply_to: None,
+            thread_root: None,
         }
     }
+
+    /// A new revision of this message with `envelope`. It keeps the uuid and
+    /// the place in history of the message.
+    pub fn edit(&self, envelope: Envelope) -> Self {
+        Self {
+            envelope: Some(envelope),
+            edited: Some(SystemTime::now()),
+            ..self.clone()
+        }
+    }
+
+    /// The tombstone replacing this message once deleted for everyone. It
+    /// keeps the uuid and the place in history of the message, and nothing of
+    /// its content.
+    pub fn tombstone(&self) -> Self {
+        Self {
+            envelope: None,
+            edited: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.envelope.is_none()
+    }
 }